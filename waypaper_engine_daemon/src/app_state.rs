@@ -1,23 +1,38 @@
+use std::collections::HashMap;
 use std::error::Error;
 use std::path::PathBuf;
-use std::sync::mpsc;
-use std::sync::mpsc::TryRecvError;
 use std::thread;
 
 use linux_ipc::IpcChannel;
+use smithay_client_toolkit::reexports::calloop::channel::{self, Event};
+use smithay_client_toolkit::reexports::calloop::generic::Generic;
+use smithay_client_toolkit::reexports::calloop::{
+    EventLoop, Interest, LoopHandle, Mode, PostAction,
+};
 
-use waypaper_engine_shared::ipc::IPCRequest;
+use waypaper_engine_shared::ipc::{IPCRequest, IPCResponse, Transition};
 
+use crate::config::Config;
+use crate::signals::TerminationSignals;
 use crate::wallpaper::Wallpaper;
 use crate::wl_renderer::RenderingContext;
 
 pub struct AppState {
     wpe_dir: PathBuf,
-    rendering_context: RenderingContext,
+    pub(crate) rendering_context: RenderingContext,
+    // Workshop id of the wallpaper set on each output, restored when the output is plugged back
+    assignments: HashMap<String, u64>,
+    exit: Option<Exit>,
+}
+
+/// Why the event loop stopped
+enum Exit {
+    Requested,
+    Signal(u32),
 }
 
 impl AppState {
-    pub fn new(wpe_dir: PathBuf) -> Self {
+    pub fn new(wpe_dir: PathBuf, event_loop: &LoopHandle<'static, AppState>) -> Self {
         tracing::debug!(
             "Using wallpaper engine workshop path {}",
             wpe_dir.to_string_lossy()
@@ -25,25 +40,42 @@ impl AppState {
 
         AppState {
             wpe_dir,
-            rendering_context: RenderingContext::new(),
+            rendering_context: RenderingContext::new(Config::load(), event_loop),
+            assignments: HashMap::new(),
+            exit: None,
         }
     }
 
-    pub fn run(&mut self) -> Result<(), Box<dyn Error>> {
+    /// Sleeps in `event_loop` until Wayland events, IPC requests, decoder wakeups or `signals`
+    /// come in
+    pub fn run(
+        &mut self,
+        event_loop: &mut EventLoop<'static, AppState>,
+        signals: TerminationSignals,
+    ) -> Result<(), Box<dyn Error>> {
         video_rs::init().unwrap();
 
-        let (tx, rx) = mpsc::channel::<IPCRequest>();
+        let (tx, rx) = channel::channel::<(IPCRequest, oneshot::Sender<IPCResponse>)>();
 
         let ipc_thread = thread::spawn(move || {
             let mut channel = IpcChannel::new("/tmp/waypaper-engine.sock").unwrap();
             tracing::info!("Started IPC channel");
 
             loop {
-                match channel.receive::<IPCRequest, String>() {
-                    Ok((response, reply)) => {
-                        tracing::debug!("Received msg : [{:?}]", response);
-                        tx.send(response.clone()).unwrap();
-                        if let IPCRequest::StopDaemon = response {
+                match channel.receive::<IPCRequest, IPCResponse>() {
+                    Ok((request, reply)) => {
+                        tracing::debug!("Received msg : [{:?}]", request);
+                        let (response_tx, response_rx) = oneshot::channel();
+                        tx.send((request.clone(), response_tx)).unwrap();
+
+                        // Requests without a response drop the sender, which leaves the reply empty
+                        if let Ok(response) = response_rx.recv() {
+                            if let Err(err) = reply(response) {
+                                tracing::warn!("Failed to reply to IPC request (Error: {})", err);
+                            }
+                        }
+
+                        if let IPCRequest::StopDaemon = request {
                             break;
                         }
                     }
@@ -52,51 +84,193 @@ impl AppState {
             }
         });
 
-        loop {
-            self.rendering_context.tick();
-
-            match rx.try_recv() {
-                Ok(req) => match req {
-                    IPCRequest::SetWP { id, screen } => {
-                        let outputs = self.rendering_context.get_outputs();
-                        if let Some(output) = outputs
-                            .iter()
-                            .find(|output| output.1.name.as_ref().unwrap() == &screen)
-                        {
-                            let path = self.wpe_dir.join(id.to_string());
-                            if path.exists() && path.is_dir() {
-                                let wallpaper = Wallpaper::new(path).unwrap();
-
-                                if let Wallpaper::Video { ref project, .. } = wallpaper {
-                                    let path = self
-                                        .wpe_dir
-                                        .join(project.workshop_id.unwrap().to_string())
-                                        .join(project.file.as_ref().unwrap());
-
-                                    if path.exists() {
-                                        tracing::info!("Found video file ! (Path : {path:?})");
-
-                                        self.rendering_context.set_wallpaper(output, wallpaper);
-                                    }
-                                }
-                            }
-                        } else {
-                            tracing::warn!("Received wrong output in SetWallpaper request: [{}]", screen);
-                        }
-                    }
-                    IPCRequest::StopDaemon => {
-                        break;
-                    }
-                },
-                Err(err) => match err {
-                    TryRecvError::Empty => {}
-                    TryRecvError::Disconnected => panic!(),
-                },
+        let handle = event_loop.handle();
+        handle.insert_source(rx, |event, _, app| match event {
+            Event::Msg((request, response_tx)) => app.handle_request(request, response_tx),
+            // The IPC thread stops after replying to StopDaemon, which may be handled first
+            Event::Closed => {
+                if app.exit.is_none() {
+                    tracing::warn!("IPC channel closed, requests are no longer handled");
+                }
             }
+        })?;
+        handle.insert_source(
+            Generic::new(signals, Interest::READ, Mode::Level),
+            |_, signals, app| {
+                while let Some(signal) = signals.read()? {
+                    app.exit = Some(Exit::Signal(signal));
+                }
+                Ok(PostAction::Continue)
+            },
+        )?;
+
+        // Outputs present at startup
+        self.restore_wallpapers();
+
+        while self.exit.is_none() {
+            event_loop.dispatch(None, self)?;
         }
 
-        ipc_thread.join().unwrap();
+        match self.exit {
+            // The IPC thread stops after replying to the request
+            Some(Exit::Requested) => ipc_thread.join().unwrap(),
+            Some(Exit::Signal(signal)) => tracing::info!("Stopping on signal {signal}"),
+            None => unreachable!(),
+        }
 
         Ok(())
     }
+
+    /// Shows the wallpaper last set on the outputs connected since the last call, or the one
+    /// from the config
+    pub(crate) fn restore_wallpapers(&mut self) {
+        for output_name in self.rendering_context.take_new_outputs() {
+            let id = self
+                .assignments
+                .get(&output_name)
+                .copied()
+                .or_else(|| self.rendering_context.configured_wallpaper(&output_name));
+
+            if let Some(id) = id {
+                tracing::info!("Restoring wallpaper {id} on output {output_name}");
+                self.set_wallpaper(id, &output_name, None);
+            }
+        }
+    }
+
+    /// Returns whether the wallpaper could be shown
+    fn set_wallpaper(&mut self, id: u64, screen: &str, transition: Option<Transition>) -> bool {
+        let outputs = self.rendering_context.get_outputs();
+        let Some(output) = outputs
+            .iter()
+            .find(|output| output.1.name.as_deref() == Some(screen))
+        else {
+            tracing::warn!(
+                "Received wrong output in SetWallpaper request: [{}]",
+                screen
+            );
+            return false;
+        };
+
+        let path = self.wpe_dir.join(id.to_string());
+        if !path.exists() || !path.is_dir() {
+            tracing::warn!("Wallpaper {id} not found");
+            return false;
+        }
+
+        let wallpaper = Wallpaper::new(path).unwrap();
+
+        match wallpaper {
+            Wallpaper::Video { ref project, .. } => {
+                let path = self
+                    .wpe_dir
+                    .join(project.workshop_id.unwrap().to_string())
+                    .join(project.file.as_ref().unwrap());
+
+                if !path.exists() {
+                    return false;
+                }
+
+                tracing::info!("Found video file ! (Path : {path:?})");
+                self.rendering_context
+                    .set_wallpaper(output, wallpaper, transition);
+            }
+            Wallpaper::Scene { .. } => {
+                self.rendering_context
+                    .set_wallpaper(output, wallpaper, transition);
+            }
+            _ => {
+                tracing::warn!("Unsupported wallpaper type: {:?}", wallpaper.get_wp_type());
+                return false;
+            }
+        }
+
+        true
+    }
+
+    fn handle_request(&mut self, req: IPCRequest, response_tx: oneshot::Sender<IPCResponse>) {
+        match req {
+            IPCRequest::SetWP {
+                id,
+                screen,
+                transition,
+            } => {
+                self.rendering_context.leave_span(&screen);
+                if self.set_wallpaper(id, &screen, transition) {
+                    self.assignments.insert(screen, id);
+                }
+            }
+            IPCRequest::SetSpannedWP {
+                id,
+                screens,
+                transition,
+            } => {
+                self.rendering_context.set_span(screens.clone());
+                for screen in screens {
+                    if self.set_wallpaper(id, &screen, transition) {
+                        self.assignments.insert(screen, id);
+                    }
+                }
+            }
+            IPCRequest::SetBloom { screen, enabled } => {
+                self.rendering_context.set_bloom_enabled(&screen, enabled);
+            }
+            IPCRequest::SetVolume { screen, volume } => {
+                self.rendering_context.set_volume(&screen, volume);
+            }
+            IPCRequest::SetMuted { screen, muted } => {
+                self.rendering_context.set_muted(&screen, muted);
+            }
+            IPCRequest::SetScaling { screen, scaling } => {
+                self.rendering_context.set_scaling(&screen, scaling);
+            }
+            IPCRequest::SetPaused { screen, paused } => {
+                self.rendering_context.set_paused(screen.as_deref(), paused);
+            }
+            IPCRequest::SetPlaybackRate { screen, rate } => {
+                self.rendering_context
+                    .set_playback_rate(screen.as_deref(), rate);
+            }
+            IPCRequest::Seek { screen, position } => {
+                self.rendering_context.seek(screen.as_deref(), position);
+            }
+            IPCRequest::SetLoopRange { screen, range } => {
+                self.rendering_context
+                    .set_loop_range(screen.as_deref(), range);
+            }
+            IPCRequest::SetMaxFps { screen, fps } => {
+                self.rendering_context.set_max_fps(screen.as_deref(), fps);
+            }
+            IPCRequest::SetLayerOptions {
+                screen,
+                layer,
+                namespace,
+                margin,
+            } => {
+                self.rendering_context.set_layer_options(
+                    screen.as_deref(),
+                    layer,
+                    namespace,
+                    margin,
+                );
+            }
+            IPCRequest::SetPointerTracking { mode } => {
+                self.rendering_context.set_pointer_tracking(mode);
+            }
+            IPCRequest::PointerMoved { x, y } => {
+                self.rendering_context.pointer_moved(x, y);
+            }
+            IPCRequest::GetStatus => {
+                let status = self.rendering_context.status();
+                let _ = response_tx.send(IPCResponse::Status(status));
+            }
+            IPCRequest::GetEvents => {
+                let events = self.rendering_context.take_events();
+                let _ = response_tx.send(IPCResponse::Events(events));
+            }
+            IPCRequest::StopDaemon => {
+                self.exit = Some(Exit::Requested);
+            }
+        }
+    }
 }
@@ -0,0 +1,409 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::{FromSample, SampleFormat, SizedSample, Stream, StreamConfig};
+
+use crate::audio_source::{spawn_decoding_thread, PlaybackMode, SoundInput};
+
+/// The mixer works in interleaved stereo, sources and devices are converted to it
+pub const CHANNELS: usize = 2;
+
+const NULL_SINK_SAMPLE_RATE: u32 = 48000;
+const NULL_SINK_PERIOD: Duration = Duration::from_millis(10);
+
+// Decoded audio kept ahead of playback per track, in chunks of a few milliseconds each
+const MAX_QUEUED_CHUNKS: usize = 64;
+
+/// Decoded interleaved stereo samples at the rate of their source
+pub struct Chunk {
+    /// Timestamp of the first frame in the source, in seconds
+    pub time: f64,
+    pub sample_rate: u32,
+    pub samples: Vec<f32>,
+}
+
+/// State shared between a track handle, its decoding thread and the mixer
+pub struct TrackShared {
+    chunks: Mutex<VecDeque<Chunk>>,
+    space_available: Condvar,
+
+    volume: AtomicU32,
+    muted: AtomicBool,
+    paused: AtomicBool,
+    speed: AtomicU32,
+    // Timestamp the decoder should jump to
+    seek: Mutex<Option<f64>>,
+    position: AtomicU64,
+    playing: AtomicBool,
+    finished: AtomicBool,
+    shutdown: AtomicBool,
+}
+
+impl TrackShared {
+    fn new(volume: f32) -> Self {
+        Self {
+            chunks: Mutex::new(VecDeque::new()),
+            space_available: Condvar::new(),
+            volume: AtomicU32::new(volume.to_bits()),
+            muted: AtomicBool::new(false),
+            paused: AtomicBool::new(false),
+            speed: AtomicU32::new(1.0f32.to_bits()),
+            seek: Mutex::new(None),
+            position: AtomicU64::new(0),
+            playing: AtomicBool::new(false),
+            finished: AtomicBool::new(false),
+            shutdown: AtomicBool::new(false),
+        }
+    }
+
+    /// Queues decoded audio, blocking while enough is buffered. Returns false once the track was
+    /// dropped and decoding should stop.
+    pub fn push(&self, chunk: Chunk) -> bool {
+        let mut chunks = self.chunks.lock().unwrap();
+
+        while chunks.len() >= MAX_QUEUED_CHUNKS && !self.is_shutdown() && !self.seek_pending() {
+            chunks = self.space_available.wait(chunks).unwrap();
+        }
+
+        if self.is_shutdown() {
+            return false;
+        }
+
+        // Audio decoded before the seek is stale
+        if self.seek_pending() {
+            return true;
+        }
+
+        chunks.push_back(chunk);
+        true
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        self.shutdown.load(Ordering::Relaxed)
+    }
+
+    /// Timestamp to jump to, requested since the last call
+    pub fn take_seek(&self) -> Option<f64> {
+        self.seek.lock().unwrap().take()
+    }
+
+    fn seek_pending(&self) -> bool {
+        self.seek.lock().unwrap().is_some()
+    }
+
+    pub fn set_finished(&self) {
+        self.finished.store(true, Ordering::Relaxed);
+    }
+
+    fn pop(&self) -> Option<Chunk> {
+        let chunk = self.chunks.lock().unwrap().pop_front();
+        self.space_available.notify_one();
+        chunk
+    }
+
+    fn gain(&self) -> f32 {
+        if self.muted.load(Ordering::Relaxed) {
+            0.0
+        } else {
+            f32::from_bits(self.volume.load(Ordering::Relaxed))
+        }
+    }
+}
+
+/// Owner side of a playing track, the track stops when this is dropped
+pub struct TrackHandle {
+    shared: Arc<TrackShared>,
+    decoding_thread: Option<JoinHandle<()>>,
+}
+
+impl TrackHandle {
+    pub fn set_volume(&self, volume: f32) {
+        self.shared
+            .volume
+            .store(volume.max(0.0).to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn set_muted(&self, muted: bool) {
+        self.shared.muted.store(muted, Ordering::Relaxed);
+    }
+
+    /// Paused tracks keep their position and queued audio
+    pub fn set_paused(&self, paused: bool) {
+        self.shared.paused.store(paused, Ordering::Relaxed);
+    }
+
+    /// Plays faster or slower, which also changes the pitch
+    pub fn set_speed(&self, speed: f32) {
+        self.shared
+            .speed
+            .store(speed.max(0.0).to_bits(), Ordering::Relaxed);
+    }
+
+    /// Jumps to a timestamp of the source, once the decoder caught up
+    pub fn seek(&self, time: f64) {
+        *self.shared.seek.lock().unwrap() = Some(time);
+
+        self.shared.chunks.lock().unwrap().clear();
+        self.shared.space_available.notify_all();
+    }
+
+    /// Timestamp of the source currently heard, `None` before playback starts or after it ended.
+    /// Muted tracks keep advancing so they can still drive video synchronisation.
+    pub fn position(&self) -> Option<f64> {
+        let finished = self.shared.finished.load(Ordering::Relaxed)
+            && self.shared.chunks.lock().unwrap().is_empty();
+
+        (self.shared.playing.load(Ordering::Relaxed) && !finished)
+            .then(|| f64::from_bits(self.shared.position.load(Ordering::Relaxed)))
+    }
+}
+
+impl Drop for TrackHandle {
+    fn drop(&mut self) {
+        self.shared.shutdown.store(true, Ordering::Relaxed);
+        self.shared.space_available.notify_all();
+
+        if let Some(handle) = self.decoding_thread.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+struct Track {
+    shared: Arc<TrackShared>,
+    current: Option<Chunk>,
+    // Fractional frame index in the current chunk
+    cursor: f64,
+}
+
+impl Track {
+    /// Adds this track to `out`, resampling from the source rate with linear interpolation
+    fn mix_into(&mut self, out: &mut [f32], sample_rate: u32) {
+        if self.shared.paused.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let gain = self.shared.gain();
+        let speed = f32::from_bits(self.shared.speed.load(Ordering::Relaxed)) as f64;
+
+        for frame in out.chunks_exact_mut(CHANNELS) {
+            loop {
+                match &self.current {
+                    Some(chunk) if (self.cursor as usize) < chunk.samples.len() / CHANNELS => break,
+                    Some(chunk) => {
+                        self.cursor -= (chunk.samples.len() / CHANNELS) as f64;
+                        self.current = self.shared.pop();
+                    }
+                    None => {
+                        self.current = self.shared.pop();
+                        self.cursor = 0.0;
+                        if self.current.is_none() {
+                            return; // Underrun, the decoder will catch up
+                        }
+                    }
+                }
+            }
+
+            let chunk = self.current.as_ref().unwrap();
+            let frames = chunk.samples.len() / CHANNELS;
+            let index = self.cursor as usize;
+            let next = (index + 1).min(frames - 1);
+            let t = (self.cursor - index as f64) as f32;
+
+            for (channel, sample) in frame.iter_mut().enumerate() {
+                let a = chunk.samples[index * CHANNELS + channel];
+                let b = chunk.samples[next * CHANNELS + channel];
+                *sample += (a + (b - a) * t) * gain;
+            }
+
+            self.shared.position.store(
+                (chunk.time + self.cursor / chunk.sample_rate as f64).to_bits(),
+                Ordering::Relaxed,
+            );
+            self.shared.playing.store(true, Ordering::Relaxed);
+
+            self.cursor += chunk.sample_rate as f64 / sample_rate as f64 * speed;
+        }
+    }
+}
+
+/// Mixes every playing track, cheap to clone and shared with the output thread
+#[derive(Clone)]
+pub struct AudioMixer {
+    tracks: Arc<Mutex<Vec<Track>>>,
+    sample_rate: u32,
+}
+
+impl AudioMixer {
+    fn new(sample_rate: u32) -> Self {
+        Self {
+            tracks: Arc::new(Mutex::new(vec![])),
+            sample_rate,
+        }
+    }
+
+    /// Starts decoding the sounds in a background thread and mixes them in as soon as audio is
+    /// available
+    pub fn play(&self, inputs: Vec<SoundInput>, mode: PlaybackMode, volume: f32) -> TrackHandle {
+        let shared = Arc::new(TrackShared::new(volume));
+        let decoding_thread = spawn_decoding_thread(inputs, mode, shared.clone());
+
+        self.tracks.lock().unwrap().push(Track {
+            shared: shared.clone(),
+            current: None,
+            cursor: 0.0,
+        });
+
+        TrackHandle {
+            shared,
+            decoding_thread: Some(decoding_thread),
+        }
+    }
+
+    /// Fills `out` with interleaved stereo samples at the output sample rate
+    fn mix(&self, out: &mut [f32]) {
+        out.fill(0.0);
+
+        let mut tracks = self.tracks.lock().unwrap();
+        tracks.retain(|track| !track.shared.is_shutdown());
+
+        for track in tracks.iter_mut() {
+            track.mix_into(out, self.sample_rate);
+        }
+
+        for sample in out.iter_mut() {
+            *sample = sample.clamp(-1.0, 1.0);
+        }
+    }
+}
+
+/// Where the mixed audio ends up
+enum Sink {
+    /// The default device of the system, which is PipeWire or PulseAudio on most desktops. The
+    /// stream plays as long as it is alive.
+    Device { _stream: Stream },
+    /// Consumes audio in real time without playing it, for machines without sound and tests
+    Null {
+        thread: Option<JoinHandle<()>>,
+        shutdown: Arc<AtomicBool>,
+    },
+}
+
+pub struct AudioOutput {
+    mixer: AudioMixer,
+    _sink: Sink,
+}
+
+impl AudioOutput {
+    /// Opens the default output device, `WAYPAPER_ENGINE_AUDIO_SINK=null` forces the null sink
+    pub fn new() -> Self {
+        let force_null =
+            std::env::var("WAYPAPER_ENGINE_AUDIO_SINK").is_ok_and(|sink| sink == "null");
+
+        if !force_null {
+            match open_device() {
+                Ok((stream, mixer)) => {
+                    tracing::info!("Opened audio output at {} Hz", mixer.sample_rate);
+                    return Self {
+                        mixer,
+                        _sink: Sink::Device { _stream: stream },
+                    };
+                }
+                Err(err) => tracing::warn!("No usable audio output, using null sink: {err}"),
+            }
+        }
+
+        let mixer = AudioMixer::new(NULL_SINK_SAMPLE_RATE);
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let thread = spawn_null_sink(mixer.clone(), shutdown.clone());
+
+        Self {
+            mixer,
+            _sink: Sink::Null {
+                thread: Some(thread),
+                shutdown,
+            },
+        }
+    }
+
+    pub fn mixer(&self) -> AudioMixer {
+        self.mixer.clone()
+    }
+}
+
+impl Drop for Sink {
+    fn drop(&mut self) {
+        if let Sink::Null { thread, shutdown } = self {
+            shutdown.store(true, Ordering::Relaxed);
+            if let Some(thread) = thread.take() {
+                let _ = thread.join();
+            }
+        }
+    }
+}
+
+fn open_device() -> Result<(Stream, AudioMixer), Box<dyn std::error::Error>> {
+    let device = cpal::default_host()
+        .default_output_device()
+        .ok_or("no default output device")?;
+    let supported = device.default_output_config()?;
+    let config: StreamConfig = supported.config();
+    let mixer = AudioMixer::new(config.sample_rate.0);
+
+    let stream = match supported.sample_format() {
+        SampleFormat::F32 => build_stream::<f32>(&device, &config, mixer.clone())?,
+        SampleFormat::I16 => build_stream::<i16>(&device, &config, mixer.clone())?,
+        SampleFormat::U16 => build_stream::<u16>(&device, &config, mixer.clone())?,
+        SampleFormat::I32 => build_stream::<i32>(&device, &config, mixer.clone())?,
+        format => return Err(format!("unsupported sample format {format}").into()),
+    };
+    stream.play()?;
+
+    Ok((stream, mixer))
+}
+
+fn build_stream<T: SizedSample + FromSample<f32>>(
+    device: &cpal::Device,
+    config: &StreamConfig,
+    mixer: AudioMixer,
+) -> Result<Stream, cpal::BuildStreamError> {
+    let channels = config.channels as usize;
+    let mut buffer = vec![];
+
+    device.build_output_stream(
+        config,
+        move |data: &mut [T], _| {
+            buffer.resize(data.len() / channels * CHANNELS, 0.0);
+            mixer.mix(&mut buffer);
+
+            for (frame, stereo) in data.chunks_mut(channels).zip(buffer.chunks(CHANNELS)) {
+                for (channel, sample) in frame.iter_mut().enumerate() {
+                    let value = match channels {
+                        1 => (stereo[0] + stereo[1]) * 0.5,
+                        _ => stereo.get(channel).copied().unwrap_or(0.0),
+                    };
+                    *sample = T::from_sample(value);
+                }
+            }
+        },
+        |err| tracing::warn!("Audio output error: {err}"),
+        None,
+    )
+}
+
+fn spawn_null_sink(mixer: AudioMixer, shutdown: Arc<AtomicBool>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let frames = (mixer.sample_rate as f64 * NULL_SINK_PERIOD.as_secs_f64()) as usize;
+        let mut buffer = vec![0.0; frames * CHANNELS];
+
+        while !shutdown.load(Ordering::Relaxed) {
+            mixer.mix(&mut buffer);
+            thread::sleep(NULL_SINK_PERIOD);
+        }
+    })
+}
@@ -0,0 +1,255 @@
+use std::fs::File;
+use std::io::{Cursor, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::{DecoderOptions, CODEC_TYPE_NULL};
+use symphonia::core::errors::Error;
+use symphonia::core::formats::{FormatOptions, FormatReader, SeekMode, SeekTo};
+use symphonia::core::io::{MediaSource, MediaSourceStream};
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+use symphonia::core::units::Time;
+
+use crate::audio::{Chunk, TrackShared, CHANNELS};
+use crate::noise::Rng;
+
+/// What happens once a sound of a track ends
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlaybackMode {
+    /// Plays the sounds in order, forever
+    Loop,
+    /// Picks a random sound every time the previous one ends
+    Random,
+    /// Plays the sounds in order once
+    Single,
+}
+
+impl PlaybackMode {
+    /// Parses the `playbackmode` of scene sound objects, anything unknown loops like in Wallpaper
+    /// Engine
+    pub fn from_scene(mode: &str) -> Self {
+        match mode.to_lowercase().as_str() {
+            "random" => PlaybackMode::Random,
+            "single" => PlaybackMode::Single,
+            _ => PlaybackMode::Loop,
+        }
+    }
+}
+
+pub enum SoundInput {
+    File(PathBuf),
+    /// Sound extracted from a scene package, the name is used as a format hint
+    Memory {
+        name: String,
+        bytes: Arc<[u8]>,
+    },
+}
+
+impl SoundInput {
+    fn name(&self) -> String {
+        match self {
+            SoundInput::File(path) => path.to_string_lossy().to_string(),
+            SoundInput::Memory { name, .. } => name.clone(),
+        }
+    }
+
+    fn extension(&self) -> Option<String> {
+        let path = match self {
+            SoundInput::File(path) => path.as_path(),
+            SoundInput::Memory { name, .. } => Path::new(name),
+        };
+
+        path.extension()
+            .map(|extension| extension.to_string_lossy().to_string())
+    }
+
+    fn open(&self) -> std::io::Result<Box<dyn MediaSource>> {
+        Ok(match self {
+            SoundInput::File(path) => Box::new(File::open(path)?),
+            SoundInput::Memory { bytes, .. } => Box::new(Cursor::new(bytes.clone())),
+        })
+    }
+}
+
+pub fn spawn_decoding_thread(
+    inputs: Vec<SoundInput>,
+    mode: PlaybackMode,
+    shared: Arc<TrackShared>,
+) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(1, |time| time.as_nanos() as u64);
+        let mut rng = Rng::new(seed);
+
+        let mut index = match mode {
+            PlaybackMode::Random => rng.next_u64() as usize % inputs.len().max(1),
+            _ => 0,
+        };
+
+        while !shared.is_shutdown() {
+            let Some(input) = inputs.get(index) else {
+                break;
+            };
+
+            // Don't spin on broken or empty files, a track that can't be decoded just ends
+            match decode(input, &shared) {
+                Ok(true) => {}
+                Ok(false) => {
+                    tracing::debug!("No audio decoded from {}", input.name());
+                    break;
+                }
+                Err(err) => {
+                    tracing::warn!("Failed to decode audio from {}: {err}", input.name());
+                    break;
+                }
+            }
+
+            index = match mode {
+                PlaybackMode::Loop => (index + 1) % inputs.len(),
+                PlaybackMode::Random => rng.next_u64() as usize % inputs.len(),
+                PlaybackMode::Single => index + 1,
+            };
+        }
+
+        shared.set_finished();
+        tracing::debug!("Exited audio decoding thread");
+    })
+}
+
+/// Whether the input has an audio track that can be decoded, only reads the container headers
+pub fn has_audio(input: &SoundInput) -> bool {
+    let codecs = symphonia::default::get_codecs();
+
+    match open_format(input) {
+        Ok(format) => format.tracks().iter().any(|track| {
+            track.codec_params.codec != CODEC_TYPE_NULL
+                && codecs.get_codec(track.codec_params.codec).is_some()
+        }),
+        Err(err) => {
+            tracing::debug!("No audio in {}: {err}", input.name());
+            false
+        }
+    }
+}
+
+fn open_format(input: &SoundInput) -> Result<Box<dyn FormatReader>, Error> {
+    let stream = MediaSourceStream::new(input.open()?, Default::default());
+
+    let mut hint = Hint::new();
+    if let Some(extension) = input.extension() {
+        hint.with_extension(&extension);
+    }
+
+    Ok(symphonia::default::get_probe()
+        .format(
+            &hint,
+            stream,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )?
+        .format)
+}
+
+/// Decodes the first audio track of an input until it ends or the track is dropped, returns
+/// whether any audio was decoded
+fn decode(input: &SoundInput, shared: &TrackShared) -> Result<bool, Error> {
+    let mut format = open_format(input)?;
+
+    let track = format
+        .tracks()
+        .iter()
+        .find(|track| track.codec_params.codec != CODEC_TYPE_NULL)
+        .ok_or(Error::Unsupported("no audio track"))?;
+    let track_id = track.id;
+    let time_base = track.codec_params.time_base;
+
+    let mut decoder =
+        symphonia::default::get_codecs().make(&track.codec_params, &DecoderOptions::default())?;
+    let mut buffer: Option<SampleBuffer<f32>> = None;
+    let mut decoded_any = false;
+
+    while !shared.is_shutdown() {
+        if let Some(time) = shared.take_seek() {
+            let to = SeekTo::Time {
+                time: Time::from(time.max(0.0)),
+                track_id: Some(track_id),
+            };
+
+            match format.seek(SeekMode::Coarse, to) {
+                Ok(_) => decoder.reset(),
+                Err(err) => tracing::warn!("Failed to seek {}: {err}", input.name()),
+            }
+        }
+
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            Err(Error::IoError(err)) if err.kind() == ErrorKind::UnexpectedEof => break,
+            Err(Error::ResetRequired) => break,
+            Err(err) => return Err(err),
+        };
+
+        if packet.track_id() != track_id {
+            continue;
+        }
+
+        let decoded = match decoder.decode(&packet) {
+            Ok(decoded) => decoded,
+            Err(Error::DecodeError(err)) => {
+                tracing::debug!("Skipping corrupted audio packet: {err}");
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+
+        // Decoders may change the channel layout or packet size, so compare samples, not frames
+        let spec = *decoded.spec();
+        let samples = decoded.capacity() * spec.channels.count();
+        if buffer
+            .as_ref()
+            .map_or(true, |buffer| buffer.capacity() < samples)
+        {
+            buffer = Some(SampleBuffer::new(decoded.capacity() as u64, spec));
+        }
+
+        let buffer = buffer.as_mut().unwrap();
+        buffer.copy_interleaved_ref(decoded);
+
+        let time = time_base.map_or(0.0, |time_base| {
+            let time = time_base.calc_time(packet.ts());
+            time.seconds as f64 + time.frac
+        });
+
+        let chunk = Chunk {
+            time,
+            sample_rate: spec.rate,
+            samples: to_stereo(buffer.samples(), spec.channels.count()),
+        };
+
+        if !shared.push(chunk) {
+            break;
+        }
+        decoded_any = true;
+    }
+
+    Ok(decoded_any)
+}
+
+fn to_stereo(samples: &[f32], channels: usize) -> Vec<f32> {
+    match channels {
+        0 => vec![],
+        1 => samples
+            .iter()
+            .flat_map(|&sample| [sample; CHANNELS])
+            .collect(),
+        _ => samples
+            .chunks_exact(channels)
+            .flat_map(|frame| [frame[0], frame[1]])
+            .collect(),
+    }
+}
@@ -0,0 +1,341 @@
+use std::ptr::null;
+
+use gl::types::{GLint, GLsizei, GLuint};
+
+use crate::gl_utils::{create_program, set_float, set_int, set_vec2, FULLSCREEN_VERTEX_SHADER_SRC};
+
+// Mip levels stop when they get smaller than this, blurring further doesn't add anything visible
+const MIN_MIP_SIZE: u32 = 8;
+const MAX_MIP_LEVELS: usize = 6;
+
+const DOWNSAMPLE_SHADER_SRC: &str = r#"
+    #version 330 core
+
+    out vec4 out_color;
+
+    in vec2 uv;
+
+    uniform sampler2D source;
+    uniform vec2 texel_size;
+    uniform bool prefilter;
+    uniform float threshold;
+
+    void main()
+    {
+        vec4 d = texel_size.xyxy * vec4(-1.0, -1.0, 1.0, 1.0);
+        vec3 color = (texture(source, uv + d.xy).rgb + texture(source, uv + d.zy).rgb
+            + texture(source, uv + d.xw).rgb + texture(source, uv + d.zw).rgb) * 0.25;
+
+        if (prefilter) {
+            // Soft knee so areas right above the threshold don't pop in
+            float brightness = max(color.r, max(color.g, color.b));
+            float knee = threshold * 0.5;
+            float soft = clamp(brightness - threshold + knee, 0.0, 2.0 * knee);
+            soft = soft * soft / (4.0 * knee + 0.0001);
+            color *= max(soft, brightness - threshold) / max(brightness, 0.0001);
+        }
+
+        out_color = vec4(color, 1.0);
+    }
+"#;
+
+const UPSAMPLE_SHADER_SRC: &str = r#"
+    #version 330 core
+
+    out vec4 out_color;
+
+    in vec2 uv;
+
+    uniform sampler2D source;
+    uniform vec2 texel_size;
+
+    void main()
+    {
+        // 3x3 tent filter
+        vec4 d = texel_size.xyxy * vec4(1.0, 1.0, -1.0, 0.0);
+
+        vec3 color = texture(source, uv - d.xy).rgb;
+        color += texture(source, uv - d.wy).rgb * 2.0;
+        color += texture(source, uv - d.zy).rgb;
+        color += texture(source, uv + d.zw).rgb * 2.0;
+        color += texture(source, uv).rgb * 4.0;
+        color += texture(source, uv + d.xw).rgb * 2.0;
+        color += texture(source, uv + d.zy).rgb;
+        color += texture(source, uv + d.wy).rgb * 2.0;
+        color += texture(source, uv + d.xy).rgb;
+
+        out_color = vec4(color / 16.0, 1.0);
+    }
+"#;
+
+const COMPOSITE_SHADER_SRC: &str = r#"
+    #version 330 core
+
+    out vec4 out_color;
+
+    in vec2 uv;
+
+    uniform sampler2D scene;
+    uniform sampler2D bloom;
+    uniform float strength;
+
+    // Identity up to the shoulder so regular colors are untouched, then rolls off towards white
+    vec3 tonemap(vec3 color)
+    {
+        const float shoulder = 0.8;
+        vec3 over = max(color - shoulder, 0.0);
+        return min(color, shoulder) + over / (1.0 + over / (1.0 - shoulder));
+    }
+
+    void main()
+    {
+        vec3 color = texture(scene, uv).rgb + texture(bloom, uv).rgb * strength;
+        out_color = vec4(tonemap(color), 1.0);
+    }
+"#;
+
+#[derive(Debug, Clone, Copy)]
+pub struct BloomSettings {
+    pub strength: f32,
+    pub threshold: f32,
+}
+
+struct MipLevel {
+    texture: GLuint,
+    width: u32,
+    height: u32,
+}
+
+/// Renders the scene in an HDR framebuffer, then adds a blurred copy of its brightest areas on top
+/// when composing it onto the output surface
+///
+/// The blur is a downsample chain followed by an additive upsample chain, which gives a wide and
+/// smooth glow for a few cheap passes.
+pub struct BloomPass {
+    downsample_program: GLuint,
+    upsample_program: GLuint,
+    composite_program: GLuint,
+    vao: GLuint,
+
+    framebuffer: GLuint,
+    // Bound when `begin` was called, composed onto by `end`
+    target: GLuint,
+    scene_texture: GLuint,
+    mips: Vec<MipLevel>,
+    size: (u32, u32),
+}
+
+impl BloomPass {
+    pub fn new() -> Self {
+        let mut vao: GLuint = 0;
+        let mut framebuffer: GLuint = 0;
+
+        unsafe {
+            gl::GenVertexArrays(1, &mut vao);
+            gl::GenFramebuffers(1, &mut framebuffer);
+        }
+
+        Self {
+            downsample_program: create_program(FULLSCREEN_VERTEX_SHADER_SRC, DOWNSAMPLE_SHADER_SRC),
+            upsample_program: create_program(FULLSCREEN_VERTEX_SHADER_SRC, UPSAMPLE_SHADER_SRC),
+            composite_program: create_program(FULLSCREEN_VERTEX_SHADER_SRC, COMPOSITE_SHADER_SRC),
+            vao,
+            framebuffer,
+            target: 0,
+            scene_texture: 0,
+            mips: vec![],
+            size: (0, 0),
+        }
+    }
+
+    /// Redirects drawing to the HDR scene framebuffer, resized to the output if needed
+    pub fn begin(&mut self, width: u32, height: u32, clear_color: (f32, f32, f32)) {
+        if self.size != (width, height) {
+            self.resize(width, height);
+        }
+
+        unsafe {
+            let mut target: GLint = 0;
+            gl::GetIntegerv(gl::FRAMEBUFFER_BINDING, &mut target);
+            self.target = target as GLuint;
+
+            gl::BindFramebuffer(gl::FRAMEBUFFER, self.framebuffer);
+            attach(self.scene_texture);
+            gl::Viewport(0, 0, width as GLsizei, height as GLsizei);
+
+            gl::ClearColor(clear_color.0, clear_color.1, clear_color.2, 1.0);
+            gl::Clear(gl::COLOR_BUFFER_BIT);
+        }
+    }
+
+    /// Runs the bloom passes and composes the result onto the framebuffer bound before `begin`
+    pub fn end(&mut self, settings: &BloomSettings) {
+        let (width, height) = self.size;
+
+        unsafe {
+            gl::BindVertexArray(self.vao);
+            gl::ActiveTexture(gl::TEXTURE0);
+
+            gl::UseProgram(self.downsample_program);
+            set_int(self.downsample_program, "source", 0);
+            set_float(self.downsample_program, "threshold", settings.threshold);
+
+            let mut source = (self.scene_texture, width, height);
+            for (i, mip) in self.mips.iter().enumerate() {
+                set_int(self.downsample_program, "prefilter", (i == 0) as GLint);
+                set_vec2(
+                    self.downsample_program,
+                    "texel_size",
+                    1.0 / source.1 as f32,
+                    1.0 / source.2 as f32,
+                );
+                self.draw_into(mip, source.0);
+
+                source = (mip.texture, mip.width, mip.height);
+            }
+
+            gl::UseProgram(self.upsample_program);
+            set_int(self.upsample_program, "source", 0);
+
+            gl::Enable(gl::BLEND);
+            gl::BlendFunc(gl::ONE, gl::ONE);
+
+            for pair in self.mips.windows(2).rev() {
+                let (target, source) = (&pair[0], &pair[1]);
+                set_vec2(
+                    self.upsample_program,
+                    "texel_size",
+                    1.0 / source.width as f32,
+                    1.0 / source.height as f32,
+                );
+                self.draw_into(target, source.texture);
+            }
+
+            gl::Disable(gl::BLEND);
+
+            gl::BindFramebuffer(gl::FRAMEBUFFER, self.target);
+            gl::Viewport(0, 0, width as GLsizei, height as GLsizei);
+
+            gl::UseProgram(self.composite_program);
+            set_int(self.composite_program, "scene", 0);
+            set_int(self.composite_program, "bloom", 1);
+            set_float(self.composite_program, "strength", settings.strength);
+
+            gl::BindTexture(gl::TEXTURE_2D, self.scene_texture);
+            gl::ActiveTexture(gl::TEXTURE1);
+            gl::BindTexture(
+                gl::TEXTURE_2D,
+                self.mips.first().map_or(0, |mip| mip.texture),
+            );
+
+            gl::DrawArrays(gl::TRIANGLES, 0, 3);
+
+            gl::BindTexture(gl::TEXTURE_2D, 0);
+            gl::ActiveTexture(gl::TEXTURE0);
+            gl::BindTexture(gl::TEXTURE_2D, 0);
+            gl::UseProgram(0);
+            gl::BindVertexArray(0);
+        }
+    }
+
+    unsafe fn draw_into(&self, target: &MipLevel, source: GLuint) {
+        attach(target.texture);
+        gl::Viewport(0, 0, target.width as GLsizei, target.height as GLsizei);
+        gl::BindTexture(gl::TEXTURE_2D, source);
+        gl::DrawArrays(gl::TRIANGLES, 0, 3);
+    }
+
+    /// Reallocates the framebuffers for an output size, `begin` does it when needed
+    pub fn resize(&mut self, width: u32, height: u32) {
+        tracing::debug!("Resizing bloom framebuffers to {width}x{height}");
+        self.delete_textures();
+
+        self.scene_texture = create_hdr_texture(width, height);
+
+        let (mut mip_width, mut mip_height) = (width / 2, height / 2);
+        while self.mips.len() < MAX_MIP_LEVELS && mip_width.min(mip_height) >= MIN_MIP_SIZE {
+            self.mips.push(MipLevel {
+                texture: create_hdr_texture(mip_width, mip_height),
+                width: mip_width,
+                height: mip_height,
+            });
+
+            mip_width /= 2;
+            mip_height /= 2;
+        }
+
+        self.size = (width, height);
+    }
+
+    fn delete_textures(&mut self) {
+        unsafe {
+            if self.scene_texture != 0 {
+                gl::DeleteTextures(1, &self.scene_texture);
+                self.scene_texture = 0;
+            }
+
+            for mip in self.mips.drain(..) {
+                gl::DeleteTextures(1, &mip.texture);
+            }
+        }
+    }
+}
+
+impl Drop for BloomPass {
+    fn drop(&mut self) {
+        self.delete_textures();
+
+        unsafe {
+            gl::DeleteFramebuffers(1, &self.framebuffer);
+            gl::DeleteVertexArrays(1, &self.vao);
+            gl::DeleteProgram(self.downsample_program);
+            gl::DeleteProgram(self.upsample_program);
+            gl::DeleteProgram(self.composite_program);
+        }
+    }
+}
+
+fn create_hdr_texture(width: u32, height: u32) -> GLuint {
+    let mut texture: GLuint = 0;
+
+    unsafe {
+        gl::GenTextures(1, &mut texture);
+        gl::BindTexture(gl::TEXTURE_2D, texture);
+        gl::TexImage2D(
+            gl::TEXTURE_2D,
+            0,
+            gl::RGBA16F as GLint,
+            width as GLsizei,
+            height as GLsizei,
+            0,
+            gl::RGBA,
+            gl::FLOAT,
+            null(),
+        );
+        gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::LINEAR as GLint);
+        gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::LINEAR as GLint);
+        gl::TexParameteri(
+            gl::TEXTURE_2D,
+            gl::TEXTURE_WRAP_S,
+            gl::CLAMP_TO_EDGE as GLint,
+        );
+        gl::TexParameteri(
+            gl::TEXTURE_2D,
+            gl::TEXTURE_WRAP_T,
+            gl::CLAMP_TO_EDGE as GLint,
+        );
+        gl::BindTexture(gl::TEXTURE_2D, 0);
+    }
+
+    texture
+}
+
+unsafe fn attach(texture: GLuint) {
+    gl::FramebufferTexture2D(
+        gl::FRAMEBUFFER,
+        gl::COLOR_ATTACHMENT0,
+        gl::TEXTURE_2D,
+        texture,
+        0,
+    );
+}
@@ -0,0 +1,271 @@
+use std::ffi::{c_void, CString};
+use std::ptr::null;
+
+use cgmath::{InnerSpace, Matrix4, Vector2, Vector3, Zero};
+use gl::types::{GLfloat, GLsizeiptr, GLuint};
+
+use crate::gl_utils::{compile_shader, link_program};
+use crate::noise::fractal_noise;
+use crate::scene::General;
+
+// Parallax offset of a depth 1 object when the pointer is at the edge of the output, as a fraction
+// of the scene size
+const PARALLAX_RANGE: f32 = 0.05;
+
+// Shake offset at amplitude 1, in scene units
+const SHAKE_RANGE: f32 = 10.0;
+const SHAKE_OCTAVES: u32 = 3;
+
+const FADE_DURATION: f32 = 1.0;
+// Distance to its target under which the parallax is at rest, a fraction of a pixel at the usual
+// parallax amounts
+const PARALLAX_REST_DISTANCE: f32 = 1e-3;
+
+#[rustfmt::skip]
+static FULLSCREEN_QUAD: [GLfloat; 8] = [
+    -1.0, -1.0,
+     1.0, -1.0,
+    -1.0,  1.0,
+     1.0,  1.0,
+];
+
+const VERTEX_SHADER_SRC: &str = r#"
+    #version 330 core
+
+    layout (location = 0) in vec2 aPos;
+
+    void main()
+    {
+        gl_Position = vec4(aPos, 0.0, 1.0);
+    }
+"#;
+
+const FRAGMENT_SHADER_SRC: &str = r#"
+    #version 330 core
+
+    out vec4 out_color;
+
+    uniform vec4 color;
+
+    void main()
+    {
+        out_color = color;
+    }
+"#;
+
+/// Camera behaviours configured in the `general` section of a scene
+///
+/// Parallax follows the pointer with some delay and moves every object proportionally to its
+/// parallax depth, shake moves the whole scene with fractal noise, and fade brings the scene in
+/// from the clear color once it's loaded.
+pub struct SceneCamera {
+    parallax: Option<Parallax>,
+    shake: Option<Shake>,
+    fade: bool,
+
+    scene_size: Vector2<f32>,
+    pointer: Option<Vector2<f32>>,
+    parallax_position: Vector2<f32>,
+    shake_time: f32,
+    elapsed: f32,
+}
+
+struct Parallax {
+    amount: f32,
+    delay: f32,
+    mouse_influence: f32,
+}
+
+struct Shake {
+    amplitude: f32,
+    roughness: f32,
+    speed: f32,
+}
+
+impl SceneCamera {
+    pub fn new(general: &General, scene_size: Vector2<f32>) -> Self {
+        let parallax = general.cameraparallax.then(|| Parallax {
+            amount: general.cameraparallaxamount as f32,
+            delay: general.cameraparallaxdelay as f32,
+            mouse_influence: general.cameraparallaxmouseinfluence as f32,
+        });
+
+        let shake = general.camerashake.then(|| Shake {
+            amplitude: general.camerashakeamplitude as f32,
+            roughness: general.camerashakeroughness as f32,
+            speed: general.camerashakespeed as f32,
+        });
+
+        Self {
+            parallax,
+            shake,
+            fade: general.camerafade,
+            scene_size,
+            pointer: None,
+            parallax_position: Vector2::zero(),
+            shake_time: 0.0,
+            elapsed: 0.0,
+        }
+    }
+
+    /// Pointer position normalized to [0, 1] with y pointing down, as reported by the output
+    pub fn set_pointer(&mut self, position: Option<Vector2<f32>>) {
+        self.pointer = position;
+    }
+
+    pub fn update(&mut self, dt: f32) {
+        self.elapsed += dt;
+
+        if let Some(parallax) = &self.parallax {
+            let target = self.parallax_target(parallax);
+            let t = if parallax.delay > 0.0 {
+                1.0 - (-dt / parallax.delay).exp()
+            } else {
+                1.0
+            };
+            self.parallax_position += (target - self.parallax_position) * t;
+        }
+
+        if let Some(shake) = &self.shake {
+            self.shake_time += dt * shake.speed;
+        }
+    }
+
+    /// False once the camera stopped moving, until the pointer moves again
+    pub fn is_animated(&self) -> bool {
+        let fading = self.fade && self.elapsed < FADE_DURATION;
+        let following = self.parallax.as_ref().is_some_and(|parallax| {
+            let distance = (self.parallax_target(parallax) - self.parallax_position).magnitude();
+            distance > PARALLAX_REST_DISTANCE
+        });
+
+        self.shake.is_some() || fading || following
+    }
+
+    // Centered on the output with y pointing up like the scene, back to rest without pointer
+    fn parallax_target(&self, parallax: &Parallax) -> Vector2<f32> {
+        self.pointer
+            .map(|pointer| Vector2::new(pointer.x - 0.5, 0.5 - pointer.y) * 2.0)
+            .unwrap_or_else(Vector2::zero)
+            * parallax.mouse_influence
+    }
+
+    /// View matrix shared by every object, only affected by the shake
+    pub fn view(&self) -> Matrix4<f32> {
+        let Some(shake) = &self.shake else {
+            return Matrix4::from_scale(1.0);
+        };
+
+        let roughness = shake.roughness.clamp(0.0, 1.0);
+        let offset = Vector3::new(
+            fractal_noise(self.shake_time, 0.0, 0.0, SHAKE_OCTAVES, roughness),
+            fractal_noise(self.shake_time, 17.3, 0.0, SHAKE_OCTAVES, roughness),
+            0.0,
+        ) * shake.amplitude
+            * SHAKE_RANGE;
+
+        Matrix4::from_translation(offset)
+    }
+
+    /// Translation applied to an object depending on its parallax depth
+    pub fn parallax(&self, depth: Vector2<f32>) -> Matrix4<f32> {
+        let Some(parallax) = &self.parallax else {
+            return Matrix4::from_scale(1.0);
+        };
+
+        // Deeper objects move further, against the pointer like when looking around
+        let offset = -self.parallax_position * parallax.amount * PARALLAX_RANGE;
+
+        Matrix4::from_translation(Vector3::new(
+            offset.x * depth.x * self.scene_size.x,
+            offset.y * depth.y * self.scene_size.y,
+            0.0,
+        ))
+    }
+
+    /// Opacity of the clear color drawn over the scene while it fades in
+    pub fn fade_opacity(&self) -> f32 {
+        if self.fade {
+            (1.0 - self.elapsed / FADE_DURATION).clamp(0.0, 1.0)
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Fullscreen quad used to fade the scene in from a solid color
+pub struct FadeRenderer {
+    program: GLuint,
+    vao: GLuint,
+    vbo: GLuint,
+}
+
+impl FadeRenderer {
+    pub fn new() -> Self {
+        unsafe {
+            let vertex_shader = compile_shader(VERTEX_SHADER_SRC, gl::VERTEX_SHADER);
+            let fragment_shader = compile_shader(FRAGMENT_SHADER_SRC, gl::FRAGMENT_SHADER);
+            let program = link_program(vertex_shader, fragment_shader);
+
+            let pointer = CString::new("out_color").unwrap();
+            gl::BindFragDataLocation(program, 0, pointer.as_ptr());
+
+            gl::DeleteShader(vertex_shader);
+            gl::DeleteShader(fragment_shader);
+
+            let (mut vao, mut vbo): (GLuint, GLuint) = (0, 0);
+            gl::GenVertexArrays(1, &mut vao);
+            gl::BindVertexArray(vao);
+
+            gl::GenBuffers(1, &mut vbo);
+            gl::BindBuffer(gl::ARRAY_BUFFER, vbo);
+            gl::BufferData(
+                gl::ARRAY_BUFFER,
+                std::mem::size_of_val(&FULLSCREEN_QUAD) as GLsizeiptr,
+                FULLSCREEN_QUAD.as_ptr() as *const c_void,
+                gl::STATIC_DRAW,
+            );
+            gl::VertexAttribPointer(0, 2, gl::FLOAT, gl::FALSE, 0, null());
+            gl::EnableVertexAttribArray(0);
+
+            gl::BindBuffer(gl::ARRAY_BUFFER, 0);
+            gl::BindVertexArray(0);
+
+            Self { program, vao, vbo }
+        }
+    }
+
+    pub fn draw(&self, color: (f32, f32, f32), opacity: f32) {
+        if opacity <= 0.0 {
+            return;
+        }
+
+        unsafe {
+            gl::BindVertexArray(self.vao);
+            gl::UseProgram(self.program);
+
+            let color_name = CString::new("color").unwrap();
+            let color_location = gl::GetUniformLocation(self.program, color_name.as_ptr());
+            gl::Uniform4f(color_location, color.0, color.1, color.2, opacity);
+
+            gl::Enable(gl::BLEND);
+            gl::BlendFunc(gl::SRC_ALPHA, gl::ONE_MINUS_SRC_ALPHA);
+
+            gl::DrawArrays(gl::TRIANGLE_STRIP, 0, 4);
+
+            gl::Disable(gl::BLEND);
+            gl::UseProgram(0);
+            gl::BindVertexArray(0);
+        }
+    }
+}
+
+impl Drop for FadeRenderer {
+    fn drop(&mut self) {
+        unsafe {
+            gl::DeleteBuffers(1, &self.vbo);
+            gl::DeleteVertexArrays(1, &self.vao);
+            gl::DeleteProgram(self.program);
+        }
+    }
+}
@@ -0,0 +1,435 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+
+use serde::Deserialize;
+use smithay_client_toolkit::output::OutputInfo;
+
+use waypaper_engine_shared::ipc::{
+    DecoderBackend, OcclusionPolicy, Scaling, ShellLayer, Transition,
+};
+
+use crate::power::PowerAction;
+use crate::wallpaper_renderer::OutputSettings;
+use crate::wl_renderer::LayerOptions;
+
+/// Daemon configuration, read at startup from `$XDG_CONFIG_HOME/waypaper-engine/config.toml`
+///
+/// ```toml
+/// watch_output_power = true
+/// spans = [["DP-1", "DP-2", "DP-3"]]
+///
+/// [default]
+/// volume = 0.5
+///
+/// [outputs.DP-1]
+/// wallpaper = 1234567890
+/// bloom = false
+/// scaling = { mode = "contain", background = [0.1, 0.1, 0.1] }
+/// playback_rate = 0.5
+/// loop_range = [10.0, 25.0]
+/// occlusion = "slow_down"
+/// max_fps = 30
+/// bezel = [20, 20]
+/// layer = "bottom"
+/// namespace = "waypaper_engine_left"
+/// margin = [32, 0, 0, 0]
+/// transition = { effect = "grow_circle", duration_ms = 800, easing = "ease_out" }
+///
+/// [outputs."DELL U2720Q"]
+/// volume = 0
+///
+/// [video]
+/// decoders = ["vaapi", "software"]
+/// queue_memory_mb = 64
+///
+/// [[power.rules]]
+/// on_battery = true
+/// action = { max_fps = 30 }
+///
+/// [[power.rules]]
+/// below_percent = 20
+/// action = "static_frame"
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Applied to every output, before the output specific settings
+    pub default: OutputConfig,
+    /// Settings by output name, description, make and model, model or make. Only the most
+    /// specific entry matching an output applies, in that order.
+    pub outputs: HashMap<String, OutputConfig>,
+    pub video: VideoConfig,
+    pub power: PowerConfig,
+    /// Outputs showing a single wallpaper across them, their wallpaper is still set per output
+    pub spans: Vec<Vec<String>>,
+    /// Pauses wallpapers on outputs turned off through wlr-output-power-management. Off by
+    /// default as compositors let a single client watch the power of an output, which keeps idle
+    /// daemons from turning it off.
+    pub watch_output_power: bool,
+}
+
+/// Output settings overrides, unset fields keep their previous value
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutputConfig {
+    /// Workshop id of the wallpaper shown when the output is connected, unless another one was
+    /// set on it since the daemon started
+    pub wallpaper: Option<u64>,
+    pub bloom: Option<bool>,
+    pub volume: Option<f32>,
+    pub muted: Option<bool>,
+    pub scaling: Option<Scaling>,
+    pub paused: Option<bool>,
+    pub playback_rate: Option<f32>,
+    pub loop_range: Option<(f64, f64)>,
+    /// What happens while the output is covered by a fullscreen window or turned off
+    pub occlusion: Option<OcclusionPolicy>,
+    /// 0 lifts a cap set in `default`
+    pub max_fps: Option<u32>,
+    pub bezel: Option<(u32, u32)>,
+    pub layer: Option<ShellLayer>,
+    /// Layer-shell namespace, lets compositor rules tell outputs apart
+    pub namespace: Option<String>,
+    /// Top, right, bottom and left margins of the layer, in logical pixels
+    pub margin: Option<(i32, i32, i32, i32)>,
+    /// Between the previous and the next wallpaper, when the IPC request doesn't choose one
+    pub transition: Option<Transition>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VideoConfig {
+    /// Tried in order until one of them can decode the video
+    pub decoders: Vec<DecoderBackend>,
+    /// Memory the frames decoded ahead of time may take per video, in MiB. Fewer frames are
+    /// queued for high resolution videos.
+    pub queue_memory_mb: usize,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PowerConfig {
+    /// Read instead of UPower and power-profiles-daemon, with the fields of `PowerState`
+    pub state_file: Option<PathBuf>,
+    /// How often the state file is read, the system bus tells when the power state changes
+    pub poll_interval_secs: u64,
+    /// The most restrictive action of the rules matching the power state applies to every output
+    pub rules: Vec<PowerRule>,
+}
+
+/// Conditions left unset match any state
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PowerRule {
+    pub on_battery: Option<bool>,
+    /// Matches when the battery charge is below this percentage
+    pub below_percent: Option<f64>,
+    /// power-profiles-daemon profile, like "power-saver"
+    pub profile: Option<String>,
+    pub action: PowerAction,
+}
+
+impl Default for PowerConfig {
+    fn default() -> Self {
+        Self {
+            state_file: None,
+            poll_interval_secs: 5,
+            rules: Vec::new(),
+        }
+    }
+}
+
+impl Default for VideoConfig {
+    fn default() -> Self {
+        Self {
+            decoders: vec![
+                DecoderBackend::Vaapi,
+                DecoderBackend::Vulkan,
+                DecoderBackend::Vdpau,
+                DecoderBackend::Software,
+            ],
+            queue_memory_mb: 128,
+        }
+    }
+}
+
+impl Config {
+    pub fn load() -> Self {
+        let Some(path) = config_path() else {
+            tracing::warn!("Neither XDG_CONFIG_HOME nor HOME is set, using default config");
+            return Self::default();
+        };
+
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                tracing::debug!("No config file at {}", path.to_string_lossy());
+                return Self::default();
+            }
+            Err(err) => {
+                tracing::warn!("Failed to read {}: {err}", path.to_string_lossy());
+                return Self::default();
+            }
+        };
+
+        match toml::from_str(&contents) {
+            Ok(config) => {
+                tracing::info!("Loaded config from {}", path.to_string_lossy());
+                config
+            }
+            Err(err) => {
+                tracing::warn!(
+                    "Invalid config {}, using defaults: {err}",
+                    path.to_string_lossy()
+                );
+                Self::default()
+            }
+        }
+    }
+
+    /// Settings an output starts with before any IPC request changes them
+    pub fn output_settings(&self, output: &OutputIdentity) -> OutputSettings {
+        let mut settings = OutputSettings::default();
+
+        self.default.apply(&mut settings);
+        if let Some(config) = self.output_config(output) {
+            config.apply(&mut settings);
+        }
+
+        settings
+    }
+
+    /// Layer surface an output starts with before any IPC request changes it
+    pub fn layer_options(&self, output: &OutputIdentity) -> LayerOptions {
+        let mut options = LayerOptions::default();
+
+        self.default.apply_layer(&mut options);
+        if let Some(config) = self.output_config(output) {
+            config.apply_layer(&mut options);
+        }
+
+        options
+    }
+
+    /// Wallpaper to show on an output when nothing was set on it yet
+    pub fn output_wallpaper(&self, output: &OutputIdentity) -> Option<u64> {
+        self.output_config(output)
+            .and_then(|config| config.wallpaper)
+            .or(self.default.wallpaper)
+    }
+
+    fn output_config(&self, output: &OutputIdentity) -> Option<&OutputConfig> {
+        self.outputs
+            .iter()
+            .filter_map(|(key, config)| output.match_rank(key).map(|rank| (rank, config)))
+            .max_by_key(|(rank, _)| *rank)
+            .map(|(_, config)| config)
+    }
+}
+
+/// What the keys of the `outputs` table are matched against
+#[derive(Debug, Default, Clone)]
+pub struct OutputIdentity {
+    pub name: String,
+    pub make: String,
+    pub model: String,
+    // Usually made of the make, model, serial number and name, which tells identical screens
+    // apart
+    pub description: String,
+}
+
+impl OutputIdentity {
+    /// For outputs that aren't connected, which can only be matched by name
+    pub fn from_name(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            ..Self::default()
+        }
+    }
+
+    /// How specifically `key` designates the output, `None` when it doesn't
+    fn match_rank(&self, key: &str) -> Option<u8> {
+        // Keys are compared to whole fields, as make, model and serial number can't be told apart
+        // in the description when they contain spaces
+        if key == self.name {
+            Some(4)
+        } else if !self.description.is_empty() && key == self.description {
+            Some(3)
+        } else if !self.make.is_empty() && key == format!("{} {}", self.make, self.model) {
+            Some(2)
+        } else if !self.model.is_empty() && key == self.model {
+            Some(1)
+        } else if !self.make.is_empty() && key == self.make {
+            Some(0)
+        } else {
+            None
+        }
+    }
+}
+
+impl From<&OutputInfo> for OutputIdentity {
+    fn from(info: &OutputInfo) -> Self {
+        Self {
+            name: info.name.clone().unwrap_or_default(),
+            make: info.make.clone(),
+            model: info.model.clone(),
+            description: info.description.clone().unwrap_or_default(),
+        }
+    }
+}
+
+impl OutputConfig {
+    fn apply(&self, settings: &mut OutputSettings) {
+        if let Some(bloom) = self.bloom {
+            settings.bloom = bloom;
+        }
+        if let Some(volume) = self.volume {
+            settings.volume = volume;
+        }
+        if let Some(muted) = self.muted {
+            settings.muted = muted;
+        }
+        if let Some(scaling) = self.scaling {
+            settings.scaling = scaling;
+        }
+        if let Some(paused) = self.paused {
+            settings.playback.paused = paused;
+        }
+        if let Some(rate) = self.playback_rate {
+            settings.playback.set_rate(rate);
+        }
+        if let Some(range) = self.loop_range {
+            settings.playback.set_loop_range(Some(range));
+        }
+        if let Some(occlusion) = self.occlusion {
+            settings.occlusion = occlusion;
+        }
+        if let Some(fps) = self.max_fps {
+            settings.max_fps = (fps > 0).then_some(fps);
+        }
+        if let Some(bezel) = self.bezel {
+            settings.bezel = bezel;
+        }
+        if let Some(transition) = self.transition {
+            settings.transition = transition;
+        }
+    }
+
+    fn apply_layer(&self, options: &mut LayerOptions) {
+        if let Some(layer) = self.layer {
+            options.layer = layer;
+        }
+        if let Some(namespace) = &self.namespace {
+            options.namespace = namespace.clone();
+        }
+        if let Some(margin) = self.margin {
+            options.margin = margin;
+        }
+    }
+}
+
+fn config_path() -> Option<PathBuf> {
+    let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+
+    Some(config_dir.join("waypaper-engine").join("config.toml"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dell(name: &str, serial: &str) -> OutputIdentity {
+        OutputIdentity {
+            name: name.to_string(),
+            make: "Dell Inc.".to_string(),
+            model: "DELL U2720Q".to_string(),
+            description: format!("Dell Inc. DELL U2720Q {serial} ({name})"),
+        }
+    }
+
+    fn config(keys: &[(&str, f32)]) -> Config {
+        let outputs = keys
+            .iter()
+            .map(|(key, volume)| {
+                let config = OutputConfig {
+                    volume: Some(*volume),
+                    ..OutputConfig::default()
+                };
+                (key.to_string(), config)
+            })
+            .collect();
+
+        Config {
+            outputs,
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn match_rank_prefers_specific_keys() {
+        let output = dell("DP-1", "ABC123");
+
+        assert_eq!(output.match_rank("DP-1"), Some(4));
+        assert_eq!(
+            output.match_rank("Dell Inc. DELL U2720Q ABC123 (DP-1)"),
+            Some(3)
+        );
+        assert_eq!(output.match_rank("Dell Inc. DELL U2720Q"), Some(2));
+        assert_eq!(output.match_rank("DELL U2720Q"), Some(1));
+        assert_eq!(output.match_rank("Dell Inc."), Some(0));
+    }
+
+    #[test]
+    fn match_rank_ignores_words_of_the_description() {
+        let output = dell("DP-1", "ABC123");
+
+        // Parts of the make and model, or a serial number, are not fields on their own
+        assert_eq!(output.match_rank("ABC123"), None);
+        assert_eq!(output.match_rank("Dell"), None);
+        assert_eq!(output.match_rank("U2720Q"), None);
+        assert_eq!(output.match_rank("(DP-1)"), None);
+    }
+
+    #[test]
+    fn match_rank_without_description() {
+        let output = OutputIdentity::from_name("DP-1");
+
+        assert_eq!(output.match_rank("DP-1"), Some(4));
+        assert_eq!(output.match_rank(""), None);
+        assert_eq!(output.match_rank(" "), None);
+    }
+
+    #[test]
+    fn output_config_tells_identical_screens_apart() {
+        let config = config(&[
+            ("DELL U2720Q", 0.1),
+            ("Dell Inc. DELL U2720Q ABC123 (DP-1)", 0.2),
+        ]);
+
+        let first = config.output_settings(&dell("DP-1", "ABC123"));
+        let second = config.output_settings(&dell("DP-2", "XYZ789"));
+
+        assert_eq!(first.volume, 0.2);
+        assert_eq!(second.volume, 0.1);
+    }
+
+    #[test]
+    fn output_config_prefers_the_name() {
+        let config = config(&[
+            ("DP-1", 0.3),
+            ("Dell Inc. DELL U2720Q ABC123 (DP-1)", 0.2),
+            ("Dell Inc.", 0.1),
+        ]);
+
+        assert_eq!(config.output_settings(&dell("DP-1", "ABC123")).volume, 0.3);
+        assert_eq!(
+            config.output_settings(&dell("HDMI-A-1", "ABC123")).volume,
+            0.1
+        );
+    }
+}
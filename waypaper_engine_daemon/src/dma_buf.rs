@@ -0,0 +1,146 @@
+use std::ffi::{c_void, CStr};
+use std::ptr::null_mut;
+use std::rc::Rc;
+
+use gl::types::{GLenum, GLuint};
+use khronos_egl as egl;
+use khronos_egl::{ClientBuffer, Context, Image};
+
+use crate::egl::EGLState;
+use crate::video_decoder::DmaBufLayer;
+
+// From EGL_EXT_image_dma_buf_import and EGL_EXT_image_dma_buf_import_modifiers
+const LINUX_DMA_BUF_EXT: egl::Enum = 0x3270;
+const LINUX_DRM_FOURCC_EXT: egl::Attrib = 0x3271;
+const DMA_BUF_PLANE0_FD_EXT: egl::Attrib = 0x3272;
+const DMA_BUF_PLANE0_OFFSET_EXT: egl::Attrib = 0x3273;
+const DMA_BUF_PLANE0_PITCH_EXT: egl::Attrib = 0x3274;
+const DMA_BUF_PLANE0_MODIFIER_LO_EXT: egl::Attrib = 0x3443;
+const DMA_BUF_PLANE0_MODIFIER_HI_EXT: egl::Attrib = 0x3444;
+
+const DRM_FORMAT_MOD_LINEAR: u64 = 0;
+const DRM_FORMAT_MOD_INVALID: u64 = 0x00ff_ffff_ffff_ffff;
+
+type ImageTargetTexture2D = unsafe extern "system" fn(target: GLenum, image: *const c_void);
+
+/// Imports DMA-BUFs as GL textures without copying them
+pub struct DmaBufImporter {
+    egl_state: Rc<EGLState>,
+    modifiers: bool,
+    image_target_texture: ImageTargetTexture2D,
+}
+
+/// Image backing a texture, which must not be sampled anymore once this is dropped
+pub struct DmaBufImage {
+    egl_state: Rc<EGLState>,
+    image: Image,
+}
+
+impl DmaBufImporter {
+    /// Needs a current GL context, returns `None` when the extensions needed are missing, like with
+    /// software rendering
+    pub fn new(egl_state: Rc<EGLState>) -> Option<Self> {
+        let egl_extensions = egl_state
+            .egl
+            .query_string(Some(egl_state.egl_display), egl::EXTENSIONS)
+            .ok()?
+            .to_string_lossy();
+        let has_egl_extension = |name: &str| egl_extensions.split(' ').any(|ext| ext == name);
+
+        if !has_egl_extension("EGL_EXT_image_dma_buf_import") {
+            tracing::info!(
+                "EGL_EXT_image_dma_buf_import not supported, video frames will be copied"
+            );
+            return None;
+        }
+
+        if !has_gl_extension("GL_OES_EGL_image") {
+            tracing::info!("GL_OES_EGL_image not supported, video frames will be copied");
+            return None;
+        }
+
+        let image_target_texture = egl_state
+            .egl
+            .get_proc_address("glEGLImageTargetTexture2DOES")?;
+
+        Some(Self {
+            modifiers: has_egl_extension("EGL_EXT_image_dma_buf_import_modifiers"),
+            image_target_texture: unsafe {
+                std::mem::transmute::<extern "system" fn(), ImageTargetTexture2D>(
+                    image_target_texture,
+                )
+            },
+            egl_state,
+        })
+    }
+
+    /// Binds a single plane layer to `texture`
+    pub fn import(&self, layer: &DmaBufLayer, texture: GLuint) -> Result<DmaBufImage, egl::Error> {
+        #[rustfmt::skip]
+        let mut attributes = vec![
+            egl::WIDTH as egl::Attrib, layer.width as egl::Attrib,
+            egl::HEIGHT as egl::Attrib, layer.height as egl::Attrib,
+            LINUX_DRM_FOURCC_EXT, layer.fourcc as egl::Attrib,
+            DMA_BUF_PLANE0_FD_EXT, layer.fd as egl::Attrib,
+            DMA_BUF_PLANE0_OFFSET_EXT, layer.offset as egl::Attrib,
+            DMA_BUF_PLANE0_PITCH_EXT, layer.pitch as egl::Attrib,
+        ];
+
+        if self.modifiers && layer.modifier != DRM_FORMAT_MOD_INVALID {
+            attributes.extend([
+                DMA_BUF_PLANE0_MODIFIER_LO_EXT,
+                (layer.modifier & 0xffff_ffff) as egl::Attrib,
+                DMA_BUF_PLANE0_MODIFIER_HI_EXT,
+                (layer.modifier >> 32) as egl::Attrib,
+            ]);
+        } else if layer.modifier != DRM_FORMAT_MOD_INVALID
+            && layer.modifier != DRM_FORMAT_MOD_LINEAR
+        {
+            // Tiled buffers can't be described without the modifiers extension
+            return Err(egl::Error::BadMatch);
+        }
+        attributes.push(egl::ATTRIB_NONE);
+
+        let image = unsafe {
+            self.egl_state.egl.create_image(
+                self.egl_state.egl_display,
+                Context::from_ptr(egl::NO_CONTEXT),
+                LINUX_DMA_BUF_EXT,
+                ClientBuffer::from_ptr(null_mut()),
+                &attributes,
+            )?
+        };
+
+        unsafe {
+            gl::BindTexture(gl::TEXTURE_2D, texture);
+            (self.image_target_texture)(gl::TEXTURE_2D, image.as_ptr());
+        }
+
+        Ok(DmaBufImage {
+            egl_state: self.egl_state.clone(),
+            image,
+        })
+    }
+}
+
+impl Drop for DmaBufImage {
+    fn drop(&mut self) {
+        let _ = self
+            .egl_state
+            .egl
+            .destroy_image(self.egl_state.egl_display, self.image);
+    }
+}
+
+fn has_gl_extension(name: &str) -> bool {
+    unsafe {
+        let mut count = 0;
+        gl::GetIntegerv(gl::NUM_EXTENSIONS, &mut count);
+
+        (0..count as GLuint).any(|index| {
+            let extension = gl::GetStringi(gl::EXTENSIONS, index);
+            !extension.is_null()
+                && CStr::from_ptr(extension as *const _).to_bytes() == name.as_bytes()
+        })
+    }
+}
@@ -0,0 +1,45 @@
+use std::ops::Deref;
+use std::sync::{Arc, Mutex, Weak};
+
+/// Recycles the plane buffers of decoded frames, which keep the same size for a whole video
+#[derive(Clone, Default)]
+pub struct FramePool {
+    buffers: Arc<Mutex<Vec<Vec<u8>>>>,
+}
+
+/// Buffer going back to its pool when dropped
+pub struct PooledBuffer {
+    data: Vec<u8>,
+    pool: Weak<Mutex<Vec<Vec<u8>>>>,
+}
+
+impl FramePool {
+    /// Copies `data` into a recycled buffer, allocating one when none is free
+    pub fn copy(&self, data: &[u8]) -> PooledBuffer {
+        let mut buffer = self.buffers.lock().unwrap().pop().unwrap_or_default();
+        buffer.clear();
+        buffer.extend_from_slice(data);
+
+        PooledBuffer {
+            data: buffer,
+            pool: Arc::downgrade(&self.buffers),
+        }
+    }
+}
+
+impl Deref for PooledBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        // Buffers are freed along with the decoder
+        if let Some(pool) = self.pool.upgrade() {
+            pool.lock().unwrap().push(std::mem::take(&mut self.data));
+        }
+    }
+}
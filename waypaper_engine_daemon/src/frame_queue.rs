@@ -0,0 +1,121 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex};
+
+/// Frames decoded ahead of their presentation. The decoder blocks while the queue is full, until
+/// frames are taken out or the queue is closed.
+pub struct FrameQueue<T> {
+    state: Mutex<QueueState<T>>,
+    space_available: Condvar,
+    capacity: usize,
+    dropped: AtomicU64,
+}
+
+struct QueueState<T> {
+    frames: VecDeque<T>,
+    closed: bool,
+}
+
+impl<T> FrameQueue<T> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            state: Mutex::new(QueueState {
+                frames: VecDeque::with_capacity(capacity),
+                closed: false,
+            }),
+            space_available: Condvar::new(),
+            capacity: capacity.max(1),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Waits for a free slot, returns false once the queue is closed
+    pub fn push(&self, frame: T) -> bool {
+        let mut state = self.state.lock().unwrap();
+
+        while state.frames.len() >= self.capacity && !state.closed {
+            state = self.space_available.wait(state).unwrap();
+        }
+
+        if state.closed {
+            return false;
+        }
+
+        state.frames.push_back(frame);
+        true
+    }
+
+    /// Takes the frames at the front as long as `is_due` accepts them and returns the last one,
+    /// the others are counted as dropped. The flag is false when the queue ran out before
+    /// reaching a frame that isn't due yet.
+    pub fn pop_due(&self, is_due: impl Fn(&T) -> bool) -> (Option<T>, bool) {
+        let mut state = self.state.lock().unwrap();
+        let mut last = None;
+        let mut popped = 0;
+
+        let caught_up = loop {
+            match state.frames.front() {
+                Some(frame) if is_due(frame) => {
+                    last = state.frames.pop_front();
+                    popped += 1;
+                }
+                Some(_) => break true,
+                None => break false,
+            }
+        };
+
+        if popped > 1 {
+            tracing::debug!("Dropped {} late video frames", popped - 1);
+            self.dropped.fetch_add(popped - 1, Ordering::Relaxed);
+        }
+        if popped > 0 {
+            self.space_available.notify_one();
+        }
+
+        (last, caught_up)
+    }
+
+    /// Takes the first frame `keep` accepts, discarding the ones before it
+    pub fn pop_first(&self, keep: impl Fn(&T) -> bool) -> Option<T> {
+        let mut state = self.state.lock().unwrap();
+
+        while let Some(frame) = state.frames.pop_front() {
+            self.space_available.notify_one();
+            if keep(&frame) {
+                return Some(frame);
+            }
+        }
+
+        None
+    }
+
+    pub fn clear(&self) {
+        self.state.lock().unwrap().frames.clear();
+        self.space_available.notify_one();
+    }
+
+    /// Wakes the decoder up for good, frames pushed from now on are refused
+    pub fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        state.frames.clear();
+        self.space_available.notify_all();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.state.lock().unwrap().closed
+    }
+
+    pub fn depth(&self) -> usize {
+        self.state.lock().unwrap().frames.len()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Frames skipped because they were late, since the queue was created
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
@@ -59,3 +59,74 @@ pub(crate) fn link_program(vs: GLuint, fs: GLuint) -> GLuint {
         program
     }
 }
+
+// Fullscreen triangle generated from the vertex id, no vertex buffer needed
+pub(crate) const FULLSCREEN_VERTEX_SHADER_SRC: &str = r#"
+    #version 330 core
+
+    out vec2 uv;
+
+    void main()
+    {
+        vec2 position = vec2((gl_VertexID << 1) & 2, gl_VertexID & 2);
+        uv = position;
+        gl_Position = vec4(position * 2.0 - 1.0, 0.0, 1.0);
+    }
+"#;
+
+/// Compiles and links a program writing to `out_color`
+pub(crate) fn create_program(vertex_src: &str, fragment_src: &str) -> GLuint {
+    unsafe {
+        let vertex_shader = compile_shader(vertex_src, gl::VERTEX_SHADER);
+        let fragment_shader = compile_shader(fragment_src, gl::FRAGMENT_SHADER);
+        let program = link_program(vertex_shader, fragment_shader);
+
+        let pointer = CString::new("out_color").unwrap();
+        gl::BindFragDataLocation(program, 0, pointer.as_ptr());
+
+        gl::DeleteShader(vertex_shader);
+        gl::DeleteShader(fragment_shader);
+
+        program
+    }
+}
+
+pub(crate) unsafe fn uniform_location(program: GLuint, name: &str) -> GLint {
+    let name = CString::new(name).unwrap();
+    gl::GetUniformLocation(program, name.as_ptr())
+}
+
+pub(crate) unsafe fn set_int(program: GLuint, name: &str, value: GLint) {
+    gl::Uniform1i(uniform_location(program, name), value);
+}
+
+pub(crate) unsafe fn set_float(program: GLuint, name: &str, value: f32) {
+    gl::Uniform1f(uniform_location(program, name), value);
+}
+
+pub(crate) unsafe fn set_vec2(program: GLuint, name: &str, x: f32, y: f32) {
+    gl::Uniform2f(uniform_location(program, name), x, y);
+}
+
+pub(crate) unsafe fn set_vec3(program: GLuint, name: &str, value: [f32; 3]) {
+    gl::Uniform3f(
+        uniform_location(program, name),
+        value[0],
+        value[1],
+        value[2],
+    );
+}
+
+pub(crate) unsafe fn set_vec4(program: GLuint, name: &str, xy: (f32, f32), zw: (f32, f32)) {
+    gl::Uniform4f(uniform_location(program, name), xy.0, xy.1, zw.0, zw.1);
+}
+
+/// `value` is in column major order
+pub(crate) unsafe fn set_mat3(program: GLuint, name: &str, value: &[f32; 9]) {
+    gl::UniformMatrix3fv(
+        uniform_location(program, name),
+        1,
+        gl::FALSE,
+        value.as_ptr(),
+    );
+}
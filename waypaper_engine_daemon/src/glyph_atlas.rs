@@ -0,0 +1,212 @@
+use std::collections::HashMap;
+use std::ffi::c_void;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use ab_glyph::{point, Font, FontVec, GlyphId, PxScale};
+use gl::types::{GLint, GLsizei, GLuint};
+
+use crate::scene_package::ScenePackage;
+
+const ATLAS_SIZE: u32 = 1024;
+const GLYPH_PADDING: u32 = 1;
+const DEFAULT_FONT_FAMILY: &str = "sans-serif";
+
+/// Loads a font from the scene package, or asks fontconfig for the closest system font
+pub fn load_font(name: &str, package: &ScenePackage) -> Option<FontVec> {
+    if let Some(file) = package.contents.get(name) {
+        match FontVec::try_from_vec(file.bytes().to_vec()) {
+            Ok(font) => return Some(font),
+            Err(err) => tracing::warn!("Invalid font {name} in scene package: {err}"),
+        }
+    }
+
+    // Wallpaper Engine refers to Windows fonts as `systemfont_<family>`
+    let family = name
+        .strip_prefix("systemfont_")
+        .map(str::to_string)
+        .or_else(|| {
+            Path::new(name)
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().to_string())
+        })
+        .filter(|family| !family.is_empty())
+        .unwrap_or_else(|| DEFAULT_FONT_FAMILY.to_string());
+
+    load_system_font(&family).or_else(|| load_system_font(DEFAULT_FONT_FAMILY))
+}
+
+fn load_system_font(family: &str) -> Option<FontVec> {
+    let output = Command::new("fc-match")
+        .args(["--format", "%{file}", family])
+        .output()
+        .inspect_err(|err| tracing::warn!("Failed to run fc-match: {err}"))
+        .ok()?;
+
+    let path = String::from_utf8(output.stdout).ok()?;
+    tracing::debug!("fontconfig matched {family} to {path}");
+
+    let bytes = fs::read(&path)
+        .inspect_err(|err| tracing::warn!("Failed to read font {path}: {err}"))
+        .ok()?;
+
+    FontVec::try_from_vec(bytes).ok()
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct AtlasGlyph {
+    /// Offset of the bitmap top-left corner from the pen position, y pointing down
+    pub offset: (f32, f32),
+    pub size: (f32, f32),
+    /// Texture coordinates as (u_min, v_min, u_max, v_max)
+    pub uv: (f32, f32, f32, f32),
+}
+
+/// Single channel texture caching rasterized glyphs of a font at a given size
+///
+/// Glyphs are packed in rows and only uploaded the first time they are used, so text that changes
+/// often (like a clock) only touches the atlas for characters it hasn't displayed yet.
+pub struct GlyphAtlas {
+    pub font: FontVec,
+    pub scale: PxScale,
+    texture: GLuint,
+    glyphs: HashMap<GlyphId, Option<AtlasGlyph>>,
+    cursor: (u32, u32),
+    row_height: u32,
+}
+
+impl GlyphAtlas {
+    /// Creates the atlas texture, a GL context must be current
+    pub fn new(font: FontVec, scale: PxScale) -> Self {
+        let mut texture: GLuint = 0;
+        let empty = vec![0u8; (ATLAS_SIZE * ATLAS_SIZE) as usize];
+
+        unsafe {
+            gl::GenTextures(1, &mut texture);
+            gl::BindTexture(gl::TEXTURE_2D, texture);
+            gl::PixelStorei(gl::UNPACK_ALIGNMENT, 1);
+            gl::TexImage2D(
+                gl::TEXTURE_2D,
+                0,
+                gl::R8 as GLint,
+                ATLAS_SIZE as GLsizei,
+                ATLAS_SIZE as GLsizei,
+                0,
+                gl::RED,
+                gl::UNSIGNED_BYTE,
+                empty.as_ptr() as *const c_void,
+            );
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::LINEAR as GLint);
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::LINEAR as GLint);
+            gl::TexParameteri(
+                gl::TEXTURE_2D,
+                gl::TEXTURE_WRAP_S,
+                gl::CLAMP_TO_EDGE as GLint,
+            );
+            gl::TexParameteri(
+                gl::TEXTURE_2D,
+                gl::TEXTURE_WRAP_T,
+                gl::CLAMP_TO_EDGE as GLint,
+            );
+            gl::BindTexture(gl::TEXTURE_2D, 0);
+        }
+
+        Self {
+            font,
+            scale,
+            texture,
+            glyphs: HashMap::new(),
+            cursor: (GLYPH_PADDING, GLYPH_PADDING),
+            row_height: 0,
+        }
+    }
+
+    pub fn texture(&self) -> GLuint {
+        self.texture
+    }
+
+    /// Returns the atlas entry of a glyph, rasterizing it if needed. Whitespace has no entry.
+    pub fn glyph(&mut self, id: GlyphId) -> Option<AtlasGlyph> {
+        if let Some(entry) = self.glyphs.get(&id) {
+            return *entry;
+        }
+
+        let entry = self.rasterize(id);
+        self.glyphs.insert(id, entry);
+        entry
+    }
+
+    fn rasterize(&mut self, id: GlyphId) -> Option<AtlasGlyph> {
+        let outlined = self
+            .font
+            .outline_glyph(id.with_scale_and_position(self.scale, point(0.0, 0.0)))?;
+        let bounds = outlined.px_bounds();
+        let (width, height) = (bounds.width() as u32, bounds.height() as u32);
+
+        if width == 0 || height == 0 {
+            return None;
+        }
+
+        if self.cursor.0 + width + GLYPH_PADDING > ATLAS_SIZE {
+            self.cursor = (
+                GLYPH_PADDING,
+                self.cursor.1 + self.row_height + GLYPH_PADDING,
+            );
+            self.row_height = 0;
+        }
+
+        if self.cursor.1 + height + GLYPH_PADDING > ATLAS_SIZE {
+            tracing::warn!("Glyph atlas is full, glyph {id:?} won't be displayed");
+            return None;
+        }
+
+        let mut pixels = vec![0u8; (width * height) as usize];
+        outlined.draw(|x, y, coverage| {
+            if x < width && y < height {
+                pixels[(y * width + x) as usize] = (coverage.clamp(0.0, 1.0) * 255.0) as u8;
+            }
+        });
+
+        let (x, y) = self.cursor;
+        unsafe {
+            gl::BindTexture(gl::TEXTURE_2D, self.texture);
+            gl::PixelStorei(gl::UNPACK_ALIGNMENT, 1);
+            gl::TexSubImage2D(
+                gl::TEXTURE_2D,
+                0,
+                x as GLint,
+                y as GLint,
+                width as GLsizei,
+                height as GLsizei,
+                gl::RED,
+                gl::UNSIGNED_BYTE,
+                pixels.as_ptr() as *const c_void,
+            );
+            gl::BindTexture(gl::TEXTURE_2D, 0);
+        }
+
+        self.cursor.0 += width + GLYPH_PADDING;
+        self.row_height = self.row_height.max(height);
+
+        let size = ATLAS_SIZE as f32;
+        Some(AtlasGlyph {
+            offset: (bounds.min.x, bounds.min.y),
+            size: (width as f32, height as f32),
+            uv: (
+                x as f32 / size,
+                y as f32 / size,
+                (x + width) as f32 / size,
+                (y + height) as f32 / size,
+            ),
+        })
+    }
+}
+
+impl Drop for GlyphAtlas {
+    fn drop(&mut self) {
+        unsafe {
+            gl::DeleteTextures(1, &self.texture);
+        }
+    }
+}
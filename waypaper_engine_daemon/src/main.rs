@@ -2,23 +2,48 @@
 
 use std::error::Error;
 
+use smithay_client_toolkit::reexports::calloop::EventLoop;
 use tracing::level_filters::LevelFilter;
 use tracing_subscriber::EnvFilter;
 
 use crate::app_state::AppState;
+use crate::signals::TerminationSignals;
 
 mod app_state;
+mod audio;
+mod audio_source;
+mod bloom;
+mod camera;
+mod config;
+mod dma_buf;
 mod egl;
 mod file_reading_utils;
+mod glyph_atlas;
+mod noise;
+mod occlusion;
+mod particle_renderer;
+mod particle_system;
+mod power;
+mod scaling;
 mod scene;
 mod scene_package;
+mod scene_source;
+mod scene_wp_renderer;
+mod signals;
+mod span;
 mod tex_file;
+mod text_renderer;
+mod transition;
 mod wallpaper;
 mod wallpaper_renderer;
 mod wl_renderer;
 
+mod frame_pool;
+mod frame_queue;
 mod gl_utils;
+mod video_decoder;
 mod video_rs_wp_renderer;
+mod video_source;
 
 fn main() -> Result<(), Box<dyn Error>> {
     tracing_subscriber::fmt()
@@ -29,6 +54,10 @@ fn main() -> Result<(), Box<dyn Error>> {
         )
         .init();
 
-    let mut app = AppState::new(waypaper_engine_shared::get_wpe_dir());
-    app.run()
+    // Before any thread is spawned, so that none of them gets the signals
+    let signals = TerminationSignals::block()?;
+
+    let mut event_loop = EventLoop::try_new()?;
+    let mut app = AppState::new(waypaper_engine_shared::get_wpe_dir(), &event_loop.handle());
+    app.run(&mut event_loop, signals)
 }
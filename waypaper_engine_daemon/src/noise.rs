@@ -0,0 +1,99 @@
+use cgmath::Vector3;
+
+/// Small xorshift generator, good enough for visual randomness and cheap to seed per system
+#[derive(Debug, Clone)]
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self(seed.max(1))
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// Uniform float in [0, 1)
+    pub fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    pub fn range(&mut self, min: f32, max: f32) -> f32 {
+        min + (max - min) * self.next_f32()
+    }
+
+    pub fn range_vec3(&mut self, min: Vector3<f32>, max: Vector3<f32>) -> Vector3<f32> {
+        Vector3::new(
+            self.range(min.x, max.x),
+            self.range(min.y, max.y),
+            self.range(min.z, max.z),
+        )
+    }
+}
+
+fn hash(x: i32, y: i32, z: i32) -> f32 {
+    let mut h = (x as u32).wrapping_mul(0x8da6_b343)
+        ^ (y as u32).wrapping_mul(0xd816_3841)
+        ^ (z as u32).wrapping_mul(0xcb1a_b31f);
+    h = (h ^ (h >> 13)).wrapping_mul(0x5bd1_e995);
+    h ^= h >> 15;
+
+    (h & 0x00ff_ffff) as f32 / 0x0080_0000 as f32 - 1.0
+}
+
+fn smooth(t: f32) -> f32 {
+    t * t * (3.0 - 2.0 * t)
+}
+
+fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    a + (b - a) * t
+}
+
+/// Deterministic 3D value noise in [-1, 1]
+pub fn value_noise(x: f32, y: f32, z: f32) -> f32 {
+    let (xi, yi, zi) = (x.floor() as i32, y.floor() as i32, z.floor() as i32);
+    let (xf, yf, zf) = (
+        smooth(x - x.floor()),
+        smooth(y - y.floor()),
+        smooth(z - z.floor()),
+    );
+
+    let corner = |dx: i32, dy: i32, dz: i32| hash(xi + dx, yi + dy, zi + dz);
+
+    let x00 = lerp(corner(0, 0, 0), corner(1, 0, 0), xf);
+    let x10 = lerp(corner(0, 1, 0), corner(1, 1, 0), xf);
+    let x01 = lerp(corner(0, 0, 1), corner(1, 0, 1), xf);
+    let x11 = lerp(corner(0, 1, 1), corner(1, 1, 1), xf);
+
+    lerp(lerp(x00, x10, yf), lerp(x01, x11, yf), zf)
+}
+
+/// Noise vector field, each component sampled at a decorrelated offset
+pub fn noise_vec3(position: Vector3<f32>) -> Vector3<f32> {
+    Vector3::new(
+        value_noise(position.x, position.y, position.z),
+        value_noise(position.x + 31.4, position.y - 12.7, position.z + 7.3),
+        value_noise(position.x - 19.1, position.y + 43.9, position.z - 27.5),
+    )
+}
+
+/// Sum of `octaves` layers of value noise, each one twice the frequency of the previous one and
+/// `roughness` times its amplitude. The result is normalized back to [-1, 1].
+pub fn fractal_noise(x: f32, y: f32, z: f32, octaves: u32, roughness: f32) -> f32 {
+    let mut total = 0.0;
+    let mut amplitude = 1.0;
+    let mut frequency = 1.0;
+    let mut max = 0.0;
+
+    for _ in 0..octaves.max(1) {
+        total += value_noise(x * frequency, y * frequency, z * frequency) * amplitude;
+        max += amplitude;
+        amplitude *= roughness;
+        frequency *= 2.0;
+    }
+
+    total / max
+}
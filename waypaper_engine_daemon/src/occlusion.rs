@@ -0,0 +1,76 @@
+use std::collections::HashMap;
+
+use smithay_client_toolkit::reexports::client::backend::ObjectId;
+use smithay_client_toolkit::reexports::client::protocol::wl_output::WlOutput;
+
+/// Reasons for an output not to be visible, it is hidden as long as one of them holds
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Visibility {
+    /// Turned off by power management
+    pub powered_off: bool,
+    /// Covered by a fullscreen window
+    pub fullscreen: bool,
+    /// The compositor stopped answering frame callbacks, which it does for covered surfaces
+    pub no_frame_callbacks: bool,
+}
+
+impl Visibility {
+    pub fn is_hidden(&self) -> bool {
+        self.powered_off || self.fullscreen || self.no_frame_callbacks
+    }
+}
+
+/// Windows announced by the foreign toplevel protocol, to find the outputs covered by fullscreen
+/// ones
+#[derive(Default)]
+pub struct Toplevels {
+    toplevels: HashMap<ObjectId, Toplevel>,
+}
+
+#[derive(Default)]
+struct Toplevel {
+    fullscreen: bool,
+    pending_fullscreen: bool,
+    outputs: Vec<WlOutput>,
+}
+
+impl Toplevels {
+    /// Applied on the next done event
+    pub fn set_fullscreen(&mut self, toplevel: ObjectId, fullscreen: bool) {
+        self.toplevels
+            .entry(toplevel)
+            .or_default()
+            .pending_fullscreen = fullscreen;
+    }
+
+    pub fn output_enter(&mut self, toplevel: ObjectId, output: WlOutput) {
+        self.toplevels
+            .entry(toplevel)
+            .or_default()
+            .outputs
+            .push(output);
+    }
+
+    pub fn output_leave(&mut self, toplevel: ObjectId, output: &WlOutput) {
+        if let Some(toplevel) = self.toplevels.get_mut(&toplevel) {
+            toplevel.outputs.retain(|entered| entered != output);
+        }
+    }
+
+    pub fn done(&mut self, toplevel: ObjectId) {
+        if let Some(toplevel) = self.toplevels.get_mut(&toplevel) {
+            toplevel.fullscreen = toplevel.pending_fullscreen;
+        }
+    }
+
+    pub fn closed(&mut self, toplevel: &ObjectId) {
+        self.toplevels.remove(toplevel);
+    }
+
+    /// Whether a fullscreen window is shown on `output`
+    pub fn covers(&self, output: &WlOutput) -> bool {
+        self.toplevels
+            .values()
+            .any(|toplevel| toplevel.fullscreen && toplevel.outputs.contains(output))
+    }
+}
@@ -0,0 +1,349 @@
+use std::ffi::{c_void, CString};
+use std::ptr::null;
+
+use cgmath::Matrix4;
+use gl::types::{GLfloat, GLint, GLsizei, GLsizeiptr, GLuint};
+use serde::Deserialize;
+
+use crate::gl_utils::{compile_shader, link_program, set_vec2};
+use crate::particle_system::{ParticleInstance, ParticleSystem};
+use crate::scene_package::ScenePackage;
+use crate::tex_file::TexFile;
+
+#[rustfmt::skip]
+static QUAD_CORNERS: [GLfloat; 8] = [
+    -0.5, -0.5,
+     0.5, -0.5,
+    -0.5,  0.5,
+     0.5,  0.5,
+];
+
+const VERTEX_SHADER_SRC: &str = r#"
+    #version 330 core
+
+    layout (location = 0) in vec2 aCorner;
+    layout (location = 1) in vec3 iPosition;
+    layout (location = 2) in vec3 iAxis;
+    layout (location = 3) in vec2 iSizeRotation;
+    layout (location = 4) in vec4 iColor;
+
+    uniform mat4 mvp;
+
+    out vec2 tex_coord;
+    out vec4 color;
+
+    void main()
+    {
+        vec2 offset;
+        float axis_length = length(iAxis.xy);
+
+        if (axis_length > 0.0001) {
+            // Trails and rope segments start at the particle and stretch along the axis
+            vec2 direction = iAxis.xy / axis_length;
+            vec2 normal = vec2(-direction.y, direction.x);
+            offset = direction * (aCorner.x + 0.5) * axis_length + normal * aCorner.y * iSizeRotation.x;
+        } else {
+            float c = cos(iSizeRotation.y);
+            float s = sin(iSizeRotation.y);
+            offset = mat2(c, s, -s, c) * aCorner * iSizeRotation.x;
+        }
+
+        gl_Position = mvp * vec4(iPosition + vec3(offset, 0.0), 1.0);
+        tex_coord = vec2(aCorner.x + 0.5, 0.5 - aCorner.y);
+        color = iColor;
+    }
+"#;
+
+const FRAGMENT_SHADER_SRC: &str = r#"
+    #version 330 core
+
+    out vec4 out_color;
+
+    in vec2 tex_coord;
+    in vec4 color;
+
+    uniform sampler2D tex;
+    // Normalized position of the pointer on the output, negative while it's away
+    uniform vec2 pointer;
+
+    void main()
+    {
+        out_color = texture(tex, tex_coord) * color;
+    }
+"#;
+
+const FALLBACK_TEXTURE_SIZE: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Blending {
+    Translucent,
+    Additive,
+}
+
+#[derive(Debug, Deserialize)]
+struct Material {
+    passes: Vec<MaterialPass>,
+}
+
+#[derive(Debug, Deserialize)]
+struct MaterialPass {
+    #[serde(default)]
+    blending: Option<String>,
+    #[serde(default)]
+    textures: Vec<Option<String>>,
+}
+
+/// GL resources shared by every particle system of a scene
+pub struct ParticleRenderer {
+    program: GLuint,
+    vao: GLuint,
+    quad_vbo: GLuint,
+    instance_vbo: GLuint,
+    instance_capacity: usize,
+    fallback_texture: GLuint,
+    instances: Vec<ParticleInstance>,
+}
+
+/// Per system GL state
+pub struct ParticleDrawable {
+    pub system: ParticleSystem,
+    texture: Option<GLuint>,
+    blending: Blending,
+}
+
+impl ParticleDrawable {
+    /// Resolves the material of the system in the scene package and uploads its texture, a GL
+    /// context must be current
+    pub fn new(system: ParticleSystem, package: &ScenePackage) -> Self {
+        let material = system
+            .material()
+            .and_then(|path| package.contents.get(path))
+            .and_then(|file| serde_json::from_slice::<Material>(file.bytes()).ok());
+
+        let pass = material.as_ref().and_then(|m| m.passes.first());
+
+        let blending = match pass.and_then(|p| p.blending.as_deref()) {
+            Some("additive") => Blending::Additive,
+            _ => Blending::Translucent,
+        };
+
+        let texture = pass
+            .and_then(|p| p.textures.first().cloned().flatten())
+            .and_then(|name| {
+                let path = format!("materials/{name}.tex");
+                let file = package.contents.get(&path);
+                if file.is_none() {
+                    tracing::debug!("Particle texture {path} not found in scene package");
+                }
+                file
+            })
+            .and_then(|file| match TexFile::from_bytes(file.bytes().to_vec()) {
+                Ok(tex) => tex.upload().ok(),
+                Err(err) => {
+                    tracing::warn!("Failed to read particle texture {}: {err}", file.name);
+                    None
+                }
+            });
+
+        Self {
+            system,
+            texture,
+            blending,
+        }
+    }
+}
+
+impl Drop for ParticleDrawable {
+    fn drop(&mut self) {
+        if let Some(texture) = self.texture {
+            unsafe {
+                gl::DeleteTextures(1, &texture);
+            }
+        }
+    }
+}
+
+impl ParticleRenderer {
+    pub fn new() -> Self {
+        unsafe {
+            let vertex_shader = compile_shader(VERTEX_SHADER_SRC, gl::VERTEX_SHADER);
+            let fragment_shader = compile_shader(FRAGMENT_SHADER_SRC, gl::FRAGMENT_SHADER);
+            let program = link_program(vertex_shader, fragment_shader);
+
+            let pointer = CString::new("out_color").unwrap();
+            gl::BindFragDataLocation(program, 0, pointer.as_ptr());
+
+            gl::DeleteShader(vertex_shader);
+            gl::DeleteShader(fragment_shader);
+
+            let mut vao: GLuint = 0;
+            gl::GenVertexArrays(1, &mut vao);
+            gl::BindVertexArray(vao);
+
+            let mut quad_vbo: GLuint = 0;
+            gl::GenBuffers(1, &mut quad_vbo);
+            gl::BindBuffer(gl::ARRAY_BUFFER, quad_vbo);
+            gl::BufferData(
+                gl::ARRAY_BUFFER,
+                std::mem::size_of_val(&QUAD_CORNERS) as GLsizeiptr,
+                QUAD_CORNERS.as_ptr() as *const c_void,
+                gl::STATIC_DRAW,
+            );
+            gl::VertexAttribPointer(0, 2, gl::FLOAT, gl::FALSE, 0, null());
+            gl::EnableVertexAttribArray(0);
+
+            let mut instance_vbo: GLuint = 0;
+            gl::GenBuffers(1, &mut instance_vbo);
+            gl::BindBuffer(gl::ARRAY_BUFFER, instance_vbo);
+
+            let stride = std::mem::size_of::<ParticleInstance>() as GLsizei;
+            let float_size = std::mem::size_of::<GLfloat>();
+
+            // location, component count, offset in floats
+            for (location, size, offset) in [(1, 3, 0), (2, 3, 3), (3, 2, 6), (4, 4, 8)] {
+                gl::VertexAttribPointer(
+                    location,
+                    size,
+                    gl::FLOAT,
+                    gl::FALSE,
+                    stride,
+                    (offset * float_size) as *const c_void,
+                );
+                gl::EnableVertexAttribArray(location);
+                gl::VertexAttribDivisor(location, 1);
+            }
+
+            gl::BindBuffer(gl::ARRAY_BUFFER, 0);
+            gl::BindVertexArray(0);
+
+            Self {
+                program,
+                vao,
+                quad_vbo,
+                instance_vbo,
+                instance_capacity: 0,
+                fallback_texture: create_fallback_texture(),
+                instances: vec![],
+            }
+        }
+    }
+
+    pub fn draw(&mut self, drawable: &ParticleDrawable, mvp: &Matrix4<f32>, pointer: (f32, f32)) {
+        drawable.system.instances(&mut self.instances);
+
+        if self.instances.is_empty() {
+            return;
+        }
+
+        unsafe {
+            gl::BindVertexArray(self.vao);
+            gl::UseProgram(self.program);
+
+            let mvp_name = CString::new("mvp").unwrap();
+            let mvp_location = gl::GetUniformLocation(self.program, mvp_name.as_ptr());
+            let mvp: &[f32; 16] = mvp.as_ref();
+            gl::UniformMatrix4fv(mvp_location, 1, gl::FALSE, mvp.as_ptr());
+            set_vec2(self.program, "pointer", pointer.0, pointer.1);
+
+            gl::BindBuffer(gl::ARRAY_BUFFER, self.instance_vbo);
+            let size =
+                (self.instances.len() * std::mem::size_of::<ParticleInstance>()) as GLsizeiptr;
+            if self.instances.len() > self.instance_capacity {
+                gl::BufferData(
+                    gl::ARRAY_BUFFER,
+                    size,
+                    self.instances.as_ptr() as *const c_void,
+                    gl::STREAM_DRAW,
+                );
+                self.instance_capacity = self.instances.len();
+            } else {
+                gl::BufferSubData(
+                    gl::ARRAY_BUFFER,
+                    0,
+                    size,
+                    self.instances.as_ptr() as *const c_void,
+                );
+            }
+
+            gl::ActiveTexture(gl::TEXTURE0);
+            gl::BindTexture(
+                gl::TEXTURE_2D,
+                drawable.texture.unwrap_or(self.fallback_texture),
+            );
+
+            gl::Enable(gl::BLEND);
+            match drawable.blending {
+                Blending::Translucent => gl::BlendFunc(gl::SRC_ALPHA, gl::ONE_MINUS_SRC_ALPHA),
+                Blending::Additive => gl::BlendFunc(gl::SRC_ALPHA, gl::ONE),
+            }
+
+            gl::DrawArraysInstanced(gl::TRIANGLE_STRIP, 0, 4, self.instances.len() as GLsizei);
+
+            gl::Disable(gl::BLEND);
+            gl::BindTexture(gl::TEXTURE_2D, 0);
+            gl::BindBuffer(gl::ARRAY_BUFFER, 0);
+            gl::UseProgram(0);
+            gl::BindVertexArray(0);
+        }
+    }
+}
+
+/// Soft white disc used when a particle material can't be resolved
+fn create_fallback_texture() -> GLuint {
+    let mut pixels = Vec::with_capacity(FALLBACK_TEXTURE_SIZE * FALLBACK_TEXTURE_SIZE * 4);
+    let half = FALLBACK_TEXTURE_SIZE as f32 / 2.0;
+
+    for y in 0..FALLBACK_TEXTURE_SIZE {
+        for x in 0..FALLBACK_TEXTURE_SIZE {
+            let dx = (x as f32 + 0.5 - half) / half;
+            let dy = (y as f32 + 0.5 - half) / half;
+            let alpha = (1.0 - (dx * dx + dy * dy).sqrt()).clamp(0.0, 1.0);
+            pixels.extend_from_slice(&[255, 255, 255, (alpha * alpha * 255.0) as u8]);
+        }
+    }
+
+    let mut texture: GLuint = 0;
+    unsafe {
+        gl::GenTextures(1, &mut texture);
+        gl::BindTexture(gl::TEXTURE_2D, texture);
+        gl::TexImage2D(
+            gl::TEXTURE_2D,
+            0,
+            gl::RGBA8 as GLint,
+            FALLBACK_TEXTURE_SIZE as GLsizei,
+            FALLBACK_TEXTURE_SIZE as GLsizei,
+            0,
+            gl::RGBA,
+            gl::UNSIGNED_BYTE,
+            pixels.as_ptr() as *const c_void,
+        );
+        gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::LINEAR as GLint);
+        gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::LINEAR as GLint);
+        gl::TexParameteri(
+            gl::TEXTURE_2D,
+            gl::TEXTURE_WRAP_S,
+            gl::CLAMP_TO_EDGE as GLint,
+        );
+        gl::TexParameteri(
+            gl::TEXTURE_2D,
+            gl::TEXTURE_WRAP_T,
+            gl::CLAMP_TO_EDGE as GLint,
+        );
+        gl::BindTexture(gl::TEXTURE_2D, 0);
+    }
+
+    texture
+}
+
+impl Drop for ParticleRenderer {
+    fn drop(&mut self) {
+        unsafe {
+            tracing::debug!("Destroying particle renderer");
+            gl::DeleteTextures(1, &self.fallback_texture);
+            gl::DeleteBuffers(1, &self.instance_vbo);
+            gl::DeleteBuffers(1, &self.quad_vbo);
+            gl::DeleteVertexArrays(1, &self.vao);
+            gl::DeleteProgram(self.program);
+        }
+    }
+}
@@ -0,0 +1,902 @@
+use std::collections::HashMap;
+
+use cgmath::{InnerSpace, Vector3, Vector4, Zero};
+use serde::Deserialize;
+use serde_json::Value;
+
+use waypaper_engine_shared::serde_utils::{as_f32_lenient, as_vec3f32_lenient};
+
+use crate::noise::{noise_vec3, Rng};
+
+// Particle definitions are stored in the scene package as `particles/*.json`
+
+/// Control point ids go from 0 to 7 in Wallpaper Engine
+const MAX_CONTROL_POINTS: usize = 8;
+/// Step used to simulate the system ahead by its start time
+const PREWARM_STEP: f32 = 1.0 / 30.0;
+// Bounds the work done when loading a system with an absurd start time
+const MAX_PREWARM_TIME: f32 = 60.0;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ParticleDefinition {
+    #[serde(default, rename = "emitter")]
+    pub emitters: Vec<Emitter>,
+    #[serde(default, rename = "initializer")]
+    pub initializers: Vec<Initializer>,
+    #[serde(default, rename = "operator")]
+    pub operators: Vec<Operator>,
+    #[serde(default, rename = "renderer")]
+    pub renderers: Vec<RendererDefinition>,
+    #[serde(default, rename = "controlpoint")]
+    pub control_points: Vec<ControlPoint>,
+
+    pub material: Option<String>,
+    #[serde(default = "default_max_count", rename = "maxcount")]
+    pub max_count: u32,
+    /// Seconds the system is simulated ahead when it starts, so it doesn't begin empty
+    #[serde(default, rename = "starttime", deserialize_with = "as_f32_lenient")]
+    pub start_time: f32,
+}
+
+fn default_max_count() -> u32 {
+    100
+}
+
+fn zero_vec3() -> Vector3<f32> {
+    Vector3::zero()
+}
+
+fn one_vec3() -> Vector3<f32> {
+    Vector3::new(1.0, 1.0, 1.0)
+}
+
+fn white() -> Vector3<f32> {
+    Vector3::new(255.0, 255.0, 255.0)
+}
+
+fn one() -> f32 {
+    1.0
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "name", rename_all = "lowercase")]
+pub enum Emitter {
+    BoxRandom {
+        #[serde(flatten)]
+        common: EmitterCommon,
+        #[serde(
+            default = "zero_vec3",
+            rename = "distancemin",
+            deserialize_with = "as_vec3f32_lenient"
+        )]
+        distance_min: Vector3<f32>,
+        #[serde(
+            default = "zero_vec3",
+            rename = "distancemax",
+            deserialize_with = "as_vec3f32_lenient"
+        )]
+        distance_max: Vector3<f32>,
+    },
+    SphereRandom {
+        #[serde(flatten)]
+        common: EmitterCommon,
+        #[serde(default, rename = "distancemin", deserialize_with = "as_f32_lenient")]
+        distance_min: f32,
+        #[serde(default, rename = "distancemax", deserialize_with = "as_f32_lenient")]
+        distance_max: f32,
+        /// Restricts emission to one side of an axis when non-zero
+        #[serde(default = "zero_vec3", deserialize_with = "as_vec3f32_lenient")]
+        sign: Vector3<f32>,
+    },
+    #[serde(other)]
+    Unsupported,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct EmitterCommon {
+    #[serde(default = "zero_vec3", deserialize_with = "as_vec3f32_lenient")]
+    pub origin: Vector3<f32>,
+    #[serde(default = "one_vec3", deserialize_with = "as_vec3f32_lenient")]
+    pub directions: Vector3<f32>,
+    /// Particles emitted per second
+    #[serde(default, deserialize_with = "as_f32_lenient")]
+    pub rate: f32,
+    /// Amount of particles emitted at once when the system starts
+    #[serde(default, deserialize_with = "as_f32_lenient")]
+    pub instantaneous: f32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "name", rename_all = "lowercase")]
+pub enum Initializer {
+    SizeRandom {
+        #[serde(default = "one", deserialize_with = "as_f32_lenient")]
+        min: f32,
+        #[serde(default = "one", deserialize_with = "as_f32_lenient")]
+        max: f32,
+    },
+    ColorRandom {
+        #[serde(default = "white", deserialize_with = "as_vec3f32_lenient")]
+        min: Vector3<f32>,
+        #[serde(default = "white", deserialize_with = "as_vec3f32_lenient")]
+        max: Vector3<f32>,
+    },
+    AlphaRandom {
+        #[serde(default = "one", deserialize_with = "as_f32_lenient")]
+        min: f32,
+        #[serde(default = "one", deserialize_with = "as_f32_lenient")]
+        max: f32,
+    },
+    LifetimeRandom {
+        #[serde(default = "one", deserialize_with = "as_f32_lenient")]
+        min: f32,
+        #[serde(default = "one", deserialize_with = "as_f32_lenient")]
+        max: f32,
+    },
+    VelocityRandom {
+        #[serde(default = "zero_vec3", deserialize_with = "as_vec3f32_lenient")]
+        min: Vector3<f32>,
+        #[serde(default = "zero_vec3", deserialize_with = "as_vec3f32_lenient")]
+        max: Vector3<f32>,
+    },
+    RotationRandom {
+        #[serde(default = "zero_vec3", deserialize_with = "as_vec3f32_lenient")]
+        min: Vector3<f32>,
+        #[serde(default = "zero_vec3", deserialize_with = "as_vec3f32_lenient")]
+        max: Vector3<f32>,
+    },
+    AngularVelocityRandom {
+        #[serde(default = "zero_vec3", deserialize_with = "as_vec3f32_lenient")]
+        min: Vector3<f32>,
+        #[serde(default = "zero_vec3", deserialize_with = "as_vec3f32_lenient")]
+        max: Vector3<f32>,
+    },
+    #[serde(other)]
+    Unsupported,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "name", rename_all = "lowercase")]
+pub enum Operator {
+    Movement {
+        #[serde(default, deserialize_with = "as_f32_lenient")]
+        drag: f32,
+        #[serde(default = "zero_vec3", deserialize_with = "as_vec3f32_lenient")]
+        gravity: Vector3<f32>,
+    },
+    AngularMovement {
+        #[serde(default, deserialize_with = "as_f32_lenient")]
+        drag: f32,
+        #[serde(default = "zero_vec3", deserialize_with = "as_vec3f32_lenient")]
+        force: Vector3<f32>,
+    },
+    AlphaFade {
+        /// Fraction of the lifetime spent fading in
+        #[serde(default, rename = "fadeintime", deserialize_with = "as_f32_lenient")]
+        fade_in_time: f32,
+        /// Fraction of the lifetime after which the particle starts fading out
+        #[serde(
+            default = "one",
+            rename = "fadeouttime",
+            deserialize_with = "as_f32_lenient"
+        )]
+        fade_out_time: f32,
+    },
+    SizeChange {
+        #[serde(flatten)]
+        change: ValueChange,
+    },
+    AlphaChange {
+        #[serde(flatten)]
+        change: ValueChange,
+    },
+    Turbulence {
+        #[serde(default = "one", deserialize_with = "as_f32_lenient")]
+        scale: f32,
+        #[serde(default, rename = "speedmin", deserialize_with = "as_f32_lenient")]
+        speed_min: f32,
+        #[serde(
+            default = "one",
+            rename = "speedmax",
+            deserialize_with = "as_f32_lenient"
+        )]
+        speed_max: f32,
+        #[serde(
+            default = "one",
+            rename = "timescale",
+            deserialize_with = "as_f32_lenient"
+        )]
+        time_scale: f32,
+    },
+    ControlPointAttract {
+        #[serde(default, rename = "controlpoint")]
+        control_point: u32,
+        #[serde(default = "zero_vec3", deserialize_with = "as_vec3f32_lenient")]
+        origin: Vector3<f32>,
+        #[serde(default = "one", deserialize_with = "as_f32_lenient")]
+        scale: f32,
+        /// Distance under which the control point has an effect
+        #[serde(default, deserialize_with = "as_f32_lenient")]
+        threshold: f32,
+    },
+    #[serde(other)]
+    Unsupported,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ValueChange {
+    #[serde(default, rename = "starttime", deserialize_with = "as_f32_lenient")]
+    pub start_time: f32,
+    #[serde(
+        default = "one",
+        rename = "endtime",
+        deserialize_with = "as_f32_lenient"
+    )]
+    pub end_time: f32,
+    #[serde(
+        default = "one",
+        rename = "startvalue",
+        deserialize_with = "as_f32_lenient"
+    )]
+    pub start_value: f32,
+    #[serde(
+        default = "one",
+        rename = "endvalue",
+        deserialize_with = "as_f32_lenient"
+    )]
+    pub end_value: f32,
+}
+
+impl ValueChange {
+    fn evaluate(&self, life: f32) -> f32 {
+        let duration = self.end_time - self.start_time;
+        let t = if duration <= 0.0 {
+            if life >= self.end_time {
+                1.0
+            } else {
+                0.0
+            }
+        } else {
+            ((life - self.start_time) / duration).clamp(0.0, 1.0)
+        };
+
+        self.start_value + (self.end_value - self.start_value) * t
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "name", rename_all = "lowercase")]
+pub enum RendererDefinition {
+    Sprite,
+    SpriteTrail {
+        /// Trail length in seconds of movement
+        #[serde(default = "one", deserialize_with = "as_f32_lenient")]
+        length: f32,
+        #[serde(default, rename = "maxlength", deserialize_with = "as_f32_lenient")]
+        max_length: f32,
+    },
+    Rope,
+    #[serde(other)]
+    Unsupported,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ControlPoint {
+    #[serde(default)]
+    pub id: u32,
+    #[serde(default = "zero_vec3", deserialize_with = "as_vec3f32_lenient")]
+    pub offset: Vector3<f32>,
+}
+
+/// Per-object tweaks applied on top of the particle definition (`instanceoverride` in scene.json)
+#[derive(Debug, Clone)]
+pub struct InstanceOverride {
+    pub count: f32,
+    pub rate: f32,
+    pub size: f32,
+    pub speed: f32,
+    pub lifetime: f32,
+    pub alpha: f32,
+    pub color: Option<Vector3<f32>>,
+}
+
+impl Default for InstanceOverride {
+    fn default() -> Self {
+        Self {
+            count: 1.0,
+            rate: 1.0,
+            size: 1.0,
+            speed: 1.0,
+            lifetime: 1.0,
+            alpha: 1.0,
+            color: None,
+        }
+    }
+}
+
+impl InstanceOverride {
+    pub fn from_map(map: &HashMap<String, Value>) -> Self {
+        let float = |key: &str| {
+            map.get(key).and_then(|value| match value {
+                Value::Number(n) => n.as_f64().map(|f| f as f32),
+                Value::String(s) => s.parse().ok(),
+                _ => None,
+            })
+        };
+
+        let color = |key: &str| {
+            map.get(key)
+                .and_then(Value::as_str)
+                .map(|s| {
+                    s.split_whitespace()
+                        .filter_map(|part| part.parse::<f32>().ok())
+                        .collect::<Vec<_>>()
+                })
+                .filter(|parts| parts.len() == 3)
+                .map(|parts| Vector3::new(parts[0], parts[1], parts[2]))
+        };
+
+        let default = Self::default();
+
+        Self {
+            count: float("count").unwrap_or(default.count),
+            rate: float("rate").unwrap_or(default.rate),
+            size: float("size").unwrap_or(default.size),
+            speed: float("speed").unwrap_or(default.speed),
+            lifetime: float("lifetime").unwrap_or(default.lifetime),
+            alpha: float("alpha").unwrap_or(default.alpha),
+            // `colorn` is normalized while `color` uses the 0-255 range
+            color: color("colorn").or_else(|| color("color").map(|c| c / 255.0)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RenderMode {
+    Sprite,
+    SpriteTrail { length: f32, max_length: f32 },
+    Rope,
+}
+
+#[derive(Debug, Clone)]
+struct Particle {
+    position: Vector3<f32>,
+    velocity: Vector3<f32>,
+    rotation: Vector3<f32>,
+    angular_velocity: Vector3<f32>,
+    color: Vector3<f32>,
+    base_size: f32,
+    size: f32,
+    base_alpha: f32,
+    alpha: f32,
+    lifetime: f32,
+    age: f32,
+    turbulence_speed: f32,
+}
+
+impl Particle {
+    fn life(&self) -> f32 {
+        (self.age / self.lifetime).clamp(0.0, 1.0)
+    }
+}
+
+/// Per-instance data uploaded to the GPU, one entry per drawn quad
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct ParticleInstance {
+    pub position: [f32; 3],
+    /// Direction and length the quad is stretched along, zero for regular sprites
+    pub axis: [f32; 3],
+    pub size: f32,
+    pub rotation: f32,
+    pub color: [f32; 4],
+}
+
+pub struct ParticleSystem {
+    definition: ParticleDefinition,
+    overrides: InstanceOverride,
+    render_mode: RenderMode,
+
+    origin: Vector3<f32>,
+    scale: Vector3<f32>,
+    control_points: [Vector3<f32>; MAX_CONTROL_POINTS],
+
+    particles: Vec<Particle>,
+    emit_accumulators: Vec<f32>,
+    bursts_done: bool,
+    time: f32,
+    rng: Rng,
+}
+
+impl ParticleSystem {
+    pub fn new(
+        definition: ParticleDefinition,
+        overrides: InstanceOverride,
+        origin: Vector3<f32>,
+        scale: Vector3<f32>,
+        seed: u64,
+    ) -> Self {
+        let render_mode = definition
+            .renderers
+            .iter()
+            .find_map(|renderer| match renderer {
+                RendererDefinition::Sprite => Some(RenderMode::Sprite),
+                RendererDefinition::SpriteTrail { length, max_length } => {
+                    Some(RenderMode::SpriteTrail {
+                        length: *length,
+                        max_length: *max_length,
+                    })
+                }
+                RendererDefinition::Rope => Some(RenderMode::Rope),
+                RendererDefinition::Unsupported => None,
+            })
+            .unwrap_or(RenderMode::Sprite);
+
+        let mut control_points = [Vector3::zero(); MAX_CONTROL_POINTS];
+        for cp in &definition.control_points {
+            match control_points.get_mut(cp.id as usize) {
+                Some(point) => *point = cp.offset,
+                None => tracing::warn!("Ignoring particle control point {}", cp.id),
+            }
+        }
+
+        let emitter_count = definition.emitters.len();
+        let start_time = definition.start_time.clamp(0.0, MAX_PREWARM_TIME);
+
+        let mut system = Self {
+            time: 0.0,
+            definition,
+            overrides,
+            render_mode,
+            origin,
+            scale,
+            control_points,
+            particles: vec![],
+            emit_accumulators: vec![0.0; emitter_count],
+            bursts_done: false,
+            rng: Rng::new(seed),
+        };
+
+        let mut prewarmed = 0.0;
+        while prewarmed < start_time {
+            let dt = PREWARM_STEP.min(start_time - prewarmed);
+            system.update(dt);
+            prewarmed += dt;
+        }
+
+        system
+    }
+
+    pub fn material(&self) -> Option<&str> {
+        self.definition.material.as_deref()
+    }
+
+    pub fn max_particles(&self) -> usize {
+        (self.definition.max_count as f32 * self.overrides.count).ceil() as usize
+    }
+
+    pub fn update(&mut self, dt: f32) {
+        self.time += dt;
+
+        for particle in &mut self.particles {
+            particle.age += dt;
+        }
+        self.particles.retain(|p| p.age < p.lifetime);
+
+        self.emit(dt);
+        self.apply_operators(dt);
+    }
+
+    fn emit(&mut self, dt: f32) {
+        let max = self.max_particles();
+
+        for i in 0..self.definition.emitters.len() {
+            let (rate, instantaneous) = match &self.definition.emitters[i] {
+                Emitter::BoxRandom { common, .. } | Emitter::SphereRandom { common, .. } => {
+                    (common.rate * self.overrides.rate, common.instantaneous)
+                }
+                Emitter::Unsupported => continue,
+            };
+
+            let mut count = 0;
+
+            if !self.bursts_done {
+                count += (instantaneous * self.overrides.count) as usize;
+            }
+
+            self.emit_accumulators[i] += rate * dt;
+            let whole = self.emit_accumulators[i].floor();
+            self.emit_accumulators[i] -= whole;
+            count += whole as usize;
+
+            for _ in 0..count {
+                if self.particles.len() >= max {
+                    break;
+                }
+
+                let position = self.emitter_position(i);
+                let particle = self.spawn(position);
+                self.particles.push(particle);
+            }
+        }
+
+        self.bursts_done = true;
+    }
+
+    fn emitter_position(&mut self, emitter: usize) -> Vector3<f32> {
+        match &self.definition.emitters[emitter] {
+            Emitter::BoxRandom {
+                common,
+                distance_min,
+                distance_max,
+            } => {
+                let mut offset = self.rng.range_vec3(-*distance_max, *distance_max);
+
+                // Push points out of the inner box along their dominant axis, onto its closest face.
+                // Axes without a minimum distance, like z in flat systems, don't bound the box.
+                let axes: Vec<usize> = (0..3).filter(|&axis| distance_min[axis] > 0.0).collect();
+                let inside = !axes.is_empty()
+                    && axes
+                        .iter()
+                        .all(|&axis| offset[axis].abs() < distance_min[axis]);
+                if inside {
+                    let ratio = |axis: usize| offset[axis].abs() / distance_min[axis];
+                    let axis = axes
+                        .into_iter()
+                        .max_by(|&a, &b| ratio(a).total_cmp(&ratio(b)))
+                        .unwrap();
+                    offset[axis] = distance_min[axis].copysign(offset[axis]);
+                }
+
+                common.origin + mul(offset, common.directions)
+            }
+            Emitter::SphereRandom {
+                common,
+                distance_min,
+                distance_max,
+                sign,
+            } => {
+                let mut direction = self
+                    .rng
+                    .range_vec3(Vector3::new(-1.0, -1.0, -1.0), Vector3::new(1.0, 1.0, 1.0));
+                direction = mul(direction, common.directions);
+                for axis in 0..3 {
+                    if sign[axis] != 0.0 {
+                        direction[axis] = direction[axis].abs().copysign(sign[axis]);
+                    }
+                }
+
+                let direction = if direction.magnitude2() > 0.0 {
+                    direction.normalize()
+                } else {
+                    Vector3::zero()
+                };
+
+                common.origin + direction * self.rng.range(*distance_min, *distance_max)
+            }
+            Emitter::Unsupported => Vector3::zero(),
+        }
+    }
+
+    fn spawn(&mut self, position: Vector3<f32>) -> Particle {
+        let mut particle = Particle {
+            position,
+            velocity: Vector3::zero(),
+            rotation: Vector3::zero(),
+            angular_velocity: Vector3::zero(),
+            color: Vector3::new(1.0, 1.0, 1.0),
+            base_size: 20.0,
+            size: 20.0,
+            base_alpha: 1.0,
+            alpha: 1.0,
+            lifetime: 1.0,
+            age: 0.0,
+            turbulence_speed: 0.0,
+        };
+
+        for initializer in &self.definition.initializers {
+            match initializer {
+                Initializer::SizeRandom { min, max } => {
+                    particle.base_size = self.rng.range(*min, *max)
+                }
+                Initializer::ColorRandom { min, max } => {
+                    particle.color = self.rng.range_vec3(*min, *max) / 255.0
+                }
+                Initializer::AlphaRandom { min, max } => {
+                    particle.base_alpha = self.rng.range(*min, *max)
+                }
+                Initializer::LifetimeRandom { min, max } => {
+                    particle.lifetime = self.rng.range(*min, *max)
+                }
+                Initializer::VelocityRandom { min, max } => {
+                    particle.velocity = self.rng.range_vec3(*min, *max)
+                }
+                Initializer::RotationRandom { min, max } => {
+                    particle.rotation = self.rng.range_vec3(*min, *max)
+                }
+                Initializer::AngularVelocityRandom { min, max } => {
+                    particle.angular_velocity = self.rng.range_vec3(*min, *max)
+                }
+                Initializer::Unsupported => {}
+            }
+        }
+
+        if let Some(Operator::Turbulence {
+            speed_min,
+            speed_max,
+            ..
+        }) = self
+            .definition
+            .operators
+            .iter()
+            .find(|op| matches!(op, Operator::Turbulence { .. }))
+        {
+            particle.turbulence_speed = self.rng.range(*speed_min, *speed_max);
+        }
+
+        if let Some(color) = self.overrides.color {
+            particle.color = mul(particle.color, color);
+        }
+
+        particle.base_size *= self.overrides.size;
+        particle.base_alpha *= self.overrides.alpha;
+        particle.lifetime = (particle.lifetime * self.overrides.lifetime).max(f32::EPSILON);
+        particle.velocity *= self.overrides.speed;
+        particle.size = particle.base_size;
+        particle.alpha = particle.base_alpha;
+
+        particle
+    }
+
+    fn apply_operators(&mut self, dt: f32) {
+        for particle in &mut self.particles {
+            particle.size = particle.base_size;
+            particle.alpha = particle.base_alpha;
+        }
+
+        for operator in &self.definition.operators {
+            match operator {
+                Operator::Movement { drag, gravity } => {
+                    for p in &mut self.particles {
+                        p.velocity += gravity * dt;
+                        p.velocity *= (1.0 - drag * dt).max(0.0);
+                        p.position += p.velocity * dt;
+                    }
+                }
+                Operator::AngularMovement { drag, force } => {
+                    for p in &mut self.particles {
+                        p.angular_velocity += force * dt;
+                        p.angular_velocity *= (1.0 - drag * dt).max(0.0);
+                        p.rotation += p.angular_velocity * dt;
+                    }
+                }
+                Operator::AlphaFade {
+                    fade_in_time,
+                    fade_out_time,
+                } => {
+                    for p in &mut self.particles {
+                        let life = p.life();
+                        if life < *fade_in_time {
+                            p.alpha *= life / fade_in_time;
+                        } else if life > *fade_out_time && *fade_out_time < 1.0 {
+                            p.alpha *= 1.0 - (life - fade_out_time) / (1.0 - fade_out_time);
+                        }
+                    }
+                }
+                Operator::SizeChange { change } => {
+                    for p in &mut self.particles {
+                        p.size *= change.evaluate(p.life());
+                    }
+                }
+                Operator::AlphaChange { change } => {
+                    for p in &mut self.particles {
+                        p.alpha *= change.evaluate(p.life());
+                    }
+                }
+                Operator::Turbulence {
+                    scale, time_scale, ..
+                } => {
+                    let time = self.time * time_scale;
+                    for p in &mut self.particles {
+                        let sample = p.position * (scale * 0.01) + Vector3::new(time, time, time);
+                        let force = noise_vec3(sample);
+                        p.velocity += Vector3::new(force.x, force.y, 0.0) * p.turbulence_speed * dt;
+                    }
+                }
+                Operator::ControlPointAttract {
+                    control_point,
+                    origin,
+                    scale,
+                    threshold,
+                } => {
+                    let target = self
+                        .control_points
+                        .get(*control_point as usize)
+                        .copied()
+                        .unwrap_or_else(Vector3::zero)
+                        + origin;
+
+                    for p in &mut self.particles {
+                        let delta = target - p.position;
+                        let distance = delta.magnitude();
+                        if distance > f32::EPSILON && (*threshold <= 0.0 || distance < *threshold) {
+                            p.velocity += delta / distance * *scale * dt;
+                        }
+                    }
+                }
+                Operator::Unsupported => {}
+            }
+        }
+    }
+
+    /// Fills `out` with one quad per particle, or one per segment for ropes
+    pub fn instances(&self, out: &mut Vec<ParticleInstance>) {
+        out.clear();
+
+        let to_world = |position: Vector3<f32>| self.origin + mul(position, self.scale);
+        let size_scale = (self.scale.x + self.scale.y) * 0.5;
+
+        let instance = |p: &Particle, axis: Vector3<f32>| ParticleInstance {
+            position: to_world(p.position).into(),
+            axis: mul(axis, self.scale).into(),
+            size: p.size * size_scale,
+            rotation: p.rotation.z,
+            color: Vector4::new(p.color.x, p.color.y, p.color.z, p.alpha.clamp(0.0, 1.0)).into(),
+        };
+
+        match self.render_mode {
+            RenderMode::Sprite => {
+                out.extend(self.particles.iter().map(|p| instance(p, Vector3::zero())));
+            }
+            RenderMode::SpriteTrail { length, max_length } => {
+                out.extend(self.particles.iter().map(|p| {
+                    let mut trail = -p.velocity * length;
+                    if max_length > 0.0 && trail.magnitude() > max_length {
+                        trail = trail.normalize() * max_length;
+                    }
+                    instance(p, trail)
+                }));
+            }
+            RenderMode::Rope => {
+                // Particles are kept in spawn order so consecutive ones form the rope
+                out.extend(
+                    self.particles
+                        .windows(2)
+                        .map(|pair| instance(&pair[0], pair[1].position - pair[0].position)),
+                );
+            }
+        }
+    }
+}
+
+fn mul(a: Vector3<f32>, b: Vector3<f32>) -> Vector3<f32> {
+    Vector3::new(a.x * b.x, a.y * b.y, a.z * b.z)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn system(definition: Value) -> ParticleSystem {
+        ParticleSystem::new(
+            serde_json::from_value(definition).unwrap(),
+            InstanceOverride::default(),
+            Vector3::zero(),
+            Vector3::new(1.0, 1.0, 1.0),
+            1,
+        )
+    }
+
+    fn change(start_time: f32, end_time: f32) -> ValueChange {
+        ValueChange {
+            start_time,
+            end_time,
+            start_value: 2.0,
+            end_value: 4.0,
+        }
+    }
+
+    #[test]
+    fn value_change_interpolates_between_its_times() {
+        let change = change(0.25, 0.75);
+
+        assert_eq!(change.evaluate(0.0), 2.0);
+        assert_eq!(change.evaluate(0.25), 2.0);
+        assert_eq!(change.evaluate(0.5), 3.0);
+        assert_eq!(change.evaluate(0.75), 4.0);
+        assert_eq!(change.evaluate(1.0), 4.0);
+    }
+
+    #[test]
+    fn value_change_without_duration_steps() {
+        let step = change(0.5, 0.5);
+
+        assert_eq!(step.evaluate(0.49), 2.0);
+        assert_eq!(step.evaluate(0.5), 4.0);
+        // Inverted times step at the end time too
+        assert_eq!(change(0.8, 0.2).evaluate(0.1), 2.0);
+        assert_eq!(change(0.8, 0.2).evaluate(0.3), 4.0);
+    }
+
+    #[test]
+    fn box_emitter_stays_between_its_boxes() {
+        let mut system = system(json!({
+            "emitter": [{
+                "name": "boxrandom",
+                "distancemin": "10 20 0",
+                "distancemax": "40 50 0",
+            }],
+        }));
+
+        for _ in 0..1000 {
+            let position = system.emitter_position(0);
+
+            assert!(position.x.abs() <= 40.0 && position.y.abs() <= 50.0);
+            assert!(position.x.abs() >= 10.0 || position.y.abs() >= 20.0);
+            assert_eq!(position.z, 0.0);
+        }
+    }
+
+    #[test]
+    fn sphere_emitter_respects_distance_and_sign() {
+        let mut system = system(json!({
+            "emitter": [{
+                "name": "sphererandom",
+                "origin": "100 0 0",
+                "distancemin": 5,
+                "distancemax": 10,
+                "sign": "0 1 0",
+            }],
+        }));
+
+        for _ in 0..1000 {
+            let offset = system.emitter_position(0) - Vector3::new(100.0, 0.0, 0.0);
+            let distance = offset.magnitude();
+
+            assert!((5.0 - 1e-3..=10.0 + 1e-3).contains(&distance));
+            assert!(offset.y >= 0.0);
+        }
+    }
+
+    #[test]
+    fn emitters_burst_once_then_follow_their_rate() {
+        let mut system = system(json!({
+            "emitter": [{ "name": "boxrandom", "rate": 10, "instantaneous": 5 }],
+            "initializer": [{ "name": "lifetimerandom", "min": 100, "max": 100 }],
+        }));
+
+        system.update(0.05);
+        assert_eq!(system.particles.len(), 5);
+
+        system.update(1.0);
+        assert_eq!(system.particles.len(), 15);
+    }
+
+    #[test]
+    fn start_time_prewarms_the_system() {
+        let definition = json!({
+            "emitter": [{ "name": "boxrandom", "rate": 10 }],
+            "initializer": [{ "name": "lifetimerandom", "min": 100, "max": 100 }],
+            "starttime": 2,
+        });
+        let system = system(definition);
+
+        // Emission is accumulated over the prewarm steps
+        assert!((19..=20).contains(&system.particles.len()));
+        assert!(system.particles.iter().any(|p| p.age > 1.5));
+    }
+
+    #[test]
+    fn control_points_past_the_supported_ids_are_ignored() {
+        let system = system(json!({
+            "controlpoint": [
+                { "id": 1, "offset": "1 2 3" },
+                { "id": 4000000000u32, "offset": "4 5 6" },
+            ],
+        }));
+
+        assert_eq!(system.control_points[1], Vector3::new(1.0, 2.0, 3.0));
+    }
+}
@@ -0,0 +1,389 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use serde::Deserialize;
+use smithay_client_toolkit::reexports::calloop::channel::Sender;
+use zbus::blocking::{Connection, Proxy};
+use zbus::zvariant::OwnedValue;
+
+use crate::config::{PowerConfig, PowerRule};
+
+/// Battery and power profile of the machine
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PowerState {
+    pub on_battery: bool,
+    /// Charge of the batteries, unknown on machines without one
+    pub percentage: Option<f64>,
+    /// Active power-profiles-daemon profile, like "power-saver" or "performance"
+    pub profile: Option<String>,
+}
+
+/// How wallpapers back off while a power rule matches
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PowerAction {
+    MaxFps(u32),
+    /// Freezes wallpapers on their current frame
+    Pause,
+    /// Shows the preview image of video wallpapers, which stops their decoder
+    StaticFrame,
+}
+
+impl PowerAction {
+    fn restriction(&self) -> (u8, u32) {
+        match *self {
+            PowerAction::MaxFps(fps) => (0, u32::MAX - fps),
+            PowerAction::Pause => (1, 0),
+            PowerAction::StaticFrame => (2, 0),
+        }
+    }
+}
+
+impl PowerRule {
+    fn matches(&self, state: &PowerState) -> bool {
+        self.on_battery
+            .is_none_or(|on_battery| on_battery == state.on_battery)
+            && self.below_percent.is_none_or(|threshold| {
+                state
+                    .percentage
+                    .is_some_and(|percentage| percentage < threshold)
+            })
+            && self
+                .profile
+                .as_ref()
+                .is_none_or(|profile| state.profile.as_ref() == Some(profile))
+    }
+}
+
+/// The most restrictive action of the rules matching `state`
+pub fn select_action(rules: &[PowerRule], state: &PowerState) -> Option<PowerAction> {
+    rules
+        .iter()
+        .filter(|rule| rule.matches(state))
+        .map(|rule| rule.action)
+        .max_by_key(PowerAction::restriction)
+}
+
+/// Where the power state is read from
+enum PowerBackend {
+    /// UPower and power-profiles-daemon, on the system bus
+    DBus(DBusProxies),
+    /// A TOML file written by hand, to try rules out without unplugging anything
+    File(PathBuf),
+}
+
+/// Objects the power state is read from. Their properties are cached and kept up to date by
+/// zbus from the PropertiesChanged signals.
+struct DBusProxies {
+    upower: Proxy<'static>,
+    // Combines every battery of the machine
+    display_device: Proxy<'static>,
+    profiles: Proxy<'static>,
+}
+
+/// Sends the power state to `sender` whenever it changes. Nothing is sent when no rule is set, or
+/// when neither the state file nor the system bus can be read.
+pub fn start_monitor(config: &PowerConfig, sender: Sender<PowerState>) {
+    if config.rules.is_empty() {
+        return;
+    }
+
+    let backend = match &config.state_file {
+        Some(path) => PowerBackend::File(path.clone()),
+        None => match Connection::system().and_then(|connection| DBusProxies::new(&connection)) {
+            Ok(proxies) => PowerBackend::DBus(proxies),
+            Err(err) => {
+                tracing::warn!("Can't connect to the system bus, power rules are ignored: {err}");
+                return;
+            }
+        },
+    };
+    let interval = Duration::from_secs(config.poll_interval_secs.max(1));
+
+    thread::spawn(move || {
+        let mut last_state = None;
+        // Returns false once the daemon stopped listening
+        let mut report = |state: PowerState| {
+            if last_state.as_ref() == Some(&state) {
+                return true;
+            }
+
+            tracing::info!("Power state changed: {state:?}");
+            last_state = Some(state.clone());
+            sender.send(state).is_ok()
+        };
+
+        match backend {
+            PowerBackend::DBus(proxies) => {
+                let (changed_tx, changed_rx) = mpsc::channel();
+                proxies.watch(changed_tx);
+
+                // Every property is reported once when watched, which reads the initial state
+                while changed_rx.recv().is_ok() {
+                    // Properties often change together, like the percentage with the state
+                    while changed_rx.try_recv().is_ok() {}
+
+                    if !report(proxies.read()) {
+                        break;
+                    }
+                }
+            }
+            // Files don't tell when they change
+            PowerBackend::File(path) => loop {
+                if let Some(state) = read_file(&path)
+                    && !report(state)
+                {
+                    break;
+                }
+
+                thread::sleep(interval);
+            },
+        }
+    });
+}
+
+impl DBusProxies {
+    fn new(connection: &Connection) -> zbus::Result<Self> {
+        Ok(Self {
+            upower: Proxy::new(
+                connection,
+                "org.freedesktop.UPower",
+                "/org/freedesktop/UPower",
+                "org.freedesktop.UPower",
+            )?,
+            display_device: Proxy::new(
+                connection,
+                "org.freedesktop.UPower",
+                "/org/freedesktop/UPower/devices/DisplayDevice",
+                "org.freedesktop.UPower.Device",
+            )?,
+            profiles: Proxy::new(
+                connection,
+                "net.hadess.PowerProfiles",
+                "/net/hadess/PowerProfiles",
+                "net.hadess.PowerProfiles",
+            )?,
+        })
+    }
+
+    /// Sends to `changed` whenever a property the power state is made of changes, from a thread
+    /// per property as the iterators block
+    fn watch(&self, changed: mpsc::Sender<()>) {
+        let properties = [
+            (&self.upower, "OnBattery"),
+            (&self.display_device, "IsPresent"),
+            (&self.display_device, "Percentage"),
+            (&self.profiles, "ActiveProfile"),
+        ];
+
+        for (proxy, property) in properties {
+            let proxy = proxy.clone();
+            let changed = changed.clone();
+
+            thread::spawn(move || {
+                for _ in proxy.receive_property_changed::<OwnedValue>(property) {
+                    if changed.send(()).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    }
+
+    fn read(&self) -> PowerState {
+        let on_battery = self
+            .upower
+            .get_property::<bool>("OnBattery")
+            .unwrap_or_else(|err| {
+                tracing::debug!("Can't read the UPower state: {err}");
+                false
+            });
+
+        let percentage = self
+            .display_device
+            .get_property::<bool>("IsPresent")
+            .ok()
+            .filter(|present| *present)
+            .and_then(|_| self.display_device.get_property::<f64>("Percentage").ok());
+
+        let profile = self.profiles.get_property::<String>("ActiveProfile").ok();
+
+        PowerState {
+            on_battery,
+            percentage,
+            profile,
+        }
+    }
+}
+
+fn read_file(path: &Path) -> Option<PowerState> {
+    let contents = fs::read_to_string(path)
+        .inspect_err(|err| tracing::warn!("Failed to read {}: {err}", path.to_string_lossy()))
+        .ok()?;
+
+    toml::from_str(&contents)
+        .inspect_err(|err| tracing::warn!("Invalid power state {}: {err}", path.to_string_lossy()))
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(
+        on_battery: Option<bool>,
+        below_percent: Option<f64>,
+        profile: Option<&str>,
+        action: PowerAction,
+    ) -> PowerRule {
+        PowerRule {
+            on_battery,
+            below_percent,
+            profile: profile.map(str::to_string),
+            action,
+        }
+    }
+
+    fn state(on_battery: bool, percentage: Option<f64>, profile: Option<&str>) -> PowerState {
+        PowerState {
+            on_battery,
+            percentage,
+            profile: profile.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn threshold_is_exclusive() {
+        let rules = [rule(None, Some(20.0), None, PowerAction::Pause)];
+
+        assert_eq!(
+            select_action(&rules, &state(true, Some(19.9), None)),
+            Some(PowerAction::Pause)
+        );
+        assert_eq!(select_action(&rules, &state(true, Some(20.0), None)), None);
+        assert_eq!(select_action(&rules, &state(true, Some(80.0), None)), None);
+        // Machines without a battery never match a threshold
+        assert_eq!(select_action(&rules, &state(false, None, None)), None);
+    }
+
+    #[test]
+    fn on_battery_or_plugged() {
+        let rules = [
+            rule(Some(true), None, None, PowerAction::MaxFps(30)),
+            rule(Some(false), None, None, PowerAction::MaxFps(144)),
+        ];
+
+        assert_eq!(
+            select_action(&rules, &state(true, Some(50.0), None)),
+            Some(PowerAction::MaxFps(30))
+        );
+        assert_eq!(
+            select_action(&rules, &state(false, Some(50.0), None)),
+            Some(PowerAction::MaxFps(144))
+        );
+    }
+
+    #[test]
+    fn profile_matches_exactly() {
+        let rules = [rule(
+            None,
+            None,
+            Some("power-saver"),
+            PowerAction::StaticFrame,
+        )];
+
+        assert_eq!(
+            select_action(&rules, &state(false, None, Some("power-saver"))),
+            Some(PowerAction::StaticFrame)
+        );
+        assert_eq!(
+            select_action(&rules, &state(false, None, Some("balanced"))),
+            None
+        );
+        assert_eq!(select_action(&rules, &state(false, None, None)), None);
+    }
+
+    #[test]
+    fn every_condition_of_a_rule_has_to_match() {
+        let rules = [rule(Some(true), Some(20.0), None, PowerAction::Pause)];
+
+        assert_eq!(select_action(&rules, &state(false, Some(10.0), None)), None);
+        assert_eq!(select_action(&rules, &state(true, Some(30.0), None)), None);
+        assert_eq!(
+            select_action(&rules, &state(true, Some(10.0), None)),
+            Some(PowerAction::Pause)
+        );
+    }
+
+    #[test]
+    fn most_restrictive_action_wins() {
+        let rules = [
+            rule(Some(true), None, None, PowerAction::MaxFps(30)),
+            rule(Some(true), None, None, PowerAction::MaxFps(15)),
+            rule(None, Some(20.0), None, PowerAction::StaticFrame),
+            rule(None, None, Some("power-saver"), PowerAction::Pause),
+        ];
+
+        assert_eq!(
+            select_action(&rules, &state(true, Some(50.0), None)),
+            Some(PowerAction::MaxFps(15))
+        );
+        assert_eq!(
+            select_action(&rules, &state(true, Some(50.0), Some("power-saver"))),
+            Some(PowerAction::Pause)
+        );
+        assert_eq!(
+            select_action(&rules, &state(true, Some(10.0), Some("power-saver"))),
+            Some(PowerAction::StaticFrame)
+        );
+    }
+
+    #[test]
+    fn no_rule_matching() {
+        assert_eq!(select_action(&[], &state(true, Some(5.0), None)), None);
+    }
+
+    fn state_file(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "waypaper-engine-power-{}-{name}.toml",
+            std::process::id()
+        ));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn read_file_fields() {
+        let path = state_file(
+            "full",
+            "on_battery = true\npercentage = 15.0\nprofile = \"power-saver\"\n",
+        );
+        assert_eq!(
+            read_file(&path),
+            Some(state(true, Some(15.0), Some("power-saver")))
+        );
+        fs::remove_file(path).unwrap();
+
+        // Missing fields keep their default
+        let path = state_file("partial", "on_battery = true\n");
+        assert_eq!(read_file(&path), Some(state(true, None, None)));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn read_file_rejects_unknown_fields() {
+        // A typo would otherwise silently read as plugged in
+        let path = state_file("unknown", "onbattery = true\n");
+        assert_eq!(read_file(&path), None);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn read_file_missing() {
+        assert_eq!(read_file(Path::new("/nonexistent/power-state.toml")), None);
+    }
+}
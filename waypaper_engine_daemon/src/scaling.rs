@@ -0,0 +1,209 @@
+use cgmath::{Matrix4, Vector3};
+use gl::types::GLsizei;
+
+use waypaper_engine_shared::ipc::{Scaling, ScalingMode};
+
+use crate::span::Span;
+
+/// Where a wallpaper of a given size is drawn on an output
+///
+/// The quad transform maps a fullscreen quad to the wallpaper rectangle, in normalized device
+/// coordinates with y pointing down. The uv transform is only used by tiling, which keeps the
+/// fullscreen quad and repeats the texture instead.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Placement {
+    pub quad_scale: (f32, f32),
+    pub quad_offset: (f32, f32),
+    pub uv_scale: (f32, f32),
+    pub uv_offset: (f32, f32),
+}
+
+impl Placement {
+    /// A wallpaper spanning several outputs is placed on their canvas, offsets are then in logical
+    /// pixels instead of output pixels
+    pub fn new(
+        scaling: &Scaling,
+        content: (u32, u32),
+        output: (u32, u32),
+        span: Option<&Span>,
+    ) -> Self {
+        match span {
+            Some(span) => Self::place(scaling, content, span.canvas).cropped(span),
+            None => Self::place(scaling, content, output),
+        }
+    }
+
+    fn place(scaling: &Scaling, content: (u32, u32), output: (u32, u32)) -> Self {
+        let content = (content.0.max(1) as f32, content.1.max(1) as f32);
+        let output = (output.0.max(1) as f32, output.1.max(1) as f32);
+
+        let size = match scaling.mode {
+            ScalingMode::Cover => {
+                let scale = (output.0 / content.0).max(output.1 / content.1);
+                (content.0 * scale, content.1 * scale)
+            }
+            ScalingMode::Contain => {
+                let scale = (output.0 / content.0).min(output.1 / content.1);
+                (content.0 * scale, content.1 * scale)
+            }
+            ScalingMode::Stretch => output,
+            ScalingMode::Center | ScalingMode::Tile => content,
+        };
+
+        // Top left corner of the wallpaper in output pixels
+        let position = (
+            (output.0 - size.0) * scaling.alignment.0 + scaling.offset.0,
+            (output.1 - size.1) * scaling.alignment.1 + scaling.offset.1,
+        );
+
+        if scaling.mode == ScalingMode::Tile {
+            return Self {
+                quad_scale: (1.0, 1.0),
+                quad_offset: (0.0, 0.0),
+                uv_scale: (output.0 / content.0, output.1 / content.1),
+                uv_offset: (-position.0 / content.0, -position.1 / content.1),
+            };
+        }
+
+        Self {
+            quad_scale: (size.0 / output.0, size.1 / output.1),
+            quad_offset: (
+                (position.0 + size.0 / 2.0) / output.0 * 2.0 - 1.0,
+                (position.1 + size.1 / 2.0) / output.1 * 2.0 - 1.0,
+            ),
+            uv_scale: (1.0, 1.0),
+            uv_offset: (0.0, 0.0),
+        }
+    }
+
+    /// Maps a placement on the canvas of a span to the part of it shown by one output
+    fn cropped(self, span: &Span) -> Self {
+        let canvas = (span.canvas.0.max(1) as f32, span.canvas.1.max(1) as f32);
+        let size = (span.size.0.max(1) as f32, span.size.1.max(1) as f32);
+        let position = (span.position.0 as f32, span.position.1 as f32);
+
+        // Canvas coordinates are scaled up around the center of the output rectangle, textures
+        // keep their mapping on the quad
+        let zoom = (canvas.0 / size.0, canvas.1 / size.1);
+        let center = (
+            (position.0 * 2.0 + size.0) / canvas.0 - 1.0,
+            (position.1 * 2.0 + size.1) / canvas.1 - 1.0,
+        );
+
+        Self {
+            quad_scale: (self.quad_scale.0 * zoom.0, self.quad_scale.1 * zoom.1),
+            quad_offset: (
+                (self.quad_offset.0 - center.0) * zoom.0,
+                (self.quad_offset.1 - center.1) * zoom.1,
+            ),
+            ..self
+        }
+    }
+
+    /// Maps a position normalized to the output to the same point normalized to the wallpaper,
+    /// both with y pointing down. Spanned wallpapers give every output of the span the same
+    /// position on the canvas.
+    pub fn content_position(&self, position: (f32, f32)) -> (f32, f32) {
+        let to_content = |output: f32, offset: f32, scale: f32| {
+            ((output * 2.0 - 1.0 - offset) / scale + 1.0) / 2.0
+        };
+
+        (
+            to_content(position.0, self.quad_offset.0, self.quad_scale.0),
+            to_content(position.1, self.quad_offset.1, self.quad_scale.1),
+        )
+    }
+
+    /// Applies the quad transform after a projection to clip space with y pointing up
+    pub fn clip_transform(&self) -> Matrix4<f32> {
+        Matrix4::from_translation(Vector3::new(self.quad_offset.0, -self.quad_offset.1, 0.0))
+            * Matrix4::from_nonuniform_scale(self.quad_scale.0, self.quad_scale.1, 1.0)
+    }
+
+    /// Fills the output around the wallpaper with `color`, for renderers that can draw outside of
+    /// the wallpaper bounds
+    pub fn clear_outside(&self, output: (u32, u32), color: (f32, f32, f32)) {
+        let (width, height) = (output.0 as GLsizei, output.1 as GLsizei);
+        let to_pixels = |ndc: f32, size: GLsizei| {
+            (((ndc + 1.0) / 2.0 * size as f32).round() as GLsizei).clamp(0, size)
+        };
+
+        let left = to_pixels(self.quad_offset.0 - self.quad_scale.0, width);
+        let right = to_pixels(self.quad_offset.0 + self.quad_scale.0, width);
+        let top = to_pixels(self.quad_offset.1 - self.quad_scale.1, height);
+        let bottom = to_pixels(self.quad_offset.1 + self.quad_scale.1, height);
+
+        // Scissor boxes start from the bottom left corner
+        let bars = [
+            (0, 0, width, height - bottom),
+            (0, height - top, width, top),
+            (0, height - bottom, left, bottom - top),
+            (right, height - bottom, width - right, bottom - top),
+        ];
+
+        unsafe {
+            gl::Enable(gl::SCISSOR_TEST);
+            gl::ClearColor(color.0, color.1, color.2, 1.0);
+
+            for (x, y, width, height) in bars {
+                if width > 0 && height > 0 {
+                    gl::Scissor(x, y, width, height);
+                    gl::Clear(gl::COLOR_BUFFER_BIT);
+                }
+            }
+
+            gl::Disable(gl::SCISSOR_TEST);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: (f32, f32), expected: (f32, f32)) {
+        assert!(
+            (actual.0 - expected.0).abs() < 1e-4 && (actual.1 - expected.1).abs() < 1e-4,
+            "{actual:?} != {expected:?}"
+        );
+    }
+
+    #[test]
+    fn content_position_accounts_for_letterboxing() {
+        let scaling = Scaling {
+            mode: ScalingMode::Contain,
+            ..Scaling::default()
+        };
+        // A square wallpaper on a wide output keeps a quarter of the width empty on each side
+        let placement = Placement::new(&scaling, (100, 100), (200, 100), None);
+
+        assert_close(placement.content_position((0.5, 0.5)), (0.5, 0.5));
+        assert_close(placement.content_position((0.25, 0.0)), (0.0, 0.0));
+        assert_close(placement.content_position((0.75, 1.0)), (1.0, 1.0));
+    }
+
+    #[test]
+    fn content_position_is_on_the_span_canvas() {
+        let scaling = Scaling {
+            mode: ScalingMode::Stretch,
+            ..Scaling::default()
+        };
+        let left = Span {
+            canvas: (200, 100),
+            position: (0, 0),
+            size: (100, 100),
+        };
+        let right = Span {
+            position: (100, 0),
+            ..left
+        };
+
+        let left = Placement::new(&scaling, (50, 50), (100, 100), Some(&left));
+        let right = Placement::new(&scaling, (50, 50), (100, 100), Some(&right));
+
+        assert_close(left.content_position((0.5, 0.5)), (0.25, 0.5));
+        assert_close(right.content_position((0.5, 0.5)), (0.75, 0.5));
+        // The seam is the same point of the wallpaper on both outputs
+        assert_close(left.content_position((1.0, 0.0)), right.content_position((0.0, 0.0)));
+    }
+}
@@ -5,7 +5,9 @@ use serde::Deserialize;
 use serde_json::Value;
 use serde_this_or_that::as_bool;
 
-use waypaper_engine_shared::serde_utils::{as_vec2f32, as_vec3f32, from_str_color};
+use waypaper_engine_shared::serde_utils::{
+    as_f32_lenient, as_vec2f32, as_vec2f32_opt, as_vec3f32, as_vec3f32_lenient, from_str_color,
+};
 
 #[derive(Debug, Clone, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -15,6 +17,19 @@ pub struct Scene {
     pub objects: Vec<Object>,
 }
 
+/// Size of the orthogonal projection of a scene, 1920x1080 when it doesn't set a usable one
+pub fn scene_size(scene: Option<&Scene>) -> Vector2<f32> {
+    let (width, height) = scene
+        .map(|scene| {
+            let projection = &scene.general.orthogonalprojection;
+            (projection.width as f32, projection.height as f32)
+        })
+        .filter(|(width, height)| *width > 0.0 && *height > 0.0)
+        .unwrap_or((1920.0, 1080.0));
+
+    Vector2::new(width, height)
+}
+
 #[derive(Debug, Clone, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Camera {
@@ -33,7 +48,7 @@ pub struct General {
     pub ambientcolor: (f64, f64, f64),
     pub bloom: bool,
     pub bloomstrength: f64,
-    pub bloomthreshold: i64,
+    pub bloomthreshold: f64,
     pub camerafade: bool,
     pub cameraparallax: bool,
     pub cameraparallaxamount: f64,
@@ -63,21 +78,21 @@ pub struct OrthogonalProjection {
 #[serde(tag = "type", rename_all = "camelCase")]
 pub struct Object {
     #[serde(deserialize_with = "as_vec3f32")]
-    angles: Vector3<f32>,
+    pub angles: Vector3<f32>,
     #[serde(deserialize_with = "as_vec3f32")]
-    origin: Vector3<f32>,
+    pub origin: Vector3<f32>,
     #[serde(deserialize_with = "as_vec3f32")]
-    scale: Vector3<f32>,
+    pub scale: Vector3<f32>,
 
-    name: String,
+    pub name: String,
 
     #[serde(alias = "parallaxDepth", deserialize_with = "as_vec2f32")]
-    parallax_depth: Vector2<f32>,
+    pub parallax_depth: Vector2<f32>,
 
-    id: u32,
+    pub id: u32,
 
     #[serde(flatten)]
-    value: ObjectValue,
+    pub value: ObjectValue,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -107,7 +122,78 @@ pub enum ObjectValue {
         image: Option<String>,
         model: Option<String>,
         particle: String,
-        #[serde(alias = "instanceoverride")]
+        #[serde(alias = "instanceoverride", default)]
         instance_override: HashMap<String, Value>,
     },
+    Text {
+        text: TextValue,
+        font: String,
+        #[serde(alias = "pointsize", deserialize_with = "as_f32_lenient")]
+        point_size: f32,
+        #[serde(alias = "horizontalalign", default)]
+        horizontal_align: HorizontalAlign,
+        #[serde(alias = "verticalalign", default)]
+        vertical_align: VerticalAlign,
+        #[serde(default, deserialize_with = "as_f32_lenient")]
+        padding: f32,
+        #[serde(default = "white", deserialize_with = "as_vec3f32_lenient")]
+        color: Vector3<f32>,
+        #[serde(default = "one", deserialize_with = "as_f32_lenient")]
+        alpha: f32,
+        #[serde(default, deserialize_with = "as_vec2f32_opt")]
+        size: Option<Vector2<f32>>,
+    },
+}
+
+/// Text content, either plain or driven by a script we don't execute (its last value is used)
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum TextValue {
+    Plain(String),
+    Scripted {
+        value: String,
+        #[serde(default)]
+        script: Option<String>,
+    },
+}
+
+impl TextValue {
+    pub fn value(&self) -> &str {
+        match self {
+            TextValue::Plain(value) | TextValue::Scripted { value, .. } => value,
+        }
+    }
+
+    pub fn script(&self) -> Option<&str> {
+        match self {
+            TextValue::Plain(_) => None,
+            TextValue::Scripted { script, .. } => script.as_deref(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum HorizontalAlign {
+    Left,
+    #[default]
+    Center,
+    Right,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum VerticalAlign {
+    Top,
+    #[default]
+    Center,
+    Bottom,
+}
+
+fn white() -> Vector3<f32> {
+    Vector3::new(1.0, 1.0, 1.0)
+}
+
+fn one() -> f32 {
+    1.0
 }
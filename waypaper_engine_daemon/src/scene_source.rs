@@ -0,0 +1,424 @@
+use std::cell::{Cell, RefCell, RefMut};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::rc::{Rc, Weak};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use cgmath::Vector2;
+
+use crate::audio::{AudioMixer, TrackHandle};
+use crate::audio_source::{PlaybackMode, SoundInput};
+use crate::camera::SceneCamera;
+use crate::particle_renderer::ParticleDrawable;
+use crate::particle_system::{InstanceOverride, ParticleDefinition, ParticleSystem};
+use crate::scene::{scene_size, ObjectValue, Scene};
+use crate::scene_package::ScenePackage;
+use crate::text_renderer::{TextDrawable, TextLayout};
+use crate::wallpaper_renderer::{OutputSettings, Playback};
+
+// Avoid huge simulation steps after no output requested frames for a while
+const MAX_FRAME_DELTA: f32 = 0.1;
+
+/// Scenes being played, shared by the renderers of every output so a scene shown on several
+/// outputs, or spanning them, is simulated once and its sounds are only heard once
+#[derive(Clone)]
+pub struct SceneSources {
+    mixer: AudioMixer,
+    sources: Rc<RefCell<HashMap<PathBuf, Weak<RefCell<SceneSource>>>>>,
+    next_output_id: Rc<Cell<usize>>,
+}
+
+/// Objects, camera and sounds of a scene, moved forward by whichever output draws first
+pub struct SceneSource {
+    objects: Vec<SceneObject>,
+    camera: Option<SceneCamera>,
+    sounds: Vec<SceneSound>,
+
+    last_update: Instant,
+    playback: Playback,
+    // Settings of the outputs showing the scene, by output id
+    outputs: HashMap<usize, OutputSettings>,
+    // Sounds pause when every output showing the scene is suspended
+    suspended_outputs: HashSet<usize>,
+    // Output the pointer is on, and its position on the scene
+    pointer: Option<(usize, Vector2<f32>)>,
+}
+
+/// Drawable scene objects, kept in scene.json order as it is also the drawing order
+pub struct SceneObject {
+    pub parallax_depth: Vector2<f32>,
+    pub drawable: Drawable,
+}
+
+pub enum Drawable {
+    Particles(ParticleDrawable),
+    Text(TextDrawable),
+}
+
+struct SceneSound {
+    track: TrackHandle,
+    volume: f32,
+}
+
+/// An output showing a scene, which is unloaded once no output shows it anymore
+pub struct SceneSourceHandle {
+    source: Rc<RefCell<SceneSource>>,
+    output_id: usize,
+}
+
+impl SceneSources {
+    pub fn new(mixer: AudioMixer) -> Self {
+        Self {
+            mixer,
+            sources: Rc::new(RefCell::new(HashMap::new())),
+            next_output_id: Rc::new(Cell::new(0)),
+        }
+    }
+
+    /// Joins the scene loaded from the package at `path`, loading it when no output shows it yet
+    ///
+    /// Needs a current GL context.
+    pub fn open(
+        &self,
+        path: &Path,
+        scene: Option<&Scene>,
+        package: &ScenePackage,
+        settings: &OutputSettings,
+    ) -> SceneSourceHandle {
+        let existing = self.sources.borrow().get(path).and_then(Weak::upgrade);
+
+        let source = match existing {
+            Some(source) => {
+                tracing::debug!("Sharing the scene {}", path.to_string_lossy());
+                source
+            }
+            None => {
+                let source = Rc::new(RefCell::new(SceneSource::load(scene, package, &self.mixer)));
+
+                let mut sources = self.sources.borrow_mut();
+                sources.retain(|_, source| source.strong_count() > 0);
+                sources.insert(path.to_path_buf(), Rc::downgrade(&source));
+                source
+            }
+        };
+
+        self.attach(source, settings)
+    }
+
+    /// Plays the scene of `handle` from its start in a source of its own, which other outputs
+    /// don't join. Used when the outputs sharing a scene want different playback.
+    ///
+    /// Needs a current GL context.
+    pub fn detach(
+        &self,
+        handle: &SceneSourceHandle,
+        scene: Option<&Scene>,
+        package: &ScenePackage,
+    ) -> SceneSourceHandle {
+        let settings = handle.source.borrow().outputs[&handle.output_id];
+        tracing::debug!("Playing a scene separately on an output");
+
+        let source = SceneSource::load(scene, package, &self.mixer);
+        self.attach(Rc::new(RefCell::new(source)), &settings)
+    }
+
+    fn attach(
+        &self,
+        source: Rc<RefCell<SceneSource>>,
+        settings: &OutputSettings,
+    ) -> SceneSourceHandle {
+        let output_id = self.next_output_id.get();
+        self.next_output_id.set(output_id + 1);
+
+        let handle = SceneSourceHandle { source, output_id };
+        handle.set_output_settings(settings);
+        handle
+    }
+}
+
+impl SceneSource {
+    fn load(scene: Option<&Scene>, package: &ScenePackage, mixer: &AudioMixer) -> Self {
+        let mut objects = vec![];
+        let mut sounds = vec![];
+
+        for object in scene.iter().flat_map(|scene| &scene.objects) {
+            match &object.value {
+                ObjectValue::Particle {
+                    particle,
+                    instance_override,
+                    ..
+                } => {
+                    let Some(file) = package.contents.get(particle) else {
+                        tracing::warn!("Particle file {particle} not found in scene package");
+                        continue;
+                    };
+
+                    let definition =
+                        match serde_json::from_slice::<ParticleDefinition>(file.bytes()) {
+                            Ok(definition) => definition,
+                            Err(err) => {
+                                tracing::warn!("Failed to parse particle {particle}: {err}");
+                                continue;
+                            }
+                        };
+
+                    let system = ParticleSystem::new(
+                        definition,
+                        InstanceOverride::from_map(instance_override),
+                        object.origin,
+                        object.scale,
+                        u64::from(object.id),
+                    );
+
+                    objects.push(SceneObject {
+                        parallax_depth: object.parallax_depth,
+                        drawable: Drawable::Particles(ParticleDrawable::new(system, package)),
+                    });
+                }
+                ObjectValue::Text {
+                    text,
+                    font,
+                    point_size,
+                    horizontal_align,
+                    vertical_align,
+                    padding,
+                    color,
+                    alpha,
+                    size,
+                } => {
+                    let layout = TextLayout {
+                        origin: object.origin,
+                        scale: object.scale,
+                        size: *size,
+                        padding: *padding,
+                        horizontal_align: *horizontal_align,
+                        vertical_align: *vertical_align,
+                        color: [color.x, color.y, color.z, *alpha],
+                    };
+
+                    if let Some(drawable) =
+                        TextDrawable::new(text, font, *point_size, layout, package)
+                    {
+                        objects.push(SceneObject {
+                            parallax_depth: object.parallax_depth,
+                            drawable: Drawable::Text(drawable),
+                        });
+                    }
+                }
+                ObjectValue::Sound {
+                    sound,
+                    volume,
+                    playback_mode,
+                    ..
+                } => {
+                    let inputs: Vec<SoundInput> = sound
+                        .iter()
+                        .filter_map(|name| match package.contents.get(name) {
+                            Some(file) => Some(SoundInput::Memory {
+                                name: name.clone(),
+                                bytes: Arc::from(file.bytes()),
+                            }),
+                            None => {
+                                tracing::warn!("Sound {name} not found in scene package");
+                                None
+                            }
+                        })
+                        .collect();
+
+                    if inputs.is_empty() {
+                        continue;
+                    }
+
+                    // Stays silent until an output sets its volume
+                    let track = mixer.play(inputs, PlaybackMode::from_scene(playback_mode), 1.0);
+                    track.set_muted(true);
+                    track.set_paused(true);
+
+                    sounds.push(SceneSound {
+                        track,
+                        volume: *volume,
+                    });
+                }
+                _ => tracing::debug!("Skipping unsupported scene object {}", object.name),
+            }
+        }
+
+        tracing::debug!("Loaded {} scene objects", objects.len());
+
+        Self {
+            objects,
+            camera: scene.map(|scene| SceneCamera::new(&scene.general, scene_size(Some(scene)))),
+            sounds,
+            last_update: Instant::now(),
+            playback: Playback::default(),
+            outputs: HashMap::new(),
+            suspended_outputs: HashSet::new(),
+            pointer: None,
+        }
+    }
+
+    pub fn objects(&self) -> &[SceneObject] {
+        &self.objects
+    }
+
+    pub fn camera(&self) -> Option<&SceneCamera> {
+        self.camera.as_ref()
+    }
+
+    /// Moves the scene forward to now. Every output showing the scene calls it before drawing,
+    /// so the scene moves at the pace of the clock rather than of the frames of one output.
+    pub fn update(&mut self) {
+        let now = Instant::now();
+        let dt = now
+            .duration_since(self.last_update)
+            .as_secs_f32()
+            .min(MAX_FRAME_DELTA);
+        self.last_update = now;
+
+        // Scene time follows the playback rate, and stops while paused
+        let dt = if self.playback.paused {
+            0.0
+        } else {
+            dt * self.playback.rate
+        };
+
+        if let Some(camera) = self.camera.as_mut() {
+            camera.set_pointer(self.pointer.map(|(_, position)| position));
+            camera.update(dt);
+        }
+
+        for object in &mut self.objects {
+            match &mut object.drawable {
+                Drawable::Particles(drawable) => drawable.system.update(dt),
+                Drawable::Text(drawable) => drawable.update(),
+            }
+        }
+    }
+
+    /// Scenes made of still images and text only change with the pointer and the clock
+    pub fn is_animated(&self) -> bool {
+        self.objects
+            .iter()
+            .any(|object| matches!(object.drawable, Drawable::Particles(_)))
+            || self.camera.as_ref().is_some_and(SceneCamera::is_animated)
+    }
+
+    pub fn next_change(&self) -> Option<Duration> {
+        self.objects
+            .iter()
+            .filter_map(|object| match &object.drawable {
+                Drawable::Text(drawable) => drawable.next_change(),
+                _ => None,
+            })
+            .min()
+    }
+
+    fn apply_playback(&mut self, playback: Playback) {
+        if self.playback != playback {
+            self.playback = playback;
+            self.update_sounds();
+        }
+    }
+
+    /// Plays the sounds at the volume of the loudest output that isn't muted
+    fn update_sounds(&self) {
+        let volume = self
+            .outputs
+            .values()
+            .filter(|settings| !settings.muted)
+            .map(|settings| settings.volume)
+            .reduce(f32::max);
+        let suspended = self
+            .outputs
+            .keys()
+            .all(|output_id| self.suspended_outputs.contains(output_id));
+
+        for sound in &self.sounds {
+            match volume {
+                Some(volume) => {
+                    sound.track.set_volume(sound.volume * volume);
+                    sound.track.set_muted(false);
+                }
+                None => sound.track.set_muted(true),
+            }
+            sound.track.set_paused(self.playback.paused || suspended);
+            sound.track.set_speed(self.playback.rate);
+        }
+    }
+}
+
+impl SceneSourceHandle {
+    pub fn get(&self) -> RefMut<'_, SceneSource> {
+        self.source.borrow_mut()
+    }
+
+    /// The playback is only applied by `sync_playback`, so outputs updated one after the other
+    /// don't see each other's stale settings
+    pub fn set_output_settings(&self, settings: &OutputSettings) {
+        let mut source = self.source.borrow_mut();
+        source.outputs.insert(self.output_id, *settings);
+        source.update_sounds();
+    }
+
+    /// Applies the playback of this output, returns false when another output showing the scene
+    /// wants a different one
+    pub fn sync_playback(&self) -> bool {
+        let mut source = self.source.borrow_mut();
+        let playback = source.outputs[&self.output_id].playback;
+
+        // Scenes have no timeline to loop a part of
+        if source.outputs.values().any(|settings| {
+            settings.playback.paused != playback.paused || settings.playback.rate != playback.rate
+        }) {
+            return false;
+        }
+
+        source.apply_playback(playback);
+        true
+    }
+
+    pub fn set_suspended(&self, suspended: bool) {
+        let mut source = self.source.borrow_mut();
+        if suspended {
+            source.suspended_outputs.insert(self.output_id);
+        } else {
+            source.suspended_outputs.remove(&self.output_id);
+        }
+
+        source.update_sounds();
+    }
+
+    /// Pointer position on the scene normalized to [0, 1], `None` when it left this output. Every
+    /// output of a span gives positions on the whole scene, so its camera moves as one.
+    pub fn set_pointer(&self, position: Option<Vector2<f32>>) {
+        let mut source = self.source.borrow_mut();
+
+        match position {
+            Some(position) => source.pointer = Some((self.output_id, position)),
+            None => {
+                if source
+                    .pointer
+                    .is_some_and(|(output_id, _)| output_id == self.output_id)
+                {
+                    source.pointer = None;
+                }
+            }
+        }
+    }
+}
+
+impl Drop for SceneSourceHandle {
+    fn drop(&mut self) {
+        let mut source = self.source.borrow_mut();
+        source.outputs.remove(&self.output_id);
+        source.suspended_outputs.remove(&self.output_id);
+        if source
+            .pointer
+            .is_some_and(|(output_id, _)| output_id == self.output_id)
+        {
+            source.pointer = None;
+        }
+        source.update_sounds();
+    }
+}
@@ -0,0 +1,324 @@
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::time::Duration;
+
+use cgmath::{Matrix4, Vector2};
+use gl::types::GLsizei;
+use smithay_client_toolkit::reexports::client::Connection;
+
+use waypaper_engine_shared::ipc::{Scaling, ScalingMode};
+use waypaper_engine_shared::project::WallpaperType;
+
+use crate::bloom::{BloomPass, BloomSettings};
+use crate::camera::FadeRenderer;
+use crate::egl::EGLState;
+use crate::particle_renderer::ParticleRenderer;
+use crate::scaling::Placement;
+use crate::scene::{scene_size, Scene};
+use crate::scene_package::ScenePackage;
+use crate::scene_source::{Drawable, SceneSourceHandle, SceneSources};
+use crate::span::Span;
+use crate::text_renderer::TextRenderer;
+use crate::wallpaper::Wallpaper;
+use crate::wallpaper_renderer::{OutputSettings, Suspension, WPRendererImpl, NO_POINTER};
+
+pub struct SceneWPRenderer {
+    _connection: Rc<Connection>,
+    _egl_state: Rc<EGLState>,
+    sources: SceneSources,
+
+    render_context: Option<RenderContext>,
+
+    scene: Option<Scene>,
+    scene_package: Option<ScenePackage>,
+    package_path: Option<PathBuf>,
+    // Joined on the first frame, as loading needs a current GL context
+    source: Option<SceneSourceHandle>,
+
+    pointer: Option<Vector2<f32>>,
+    // Size of the last frame, to place the pointer on the scene
+    output_size: (u32, u32),
+    settings: OutputSettings,
+    span: Option<Span>,
+    // Sounds stop while suspended, drawing stops on its own
+    suspension: Suspension,
+}
+
+struct RenderContext {
+    particle_renderer: ParticleRenderer,
+    text_renderer: TextRenderer,
+    fade_renderer: FadeRenderer,
+    bloom: BloomPass,
+}
+
+impl SceneWPRenderer {
+    pub(crate) fn new(
+        connection: Rc<Connection>,
+        egl_state: Rc<EGLState>,
+        sources: SceneSources,
+    ) -> Self {
+        Self {
+            _connection: connection,
+            _egl_state: egl_state,
+            sources,
+            render_context: None,
+            scene: None,
+            scene_package: None,
+            package_path: None,
+            source: None,
+            pointer: None,
+            output_size: (1, 1),
+            settings: OutputSettings::default(),
+            span: None,
+            suspension: Suspension::default(),
+        }
+    }
+
+    /// Joins the scene shown by the other outputs, or plays it on its own when they want
+    /// different playback
+    fn sync_source(&mut self) {
+        let (Some(package), Some(path)) = (&self.scene_package, &self.package_path) else {
+            return;
+        };
+
+        let source = match self.source.take() {
+            Some(source) => source,
+            None => self
+                .sources
+                .open(path, self.scene.as_ref(), package, &self.settings),
+        };
+
+        let source = if source.sync_playback() {
+            source
+        } else {
+            let detached = self.sources.detach(&source, self.scene.as_ref(), package);
+            detached.sync_playback();
+            detached
+        };
+
+        source.set_suspended(self.suspension != Suspension::Running);
+        source.set_pointer(self.scene_pointer());
+        self.source = Some(source);
+    }
+
+    /// Pointer position on the whole scene, which spans several outputs in a span
+    fn scene_pointer(&self) -> Option<Vector2<f32>> {
+        let (width, height) = self.output_size;
+        let placement = self.placement(width, height);
+
+        self.pointer.map(|pointer| {
+            let (x, y) = placement.content_position((pointer.x, pointer.y));
+            Vector2::new(x, y)
+        })
+    }
+
+    fn scene_size(&self) -> Vector2<f32> {
+        scene_size(self.scene.as_ref())
+    }
+
+    fn bloom_settings(&self) -> Option<BloomSettings> {
+        let general = &self.scene.as_ref()?.general;
+
+        (self.settings.bloom && general.bloom).then(|| BloomSettings {
+            strength: general.bloomstrength as f32,
+            threshold: general.bloomthreshold as f32,
+        })
+    }
+
+    fn placement(&self, width: u32, height: u32) -> Placement {
+        let size = self.scene_size();
+
+        // Scenes have no native size to repeat
+        let scaling = match self.settings.scaling.mode {
+            ScalingMode::Tile => Scaling {
+                mode: ScalingMode::Cover,
+                ..self.settings.scaling
+            },
+            _ => self.settings.scaling,
+        };
+
+        Placement::new(
+            &scaling,
+            (size.x as u32, size.y as u32),
+            (width, height),
+            self.span.as_ref(),
+        )
+    }
+
+    fn projection(&self, placement: &Placement) -> Matrix4<f32> {
+        let size = self.scene_size();
+
+        placement.clip_transform() * cgmath::ortho(0.0, size.x, 0.0, size.y, -1000.0, 1000.0)
+    }
+}
+
+impl WPRendererImpl for SceneWPRenderer {
+    fn clear_color(&self) -> (f32, f32, f32) {
+        self.scene
+            .as_ref()
+            .map(|scene| {
+                let (r, g, b) = scene.general.clearcolor;
+                (r as f32, g as f32, b as f32)
+            })
+            .unwrap_or((0.0, 0.0, 0.0))
+    }
+
+    fn init_render(&mut self) {
+        self.render_context = Some(RenderContext {
+            particle_renderer: ParticleRenderer::new(),
+            text_renderer: TextRenderer::new(),
+            fade_renderer: FadeRenderer::new(),
+            bloom: BloomPass::new(),
+        });
+    }
+
+    fn setup_wallpaper(&mut self, wp: &Wallpaper) {
+        tracing::debug!("Setup scene wp");
+
+        match wp {
+            Wallpaper::Scene {
+                base_dir_path,
+                scene_package,
+                ..
+            } => {
+                self.scene = match scene_package.contents.get("scene.json") {
+                    Some(file) => match serde_json::from_slice::<Scene>(file.bytes()) {
+                        Ok(scene) => Some(scene),
+                        Err(err) => {
+                            tracing::error!("Failed to parse scene.json: {err}");
+                            None
+                        }
+                    },
+                    None => {
+                        tracing::error!("Scene package doesn't contain a scene.json");
+                        None
+                    }
+                };
+
+                self.scene_package = Some(scene_package.clone());
+                self.package_path = Some(base_dir_path.join("scene.pkg"));
+                self.source = None;
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn render(&mut self, width: u32, height: u32) {
+        self.output_size = (width, height);
+        self.sync_source();
+
+        let placement = self.placement(width, height);
+        let projection = self.projection(&placement);
+        let background = self.settings.scaling.background;
+        let clear_color = self.clear_color();
+        let bloom = self.bloom_settings();
+        let pointer = self
+            .pointer
+            .map_or(NO_POINTER, |pointer| (pointer.x, pointer.y));
+
+        let ctx = self.render_context.as_mut().unwrap();
+        let Some(source) = &self.source else {
+            return;
+        };
+        let mut source = source.get();
+        source.update();
+
+        let view_projection = match source.camera() {
+            Some(camera) => projection * camera.view(),
+            None => projection,
+        };
+
+        if bloom.is_some() {
+            ctx.bloom.begin(width, height, clear_color);
+        } else {
+            unsafe {
+                gl::Viewport(0, 0, width as GLsizei, height as GLsizei);
+            }
+        }
+
+        for object in source.objects() {
+            let mvp = match source.camera() {
+                Some(camera) => view_projection * camera.parallax(object.parallax_depth),
+                None => view_projection,
+            };
+
+            match &object.drawable {
+                Drawable::Particles(drawable) => {
+                    ctx.particle_renderer.draw(drawable, &mvp, pointer);
+                }
+                Drawable::Text(drawable) => {
+                    ctx.text_renderer.draw(drawable, &mvp, pointer);
+                }
+            }
+        }
+
+        if let Some(bloom) = &bloom {
+            ctx.bloom.end(bloom);
+        }
+
+        if let Some(camera) = source.camera() {
+            ctx.fade_renderer.draw(clear_color, camera.fade_opacity());
+        }
+
+        placement.clear_outside((width, height), background);
+    }
+
+    fn is_ready(&self) -> bool {
+        self.source.is_some()
+    }
+
+    fn resize(&mut self, width: u32, height: u32) {
+        if self.bloom_settings().is_some()
+            && let Some(ctx) = self.render_context.as_mut()
+        {
+            ctx.bloom.resize(width, height);
+        }
+    }
+
+    fn set_pointer_position(&mut self, position: Option<(f32, f32)>) {
+        self.pointer = position.map(|(x, y)| Vector2::new(x, y));
+
+        if let Some(source) = &self.source {
+            source.set_pointer(self.scene_pointer());
+        }
+    }
+
+    fn set_span(&mut self, span: Option<Span>) {
+        self.span = span;
+    }
+
+    fn set_output_settings(&mut self, settings: OutputSettings) {
+        self.settings = settings;
+
+        if let Some(source) = &self.source {
+            source.set_output_settings(&settings);
+        }
+    }
+
+    fn set_suspension(&mut self, suspension: Suspension) {
+        self.suspension = suspension;
+
+        if let Some(source) = &self.source {
+            source.set_suspended(suspension != Suspension::Running);
+        }
+    }
+
+    fn needs_redraw(&self) -> bool {
+        if self.settings.playback.paused {
+            return false;
+        }
+
+        // Not loaded yet otherwise
+        self.source
+            .as_ref()
+            .is_none_or(|source| source.get().is_animated())
+    }
+
+    fn next_change(&self) -> Option<Duration> {
+        self.source.as_ref()?.get().next_change()
+    }
+
+    fn get_wp_type(&self) -> WallpaperType {
+        WallpaperType::Scene
+    }
+}
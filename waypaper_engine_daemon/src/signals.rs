@@ -0,0 +1,68 @@
+use std::io;
+use std::mem::{self, MaybeUninit};
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd};
+use std::ptr::null_mut;
+
+/// SIGINT and SIGTERM, read from a signalfd polled by the event loop instead of killing the daemon
+pub struct TerminationSignals {
+    fd: OwnedFd,
+}
+
+impl TerminationSignals {
+    /// Blocks the signals in the calling thread and in the threads it spawns afterwards, so it
+    /// has to be called before any thread is started
+    pub fn block() -> io::Result<Self> {
+        unsafe {
+            let mut mask = MaybeUninit::<libc::sigset_t>::uninit();
+            libc::sigemptyset(mask.as_mut_ptr());
+            libc::sigaddset(mask.as_mut_ptr(), libc::SIGINT);
+            libc::sigaddset(mask.as_mut_ptr(), libc::SIGTERM);
+            let mask = mask.assume_init();
+
+            let err = libc::pthread_sigmask(libc::SIG_BLOCK, &mask, null_mut());
+            if err != 0 {
+                return Err(io::Error::from_raw_os_error(err));
+            }
+
+            let fd = libc::signalfd(-1, &mask, libc::SFD_NONBLOCK | libc::SFD_CLOEXEC);
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            Ok(Self {
+                fd: OwnedFd::from_raw_fd(fd),
+            })
+        }
+    }
+
+    /// Number of the next pending signal, if any
+    pub fn read(&self) -> io::Result<Option<u32>> {
+        let mut info: libc::signalfd_siginfo = unsafe { mem::zeroed() };
+        let size = mem::size_of::<libc::signalfd_siginfo>();
+
+        let read = unsafe {
+            libc::read(
+                self.fd.as_raw_fd(),
+                &mut info as *mut libc::signalfd_siginfo as *mut libc::c_void,
+                size,
+            )
+        };
+
+        if read == size as isize {
+            return Ok(Some(info.ssi_signo));
+        }
+
+        let err = io::Error::last_os_error();
+        if err.kind() == io::ErrorKind::WouldBlock {
+            Ok(None)
+        } else {
+            Err(err)
+        }
+    }
+}
+
+impl AsFd for TerminationSignals {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
@@ -0,0 +1,87 @@
+use std::collections::HashMap;
+
+/// Part of a wallpaper spanning several outputs that one of them shows, in logical pixels
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Span {
+    /// Bounding box of the outputs of the span, bezels included
+    pub canvas: (u32, u32),
+    /// Rectangle of the output on the canvas
+    pub position: (u32, u32),
+    pub size: (u32, u32),
+}
+
+/// An output of a span, as laid out by the compositor
+#[derive(Debug, Clone)]
+pub struct SpanOutput {
+    pub name: String,
+    pub position: (i32, i32),
+    pub size: (i32, i32),
+    /// Width of the frame around the screen on the left and right, and on the top and bottom
+    pub bezel: (u32, u32),
+}
+
+/// Lays the outputs out on a canvas, spreading them apart to hide the part of the wallpaper
+/// behind the bezels between two outputs
+pub fn layout(outputs: &[SpanOutput]) -> HashMap<String, Span> {
+    let rects: Vec<_> = outputs
+        .iter()
+        .map(|output| {
+            (
+                output.position.0 + bezel_shift(outputs, output, 0),
+                output.position.1 + bezel_shift(outputs, output, 1),
+                output.size.0.max(1),
+                output.size.1.max(1),
+            )
+        })
+        .collect();
+
+    let left = rects.iter().map(|rect| rect.0).min().unwrap_or(0);
+    let top = rects.iter().map(|rect| rect.1).min().unwrap_or(0);
+    let right = rects.iter().map(|rect| rect.0 + rect.2).max().unwrap_or(0);
+    let bottom = rects.iter().map(|rect| rect.1 + rect.3).max().unwrap_or(0);
+    let canvas = ((right - left) as u32, (bottom - top) as u32);
+
+    outputs
+        .iter()
+        .zip(rects)
+        .map(|(output, (x, y, width, height))| {
+            let span = Span {
+                canvas,
+                position: ((x - left) as u32, (y - top) as u32),
+                size: (width as u32, height as u32),
+            };
+            (output.name.clone(), span)
+        })
+        .collect()
+}
+
+/// Offset of an output along `axis`, 0 for x and 1 for y. Each screen is inset by its bezel in
+/// a frame, and the frames of a row or a column touch each other.
+fn bezel_shift(outputs: &[SpanOutput], output: &SpanOutput, axis: usize) -> i32 {
+    let across = 1 - axis;
+    let frames_before: u32 = outputs
+        .iter()
+        .filter(|other| {
+            other.start(axis) + other.length(axis) <= output.start(axis)
+                && other.start(across) < output.start(across) + output.length(across)
+                && output.start(across) < other.start(across) + other.length(across)
+        })
+        .map(|other| 2 * other.bezel(axis))
+        .sum();
+
+    (output.bezel(axis) + frames_before) as i32
+}
+
+impl SpanOutput {
+    fn start(&self, axis: usize) -> i32 {
+        [self.position.0, self.position.1][axis]
+    }
+
+    fn length(&self, axis: usize) -> i32 {
+        [self.size.0, self.size.1][axis]
+    }
+
+    fn bezel(&self, axis: usize) -> u32 {
+        [self.bezel.0, self.bezel.1][axis]
+    }
+}
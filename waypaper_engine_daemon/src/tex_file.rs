@@ -1,9 +1,11 @@
+use std::ffi::c_void;
 use std::fs;
 use std::io::{Cursor, Read};
 use std::path::Path;
 
 use bitflags::bitflags;
 use cgmath::{InnerSpace, Vector2};
+use gl::types::{GLenum, GLint, GLsizei, GLuint};
 use image::ImageFormat;
 use lz4_flex::decompress;
 use num_enum_derive::TryFromPrimitive;
@@ -23,6 +25,11 @@ pub enum TextureFormat {
     R8 = 9,
 }
 
+// From EXT_texture_compression_s3tc, which the gl crate doesn't expose
+const COMPRESSED_RGBA_S3TC_DXT1_EXT: GLenum = 0x83F1;
+const COMPRESSED_RGBA_S3TC_DXT3_EXT: GLenum = 0x83F2;
+const COMPRESSED_RGBA_S3TC_DXT5_EXT: GLenum = 0x83F3;
+
 bitflags! {
     #[derive(Debug, Clone, Copy, PartialEq, Eq)]
     struct TextureFlags: u32 {
@@ -209,9 +216,13 @@ pub struct TexFile {
 
 impl TexFile {
     pub fn new(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::from_bytes(fs::read(path)?)
+    }
+
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<Self, Box<dyn std::error::Error>> {
         tracing::debug!("Unpacking Tex File !");
 
-        let mut data: Cursor<Vec<u8>> = Cursor::new(fs::read(path)?);
+        let mut data: Cursor<Vec<u8>> = Cursor::new(bytes);
         let data_length = data.get_ref().len();
         tracing::debug!("Data Length : {data_length}");
 
@@ -236,6 +247,162 @@ impl TexFile {
             frames_infos,
         })
     }
+
+    /// Uploads the first image and its mipmaps to a new GL texture, a GL context must be current
+    pub fn upload(&self) -> Result<GLuint, Box<dyn std::error::Error>> {
+        let mipmaps = self.images.first().ok_or("Tex file contains no image")?;
+
+        // Mipmaps are whole encoded image files in this case. Decoded before creating the texture,
+        // so that corrupt files don't leak it.
+        let decoded = match &self.container.freeimage_format {
+            Some(format) => {
+                let format: ImageFormat = format
+                    .clone()
+                    .try_into()
+                    .map_err(|_| format!("Unsupported image format {format:?}"))?;
+                let images = mipmaps
+                    .iter()
+                    .map(|mipmap| {
+                        image::load_from_memory_with_format(&mipmap.bytes, format)
+                            .map(|image| image.to_rgba8())
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                Some(images)
+            }
+            None => None,
+        };
+
+        let mut texture: GLuint = 0;
+        unsafe {
+            gl::GenTextures(1, &mut texture);
+            gl::BindTexture(gl::TEXTURE_2D, texture);
+        }
+
+        for (level, mipmap) in mipmaps.iter().enumerate() {
+            if let Some(decoded) = &decoded {
+                let image = &decoded[level];
+                let level = level as GLint;
+
+                unsafe {
+                    gl::TexImage2D(
+                        gl::TEXTURE_2D,
+                        level,
+                        gl::RGBA8 as GLint,
+                        image.width() as GLsizei,
+                        image.height() as GLsizei,
+                        0,
+                        gl::RGBA,
+                        gl::UNSIGNED_BYTE,
+                        image.as_ptr() as *const c_void,
+                    );
+                }
+                continue;
+            }
+
+            let level = level as GLint;
+            let (width, height) = (mipmap.width as GLsizei, mipmap.height as GLsizei);
+            let data = mipmap.bytes.as_ptr() as *const c_void;
+
+            unsafe {
+                match self.header.format {
+                    TextureFormat::RGBA8888 => gl::TexImage2D(
+                        gl::TEXTURE_2D,
+                        level,
+                        gl::RGBA8 as GLint,
+                        width,
+                        height,
+                        0,
+                        gl::RGBA,
+                        gl::UNSIGNED_BYTE,
+                        data,
+                    ),
+                    TextureFormat::RG88 => gl::TexImage2D(
+                        gl::TEXTURE_2D,
+                        level,
+                        gl::RG8 as GLint,
+                        width,
+                        height,
+                        0,
+                        gl::RG,
+                        gl::UNSIGNED_BYTE,
+                        data,
+                    ),
+                    TextureFormat::R8 => gl::TexImage2D(
+                        gl::TEXTURE_2D,
+                        level,
+                        gl::R8 as GLint,
+                        width,
+                        height,
+                        0,
+                        gl::RED,
+                        gl::UNSIGNED_BYTE,
+                        data,
+                    ),
+                    TextureFormat::DXT1 | TextureFormat::DXT3 | TextureFormat::DXT5 => {
+                        let internal_format = match self.header.format {
+                            TextureFormat::DXT1 => COMPRESSED_RGBA_S3TC_DXT1_EXT,
+                            TextureFormat::DXT3 => COMPRESSED_RGBA_S3TC_DXT3_EXT,
+                            _ => COMPRESSED_RGBA_S3TC_DXT5_EXT,
+                        };
+
+                        gl::CompressedTexImage2D(
+                            gl::TEXTURE_2D,
+                            level,
+                            internal_format,
+                            width,
+                            height,
+                            0,
+                            mipmap.bytes.len() as GLsizei,
+                            data,
+                        )
+                    }
+                }
+            }
+        }
+
+        unsafe {
+            // Single channel textures are used as alpha masks
+            match self.header.format {
+                TextureFormat::R8 => {
+                    let swizzle = [gl::ONE, gl::ONE, gl::ONE, gl::RED].map(|c| c as GLint);
+                    gl::TexParameteriv(gl::TEXTURE_2D, gl::TEXTURE_SWIZZLE_RGBA, swizzle.as_ptr());
+                }
+                TextureFormat::RG88 => {
+                    let swizzle = [gl::RED, gl::RED, gl::RED, gl::GREEN].map(|c| c as GLint);
+                    gl::TexParameteriv(gl::TEXTURE_2D, gl::TEXTURE_SWIZZLE_RGBA, swizzle.as_ptr());
+                }
+                _ => {}
+            }
+
+            let (min_filter, mag_filter) = if self
+                .header
+                .texture_flags
+                .contains(TextureFlags::NoInterpolation)
+            {
+                (gl::NEAREST, gl::NEAREST)
+            } else {
+                (gl::LINEAR_MIPMAP_LINEAR, gl::LINEAR)
+            };
+            let wrap = if self.header.texture_flags.contains(TextureFlags::ClampUVs) {
+                gl::CLAMP_TO_EDGE
+            } else {
+                gl::REPEAT
+            };
+
+            gl::TexParameteri(
+                gl::TEXTURE_2D,
+                gl::TEXTURE_MAX_LEVEL,
+                mipmaps.len() as GLint - 1,
+            );
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, min_filter as GLint);
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, mag_filter as GLint);
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_S, wrap as GLint);
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_T, wrap as GLint);
+            gl::BindTexture(gl::TEXTURE_2D, 0);
+        }
+
+        Ok(texture)
+    }
 }
 
 fn read_header(data: &mut Cursor<Vec<u8>>) -> Header {
@@ -0,0 +1,543 @@
+use std::ffi::{c_void, CString};
+use std::ptr::null;
+use std::time::Duration;
+
+use ab_glyph::{Font, PxScale, ScaleFont};
+use cgmath::{Matrix4, Vector2, Vector3};
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, Local, Timelike};
+use gl::types::{GLfloat, GLsizei, GLsizeiptr, GLuint};
+
+use crate::gl_utils::{compile_shader, link_program, set_vec2};
+use crate::glyph_atlas::{load_font, GlyphAtlas};
+use crate::scene::{HorizontalAlign, TextValue, VerticalAlign};
+use crate::scene_package::ScenePackage;
+
+const VERTEX_SHADER_SRC: &str = r#"
+    #version 330 core
+
+    layout (location = 0) in vec2 aPos;
+    layout (location = 1) in vec2 aTexCoord;
+
+    uniform mat4 mvp;
+
+    out vec2 tex_coord;
+
+    void main()
+    {
+        gl_Position = mvp * vec4(aPos, 0.0, 1.0);
+        tex_coord = aTexCoord;
+    }
+"#;
+
+const FRAGMENT_SHADER_SRC: &str = r#"
+    #version 330 core
+
+    out vec4 out_color;
+
+    in vec2 tex_coord;
+
+    uniform sampler2D atlas;
+    uniform vec4 color;
+    // Normalized position of the pointer on the output, negative while it's away
+    uniform vec2 pointer;
+
+    void main()
+    {
+        out_color = vec4(color.rgb, color.a * texture(atlas, tex_coord).r);
+    }
+"#;
+
+// Wallpaper Engine sizes text in points, assume a 96 DPI display
+const POINTS_TO_PIXELS: f32 = 96.0 / 72.0;
+
+const DEFAULT_TIME_FORMAT: &str = "%H:%M";
+const DEFAULT_DATE_FORMAT: &str = "%A %e %B";
+
+// Characters a clock will need, rasterized upfront so ticking never stalls on glyph uploads
+const PREWARMED_CHARACTERS: &str = "0123456789:/.- ";
+
+/// Text content of a layer with its clock and date placeholders
+///
+/// `{time}` and `{date}` are replaced by the current local time and date, a chrono format string
+/// can be given after a colon, for example `{time:%H:%M:%S}` or `{date:%d/%m/%Y}`. `{{` and `}}`
+/// stand for literal braces.
+#[derive(Debug, Clone)]
+pub struct TextTemplate {
+    segments: Vec<Segment>,
+    is_dynamic: bool,
+}
+
+#[derive(Debug, Clone)]
+enum Segment {
+    Text(String),
+    /// Current local time or date, in a format parsed once when the template is built
+    Clock(Vec<Item<'static>>),
+}
+
+impl TextTemplate {
+    pub fn new(text: &TextValue) -> Self {
+        let mut template = text.value().to_string();
+
+        // Scripts aren't executed, but clock scripts are common enough to be recognized
+        if !template.contains('{') {
+            if let Some(script) = text.script() {
+                if script.contains("getHours") || script.contains("toLocaleTimeString") {
+                    template = "{time}".to_string();
+                } else if script.contains("getDate") || script.contains("toLocaleDateString") {
+                    template = "{date}".to_string();
+                }
+            }
+        }
+
+        let segments = parse_segments(&template);
+        let is_dynamic = segments
+            .iter()
+            .any(|segment| matches!(segment, Segment::Clock(_)));
+
+        Self {
+            segments,
+            is_dynamic,
+        }
+    }
+
+    pub fn is_dynamic(&self) -> bool {
+        self.is_dynamic
+    }
+
+    pub fn resolve(&self, now: &DateTime<Local>) -> String {
+        self.segments
+            .iter()
+            .map(|segment| match segment {
+                Segment::Text(text) => text.clone(),
+                Segment::Clock(items) => now.format_with_items(items.iter()).to_string(),
+            })
+            .collect()
+    }
+}
+
+fn parse_segments(template: &str) -> Vec<Segment> {
+    let mut segments = Vec::new();
+    let mut text = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find(['{', '}']) {
+        text.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        if rest.starts_with("{{") || rest.starts_with("}}") {
+            text.push_str(&rest[..1]);
+            rest = &rest[2..];
+            continue;
+        }
+
+        if rest.starts_with('}') {
+            text.push('}');
+            rest = &rest[1..];
+            continue;
+        }
+
+        let Some(end) = rest.find('}') else {
+            // Kept as is from the unmatched brace on
+            break;
+        };
+
+        let placeholder = &rest[1..end];
+        let (kind, format) = placeholder
+            .split_once(':')
+            .map_or((placeholder, None), |(kind, format)| (kind, Some(format)));
+
+        let default_format = match kind {
+            "time" => Some(DEFAULT_TIME_FORMAT),
+            "date" => Some(DEFAULT_DATE_FORMAT),
+            _ => None,
+        };
+
+        if let Some(default_format) = default_format {
+            if !text.is_empty() {
+                segments.push(Segment::Text(std::mem::take(&mut text)));
+            }
+            segments.push(Segment::Clock(clock_format(format, default_format)));
+        } else {
+            text.push_str(&rest[..=end]);
+        }
+
+        rest = &rest[end + 1..];
+    }
+
+    text.push_str(rest);
+    if !text.is_empty() {
+        segments.push(Segment::Text(text));
+    }
+
+    segments
+}
+
+/// Formatting with an invalid chrono format panics, the default format is used instead
+fn clock_format(format: Option<&str>, default_format: &str) -> Vec<Item<'static>> {
+    let parse = |format: &str| -> Vec<_> {
+        StrftimeItems::new(format)
+            .map(|item| item.to_owned())
+            .collect()
+    };
+
+    if let Some(format) = format {
+        let items = parse(format);
+        if !items.contains(&Item::Error) {
+            return items;
+        }
+
+        tracing::warn!("Invalid clock format {format:?} in text layer, using {default_format:?}");
+    }
+
+    parse(default_format)
+}
+
+pub struct TextLayout {
+    pub origin: Vector3<f32>,
+    pub scale: Vector3<f32>,
+    pub size: Option<Vector2<f32>>,
+    pub padding: f32,
+    pub horizontal_align: HorizontalAlign,
+    pub vertical_align: VerticalAlign,
+    pub color: [f32; 4],
+}
+
+/// Program shared by every text layer of a scene
+pub struct TextRenderer {
+    program: GLuint,
+}
+
+pub struct TextDrawable {
+    template: TextTemplate,
+    layout: TextLayout,
+    atlas: GlyphAtlas,
+
+    text: String,
+    last_second: Option<u32>,
+
+    vao: GLuint,
+    vbo: GLuint,
+    vertex_count: usize,
+}
+
+impl TextDrawable {
+    /// Loads the font and prepares the glyph atlas, a GL context must be current
+    pub fn new(
+        text: &TextValue,
+        font: &str,
+        point_size: f32,
+        layout: TextLayout,
+        package: &ScenePackage,
+    ) -> Option<Self> {
+        let Some(font) = load_font(font, package) else {
+            tracing::warn!("No usable font found for text layer, skipping it");
+            return None;
+        };
+
+        let mut atlas = GlyphAtlas::new(font, PxScale::from(point_size * POINTS_TO_PIXELS));
+        let template = TextTemplate::new(text);
+
+        if template.is_dynamic() {
+            for c in PREWARMED_CHARACTERS.chars() {
+                let id = atlas.font.glyph_id(c);
+                atlas.glyph(id);
+            }
+        }
+
+        let (mut vao, mut vbo): (GLuint, GLuint) = (0, 0);
+        unsafe {
+            gl::GenVertexArrays(1, &mut vao);
+            gl::BindVertexArray(vao);
+            gl::GenBuffers(1, &mut vbo);
+            gl::BindBuffer(gl::ARRAY_BUFFER, vbo);
+
+            let stride = (4 * std::mem::size_of::<GLfloat>()) as GLsizei;
+            gl::VertexAttribPointer(0, 2, gl::FLOAT, gl::FALSE, stride, null());
+            gl::EnableVertexAttribArray(0);
+            gl::VertexAttribPointer(
+                1,
+                2,
+                gl::FLOAT,
+                gl::FALSE,
+                stride,
+                (2 * std::mem::size_of::<GLfloat>()) as *const c_void,
+            );
+            gl::EnableVertexAttribArray(1);
+
+            gl::BindBuffer(gl::ARRAY_BUFFER, 0);
+            gl::BindVertexArray(0);
+        }
+
+        Some(Self {
+            template,
+            layout,
+            atlas,
+            text: String::new(),
+            last_second: None,
+            vao,
+            vbo,
+            vertex_count: 0,
+        })
+    }
+
+    /// Refreshes the displayed text, clocks only rebuild their geometry when the second changes
+    pub fn update(&mut self) {
+        let now = Local::now();
+
+        if self.last_second.is_some()
+            && (!self.template.is_dynamic() || self.last_second == Some(now.second()))
+        {
+            return;
+        }
+        self.last_second = Some(now.second());
+
+        let text = self.template.resolve(&now);
+        if text != self.text {
+            self.text = text;
+            self.rebuild_geometry();
+        }
+    }
+
+    /// Time until the text changes, for clocks
+    pub fn next_change(&self) -> Option<Duration> {
+        self.template.is_dynamic().then(|| {
+            let millis = Local::now().timestamp_subsec_millis().min(999);
+            Duration::from_millis(1000 - millis as u64)
+        })
+    }
+
+    fn rebuild_geometry(&mut self) {
+        let scaled = self.atlas.font.as_scaled(self.atlas.scale);
+        let line_height = scaled.height() + scaled.line_gap();
+        let ascent = scaled.ascent();
+
+        let lines: Vec<&str> = self.text.lines().collect();
+        let line_widths: Vec<f32> = lines
+            .iter()
+            .map(|line| {
+                let mut width = 0.0;
+                let mut previous = None;
+                for c in line.chars() {
+                    let id = scaled.glyph_id(c);
+                    if let Some(previous) = previous {
+                        width += scaled.kern(previous, id);
+                    }
+                    width += scaled.h_advance(id);
+                    previous = Some(id);
+                }
+                width
+            })
+            .collect();
+
+        let layout = &self.layout;
+        let text_width = line_widths.iter().copied().fold(0.0, f32::max);
+        let text_height = line_height * lines.len() as f32;
+        let box_size = layout.size.unwrap_or(Vector2::new(
+            text_width + 2.0 * layout.padding,
+            text_height + 2.0 * layout.padding,
+        ));
+
+        let top = match layout.vertical_align {
+            VerticalAlign::Top => layout.padding,
+            VerticalAlign::Center => (box_size.y - text_height) / 2.0,
+            VerticalAlign::Bottom => box_size.y - layout.padding - text_height,
+        };
+
+        // Glyphs are laid out in a y-down box, then mapped to the y-up scene around the origin
+        let to_scene = |x: f32, y: f32| {
+            [
+                layout.origin.x + (x - box_size.x / 2.0) * layout.scale.x,
+                layout.origin.y + (box_size.y / 2.0 - y) * layout.scale.y,
+            ]
+        };
+
+        let mut vertices: Vec<GLfloat> = vec![];
+
+        for (i, (line, line_width)) in lines.iter().zip(&line_widths).enumerate() {
+            let mut pen_x = match layout.horizontal_align {
+                HorizontalAlign::Left => layout.padding,
+                HorizontalAlign::Center => (box_size.x - line_width) / 2.0,
+                HorizontalAlign::Right => box_size.x - layout.padding - line_width,
+            };
+            let baseline = top + ascent + line_height * i as f32;
+            let mut previous = None;
+
+            for c in line.chars() {
+                let id = self.atlas.font.glyph_id(c);
+                let scaled = self.atlas.font.as_scaled(self.atlas.scale);
+                if let Some(previous) = previous {
+                    pen_x += scaled.kern(previous, id);
+                }
+                let advance = scaled.h_advance(id);
+                previous = Some(id);
+
+                if let Some(glyph) = self.atlas.glyph(id) {
+                    let x0 = pen_x + glyph.offset.0;
+                    let y0 = baseline + glyph.offset.1;
+                    let (x1, y1) = (x0 + glyph.size.0, y0 + glyph.size.1);
+                    let (u0, v0, u1, v1) = glyph.uv;
+
+                    let [ax, ay] = to_scene(x0, y0);
+                    let [bx, by] = to_scene(x1, y1);
+
+                    #[rustfmt::skip]
+                    vertices.extend_from_slice(&[
+                        ax, ay, u0, v0,
+                        bx, ay, u1, v0,
+                        ax, by, u0, v1,
+                        bx, ay, u1, v0,
+                        bx, by, u1, v1,
+                        ax, by, u0, v1,
+                    ]);
+                }
+
+                pen_x += advance;
+            }
+        }
+
+        self.vertex_count = vertices.len() / 4;
+
+        unsafe {
+            gl::BindBuffer(gl::ARRAY_BUFFER, self.vbo);
+            gl::BufferData(
+                gl::ARRAY_BUFFER,
+                (vertices.len() * std::mem::size_of::<GLfloat>()) as GLsizeiptr,
+                vertices.as_ptr() as *const c_void,
+                gl::DYNAMIC_DRAW,
+            );
+            gl::BindBuffer(gl::ARRAY_BUFFER, 0);
+        }
+    }
+}
+
+impl Drop for TextDrawable {
+    fn drop(&mut self) {
+        unsafe {
+            gl::DeleteBuffers(1, &self.vbo);
+            gl::DeleteVertexArrays(1, &self.vao);
+        }
+    }
+}
+
+impl TextRenderer {
+    pub fn new() -> Self {
+        let vertex_shader = compile_shader(VERTEX_SHADER_SRC, gl::VERTEX_SHADER);
+        let fragment_shader = compile_shader(FRAGMENT_SHADER_SRC, gl::FRAGMENT_SHADER);
+        let program = link_program(vertex_shader, fragment_shader);
+
+        unsafe {
+            let pointer = CString::new("out_color").unwrap();
+            gl::BindFragDataLocation(program, 0, pointer.as_ptr());
+
+            gl::DeleteShader(vertex_shader);
+            gl::DeleteShader(fragment_shader);
+        }
+
+        Self { program }
+    }
+
+    pub fn draw(&self, drawable: &TextDrawable, mvp: &Matrix4<f32>, pointer: (f32, f32)) {
+        if drawable.vertex_count == 0 {
+            return;
+        }
+
+        unsafe {
+            gl::BindVertexArray(drawable.vao);
+            gl::UseProgram(self.program);
+
+            let mvp_name = CString::new("mvp").unwrap();
+            let mvp: &[f32; 16] = mvp.as_ref();
+            gl::UniformMatrix4fv(
+                gl::GetUniformLocation(self.program, mvp_name.as_ptr()),
+                1,
+                gl::FALSE,
+                mvp.as_ptr(),
+            );
+
+            let color_name = CString::new("color").unwrap();
+            gl::Uniform4fv(
+                gl::GetUniformLocation(self.program, color_name.as_ptr()),
+                1,
+                drawable.layout.color.as_ptr(),
+            );
+            set_vec2(self.program, "pointer", pointer.0, pointer.1);
+
+            gl::ActiveTexture(gl::TEXTURE0);
+            gl::BindTexture(gl::TEXTURE_2D, drawable.atlas.texture());
+
+            gl::Enable(gl::BLEND);
+            gl::BlendFunc(gl::SRC_ALPHA, gl::ONE_MINUS_SRC_ALPHA);
+
+            gl::DrawArrays(gl::TRIANGLES, 0, drawable.vertex_count as GLsizei);
+
+            gl::Disable(gl::BLEND);
+            gl::BindTexture(gl::TEXTURE_2D, 0);
+            gl::UseProgram(0);
+            gl::BindVertexArray(0);
+        }
+    }
+}
+
+impl Drop for TextRenderer {
+    fn drop(&mut self) {
+        unsafe {
+            gl::DeleteProgram(self.program);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn resolve(template: &str) -> String {
+        let template = TextTemplate {
+            segments: parse_segments(template),
+            is_dynamic: false,
+        };
+        let now = Local.with_ymd_and_hms(2024, 3, 5, 7, 8, 9).unwrap();
+
+        template.resolve(&now)
+    }
+
+    #[test]
+    fn clock_placeholders() {
+        assert_eq!(resolve("{time}"), "07:08");
+        assert_eq!(resolve("{date}"), "Tuesday  5 March");
+        assert_eq!(resolve("It is {time:%H:%M:%S}"), "It is 07:08:09");
+        assert_eq!(resolve("{date:%d/%m/%Y} {time}"), "05/03/2024 07:08");
+    }
+
+    #[test]
+    fn escaped_braces() {
+        assert_eq!(resolve("{{time}}"), "{time}");
+        assert_eq!(resolve("{{{time}}}"), "{07:08}");
+        assert_eq!(resolve("a }} b {{"), "a } b {");
+    }
+
+    #[test]
+    fn unknown_specifiers_fall_back_to_the_default_format() {
+        assert_eq!(resolve("{time:%Q}"), "07:08");
+        assert_eq!(resolve("{date:%}"), "Tuesday  5 March");
+        // Unknown placeholders are plain text
+        assert_eq!(resolve("{weather} {time}"), "{weather} 07:08");
+    }
+
+    #[test]
+    fn unterminated_brace_is_kept_once() {
+        assert_eq!(resolve("{time"), "{time");
+        assert_eq!(resolve("{time} {date:%Y"), "07:08 {date:%Y");
+        assert_eq!(resolve("a } b"), "a } b");
+    }
+
+    #[test]
+    fn static_text_has_no_clock() {
+        assert!(parse_segments("Hello")
+            .iter()
+            .all(|segment| matches!(segment, Segment::Text(_))));
+        assert!(parse_segments("").is_empty());
+    }
+}
@@ -0,0 +1,274 @@
+use std::ptr::null;
+use std::time::{Duration, Instant};
+
+use gl::types::{GLint, GLsizei, GLuint};
+
+use waypaper_engine_shared::ipc::{Easing, Transition, TransitionEffect};
+
+use crate::gl_utils::{create_program, set_float, set_int, set_vec2, FULLSCREEN_VERTEX_SHADER_SRC};
+use crate::wallpaper_renderer::WPRendererImpl;
+
+const BLEND_SHADER_SRC: &str = r#"
+    #version 330 core
+
+    out vec4 out_color;
+
+    in vec2 uv;
+
+    uniform sampler2D previous;
+    uniform sampler2D next;
+    uniform int effect;
+    uniform float progress;
+    uniform vec2 size;
+
+    // Width of the soft edge of wipes and circles, in uv units
+    const float EDGE = 0.05;
+    // Size of the pixels halfway through a pixelate transition
+    const float MAX_BLOCK = 64.0;
+
+    void main()
+    {
+        vec2 sample_uv = uv;
+        float amount;
+
+        if (effect == 0) {
+            amount = progress;
+        } else if (effect == 1) {
+            amount = clamp((progress * (1.0 + EDGE) - uv.x) / EDGE, 0.0, 1.0);
+        } else if (effect == 2) {
+            vec2 aspect = vec2(size.x / size.y, 1.0);
+            // Reaches the corners at the end
+            float radius = length(aspect * 0.5) + EDGE;
+            float distance = length((uv - 0.5) * aspect);
+            amount = clamp((progress * radius - distance) / EDGE, 0.0, 1.0);
+        } else {
+            // Blocks grow until halfway, then shrink back to single pixels
+            float block = max(1.0, MAX_BLOCK * (1.0 - abs(2.0 * progress - 1.0)));
+            sample_uv = (floor(uv * size / block) + 0.5) * block / size;
+            amount = smoothstep(0.4, 0.6, progress);
+        }
+
+        vec3 color = mix(texture(previous, sample_uv).rgb, texture(next, sample_uv).rgb, amount);
+        out_color = vec4(color, 1.0);
+    }
+"#;
+
+/// Keeps the previous wallpaper of an output drawing offscreen, and blends it into the next one
+///
+/// Both wallpapers are drawn in their own texture on every frame, so videos keep playing while
+/// they fade out. Needs a current GL context, like the renderers it holds.
+pub struct TransitionRenderer {
+    previous: Box<dyn WPRendererImpl>,
+    transition: Transition,
+    // Set once the next wallpaper is ready to be shown. Videos open their decoder and scenes load
+    // while drawing their first frame, which blocks the output until they are done.
+    started: Option<Instant>,
+
+    program: GLuint,
+    vao: GLuint,
+    framebuffer: GLuint,
+    // Previous and next wallpaper
+    textures: [GLuint; 2],
+    size: (u32, u32),
+}
+
+impl TransitionRenderer {
+    pub fn new(previous: Box<dyn WPRendererImpl>, transition: Transition) -> Self {
+        let mut vao: GLuint = 0;
+        let mut framebuffer: GLuint = 0;
+
+        unsafe {
+            gl::GenVertexArrays(1, &mut vao);
+            gl::GenFramebuffers(1, &mut framebuffer);
+        }
+
+        tracing::debug!("Starting {transition:?}");
+
+        Self {
+            previous,
+            transition,
+            started: None,
+            program: create_program(FULLSCREEN_VERTEX_SHADER_SRC, BLEND_SHADER_SRC),
+            vao,
+            framebuffer,
+            textures: [0; 2],
+            size: (0, 0),
+        }
+    }
+
+    pub fn previous_mut(&mut self) -> &mut dyn WPRendererImpl {
+        self.previous.as_mut()
+    }
+
+    /// Draws both wallpapers and blends them onto the bound framebuffer
+    pub fn render(&mut self, next: &mut dyn WPRendererImpl, width: u32, height: u32) {
+        if self.size != (width, height) {
+            self.resize(width, height);
+        }
+
+        unsafe {
+            let mut target: GLint = 0;
+            gl::GetIntegerv(gl::FRAMEBUFFER_BINDING, &mut target);
+
+            gl::BindFramebuffer(gl::FRAMEBUFFER, self.framebuffer);
+            capture(self.textures[0], self.size, self.previous.as_mut());
+            capture(self.textures[1], self.size, next);
+            gl::BindFramebuffer(gl::FRAMEBUFFER, target as GLuint);
+
+            // Only the previous wallpaper shows until then
+            if self.started.is_none() && next.is_ready() {
+                self.started = Some(Instant::now());
+            }
+
+            // Scenes may leave blending on
+            gl::Disable(gl::BLEND);
+            gl::Viewport(0, 0, width as GLsizei, height as GLsizei);
+            gl::BindVertexArray(self.vao);
+            gl::UseProgram(self.program);
+            set_int(self.program, "previous", 0);
+            set_int(self.program, "next", 1);
+            set_int(self.program, "effect", effect_index(self.transition.effect));
+            set_float(self.program, "progress", self.progress());
+            set_vec2(self.program, "size", width as f32, height as f32);
+
+            gl::ActiveTexture(gl::TEXTURE0);
+            gl::BindTexture(gl::TEXTURE_2D, self.textures[0]);
+            gl::ActiveTexture(gl::TEXTURE1);
+            gl::BindTexture(gl::TEXTURE_2D, self.textures[1]);
+
+            gl::DrawArrays(gl::TRIANGLES, 0, 3);
+
+            gl::BindTexture(gl::TEXTURE_2D, 0);
+            gl::ActiveTexture(gl::TEXTURE0);
+            gl::BindTexture(gl::TEXTURE_2D, 0);
+            gl::UseProgram(0);
+            gl::BindVertexArray(0);
+        }
+    }
+
+    /// Eased progress from 0 to 1, 0 until the next wallpaper drew its first frame
+    fn progress(&self) -> f32 {
+        let duration = Duration::from_millis(self.transition.duration_ms.into());
+        let elapsed = self
+            .started
+            .map_or(Duration::ZERO, |started| started.elapsed());
+        let linear = if duration.is_zero() {
+            1.0
+        } else {
+            (elapsed.as_secs_f32() / duration.as_secs_f32()).min(1.0)
+        };
+
+        ease(self.transition.easing, linear)
+    }
+
+    /// Whether the last frame drawn showed the next wallpaper only
+    pub fn is_finished(&self) -> bool {
+        self.started.is_some() && self.progress() >= 1.0
+    }
+
+    fn resize(&mut self, width: u32, height: u32) {
+        tracing::debug!("Resizing transition framebuffers to {width}x{height}");
+        self.delete_textures();
+
+        self.textures = [create_texture(width, height), create_texture(width, height)];
+        self.size = (width, height);
+    }
+
+    fn delete_textures(&mut self) {
+        for texture in &mut self.textures {
+            if *texture != 0 {
+                unsafe { gl::DeleteTextures(1, texture) };
+                *texture = 0;
+            }
+        }
+    }
+}
+
+impl Drop for TransitionRenderer {
+    fn drop(&mut self) {
+        self.delete_textures();
+
+        unsafe {
+            gl::DeleteFramebuffers(1, &self.framebuffer);
+            gl::DeleteVertexArrays(1, &self.vao);
+            gl::DeleteProgram(self.program);
+        }
+    }
+}
+
+/// Draws a wallpaper into `texture`, through the bound framebuffer
+unsafe fn capture(texture: GLuint, size: (u32, u32), renderer: &mut dyn WPRendererImpl) {
+    gl::FramebufferTexture2D(
+        gl::FRAMEBUFFER,
+        gl::COLOR_ATTACHMENT0,
+        gl::TEXTURE_2D,
+        texture,
+        0,
+    );
+    gl::Viewport(0, 0, size.0 as GLsizei, size.1 as GLsizei);
+
+    let clear_color = renderer.clear_color();
+    gl::ClearColor(clear_color.0, clear_color.1, clear_color.2, 1.0);
+    gl::Clear(gl::COLOR_BUFFER_BIT);
+
+    renderer.render(size.0, size.1);
+}
+
+fn ease(easing: Easing, t: f32) -> f32 {
+    match easing {
+        Easing::Linear => t,
+        Easing::EaseIn => t.powi(3),
+        Easing::EaseOut => 1.0 - (1.0 - t).powi(3),
+        Easing::EaseInOut => {
+            if t < 0.5 {
+                4.0 * t.powi(3)
+            } else {
+                1.0 - (2.0 - 2.0 * t).powi(3) / 2.0
+            }
+        }
+    }
+}
+
+fn effect_index(effect: TransitionEffect) -> GLint {
+    match effect {
+        TransitionEffect::Crossfade => 0,
+        TransitionEffect::Wipe => 1,
+        TransitionEffect::GrowCircle => 2,
+        TransitionEffect::Pixelate => 3,
+    }
+}
+
+fn create_texture(width: u32, height: u32) -> GLuint {
+    let mut texture: GLuint = 0;
+
+    unsafe {
+        gl::GenTextures(1, &mut texture);
+        gl::BindTexture(gl::TEXTURE_2D, texture);
+        gl::TexImage2D(
+            gl::TEXTURE_2D,
+            0,
+            gl::RGBA8 as GLint,
+            width as GLsizei,
+            height as GLsizei,
+            0,
+            gl::RGBA,
+            gl::UNSIGNED_BYTE,
+            null(),
+        );
+        gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::LINEAR as GLint);
+        gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::LINEAR as GLint);
+        gl::TexParameteri(
+            gl::TEXTURE_2D,
+            gl::TEXTURE_WRAP_S,
+            gl::CLAMP_TO_EDGE as GLint,
+        );
+        gl::TexParameteri(
+            gl::TEXTURE_2D,
+            gl::TEXTURE_WRAP_T,
+            gl::CLAMP_TO_EDGE as GLint,
+        );
+        gl::BindTexture(gl::TEXTURE_2D, 0);
+    }
+
+    texture
+}
@@ -0,0 +1,632 @@
+use std::os::fd::RawFd;
+use std::os::raw::{c_int, c_void};
+use std::path::Path;
+use std::ptr::null_mut;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use video_rs::ffmpeg;
+use video_rs::ffmpeg::color::{Range, Space};
+use video_rs::ffmpeg::error::EAGAIN;
+use video_rs::ffmpeg::ffi::{
+    AVBufferRef, AVCodecContext, AVDRMFrameDescriptor, AVHWDeviceType, AVPixelFormat,
+};
+use video_rs::ffmpeg::format::Pixel;
+use video_rs::ffmpeg::software::scaling;
+use video_rs::ffmpeg::{frame, media, Packet};
+
+use waypaper_engine_shared::ipc::DecoderBackend;
+
+use crate::frame_pool::{FramePool, PooledBuffer};
+
+/// Decodes the best video stream of a file into planar YUV frames, which are converted to RGB on
+/// the GPU
+///
+/// video-rs always converts frames to RGB on the CPU, so this drives ffmpeg directly.
+pub struct VideoDecoder {
+    input: ffmpeg::format::context::Input,
+    stream_index: usize,
+    time_base: f64,
+    frame_rate: f32,
+
+    decoder: ffmpeg::decoder::Video,
+    backend: DecoderBackend,
+    hardware: Option<HardwareDevice>,
+    // ffmpeg silently decodes in software when the hardware doesn't support the video
+    hardware_frames: bool,
+    // Converts formats that can't be uploaded as is, created when the first one shows up
+    scaler: Option<(Pixel, u32, u32, scaling::Context)>,
+    // Cleared when exporting fails, hardware frames are then downloaded
+    dma_buf_export: Arc<AtomicBool>,
+    pool: FramePool,
+    // Consecutive packets that couldn't be read or decoded
+    corrupt_packets: usize,
+
+    last_pts: f64,
+}
+
+const MAX_CORRUPT_PACKETS: usize = 100;
+
+// The ffmpeg contexts are only ever used by the thread owning the decoder
+unsafe impl Send for VideoDecoder {}
+
+/// How the planes of a frame are laid out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlaneLayout {
+    /// Full resolution luma plane followed by a half resolution plane of interleaved chroma
+    Nv12,
+    /// Full resolution luma plane followed by two half resolution chroma planes
+    Yuv420p,
+}
+
+pub struct Plane {
+    pub data: PooledBuffer,
+    /// Bytes between the start of two rows
+    pub stride: usize,
+    pub width: u32,
+    pub height: u32,
+}
+
+pub struct YuvFrame {
+    /// Presentation timestamp in seconds
+    pub pts: f64,
+    pub layout: PlaneLayout,
+    pub width: u32,
+    pub height: u32,
+    pub data: FrameData,
+    pub colorimetry: Colorimetry,
+}
+
+impl YuvFrame {
+    /// Memory taken by the planes, in system or video memory
+    pub fn byte_size(&self) -> usize {
+        match &self.data {
+            FrameData::Memory(planes) => planes.iter().map(|plane| plane.data.len()).sum(),
+            FrameData::DmaBuf(frame) => frame
+                .layers
+                .iter()
+                .map(|layer| layer.pitch as usize * layer.height as usize)
+                .sum(),
+        }
+    }
+}
+
+pub enum FrameData {
+    /// Planes copied to system memory
+    Memory(Vec<Plane>),
+    /// Planes left in video memory, one layer per plane
+    DmaBuf(DmaBufFrame),
+}
+
+/// Hardware frame exported as DMA-BUFs, which stay valid as long as it is alive
+#[derive(Clone)]
+pub struct DmaBufFrame {
+    pub layers: Vec<DmaBufLayer>,
+    // Mapped frame holding a reference to the decoder surface
+    _frame: Arc<frame::Video>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct DmaBufLayer {
+    /// DRM fourcc of the layer, R8 or GR88 for 8 bit videos and R16 or GR1616 for 10 bit ones
+    pub fourcc: u32,
+    pub width: u32,
+    pub height: u32,
+    pub fd: RawFd,
+    pub offset: u32,
+    pub pitch: u32,
+    pub modifier: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorMatrix {
+    Bt601,
+    Bt709,
+    Bt2020,
+}
+
+/// How YUV values of a frame map to RGB
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Colorimetry {
+    pub matrix: ColorMatrix,
+    /// Full range frames use 0-255 for every component, limited range ones 16-235 for luma and
+    /// 16-240 for chroma
+    pub full_range: bool,
+}
+
+impl Colorimetry {
+    fn from_frame(frame: &frame::Video) -> Self {
+        let matrix = match frame.color_space() {
+            Space::BT709 => ColorMatrix::Bt709,
+            Space::BT470BG | Space::SMPTE170M => ColorMatrix::Bt601,
+            Space::BT2020NCL | Space::BT2020CL => ColorMatrix::Bt2020,
+            // Untagged videos are usually HD content in BT.709 or SD content in BT.601
+            _ if frame.height() >= 720 => ColorMatrix::Bt709,
+            _ => ColorMatrix::Bt601,
+        };
+
+        // The JPEG formats are deprecated aliases for full range
+        let full_range = frame.color_range() == Range::JPEG || frame.format() == Pixel::YUVJ420P;
+
+        Self { matrix, full_range }
+    }
+
+    /// Column major matrix and offset converting sampled YUV values to RGB:
+    /// `rgb = matrix * (yuv - offset)`
+    pub fn yuv_to_rgb(&self) -> ([f32; 9], [f32; 3]) {
+        let (kr, kb) = match self.matrix {
+            ColorMatrix::Bt601 => (0.299, 0.114),
+            ColorMatrix::Bt709 => (0.2126, 0.0722),
+            ColorMatrix::Bt2020 => (0.2627, 0.0593),
+        };
+        let kg = 1.0 - kr - kb;
+
+        let (luma_scale, chroma_scale, luma_offset) = if self.full_range {
+            (1.0, 1.0, 0.0)
+        } else {
+            (255.0 / 219.0, 255.0 / 224.0, 16.0 / 255.0)
+        };
+
+        let cr_to_r = 2.0 * (1.0 - kr) * chroma_scale;
+        let cb_to_g = -2.0 * kb * (1.0 - kb) / kg * chroma_scale;
+        let cr_to_g = -2.0 * kr * (1.0 - kr) / kg * chroma_scale;
+        let cb_to_b = 2.0 * (1.0 - kb) * chroma_scale;
+
+        #[rustfmt::skip]
+        let matrix = [
+            luma_scale, luma_scale, luma_scale,
+            0.0,        cb_to_g,    cb_to_b,
+            cr_to_r,    cr_to_g,    0.0,
+        ];
+
+        (matrix, [luma_offset, 128.0 / 255.0, 128.0 / 255.0])
+    }
+}
+
+impl VideoDecoder {
+    /// Opens `path` with the first of `backends` able to decode it, along with its first frame
+    pub fn open_with_fallback(
+        path: &Path,
+        backends: &[DecoderBackend],
+        dma_buf_frames: &dyn Fn(u32, u32) -> usize,
+    ) -> Option<(Self, YuvFrame)> {
+        for &backend in backends {
+            let mut decoder = match Self::open(path, backend, dma_buf_frames) {
+                Ok(decoder) => decoder,
+                Err(err) => {
+                    tracing::warn!("{backend:?} decoder unavailable: {err}");
+                    continue;
+                }
+            };
+
+            match decoder.decode() {
+                Ok(Some(frame)) if decoder.backend() == backend => {
+                    tracing::info!("Decoding {} with {backend:?}", path.to_string_lossy());
+                    return Some((decoder, frame));
+                }
+                Ok(Some(_)) => tracing::warn!("{backend:?} decoder doesn't support this video"),
+                Ok(None) => {
+                    tracing::warn!("{} doesn't contain any frame", path.to_string_lossy());
+                    return None;
+                }
+                Err(err) => tracing::warn!("{backend:?} decoder failed: {err}"),
+            }
+        }
+
+        tracing::error!("No decoder can play {}", path.to_string_lossy());
+        None
+    }
+
+    /// `dma_buf_frames` gives how many exported frames of the video size the caller may hold at
+    /// once, hardware frames are downloaded to system memory when it is 0. Only VA-API frames can
+    /// be exported.
+    pub fn open(
+        path: &Path,
+        backend: DecoderBackend,
+        dma_buf_frames: &dyn Fn(u32, u32) -> usize,
+    ) -> Result<Self, ffmpeg::Error> {
+        let input = ffmpeg::format::input(path)?;
+        let stream = input
+            .streams()
+            .best(media::Type::Video)
+            .ok_or(ffmpeg::Error::StreamNotFound)?;
+
+        let stream_index = stream.index();
+        let time_base = f64::from(stream.time_base());
+        let frame_rate = match f64::from(stream.avg_frame_rate()) {
+            rate if rate > 0.0 => rate,
+            _ => f64::from(stream.rate()),
+        } as f32;
+
+        let mut context = ffmpeg::codec::context::Context::from_parameters(stream.parameters())?;
+
+        let dma_buf_frames = match backend {
+            DecoderBackend::Vaapi => {
+                let parameters = unsafe { &*context.as_mut_ptr() };
+                dma_buf_frames(parameters.width as u32, parameters.height as u32)
+            }
+            _ => 0,
+        };
+
+        let device_type = match backend {
+            DecoderBackend::Vaapi => Some(AVHWDeviceType::AV_HWDEVICE_TYPE_VAAPI),
+            DecoderBackend::Vulkan => Some(AVHWDeviceType::AV_HWDEVICE_TYPE_VULKAN),
+            DecoderBackend::Vdpau => Some(AVHWDeviceType::AV_HWDEVICE_TYPE_VDPAU),
+            DecoderBackend::Software => None,
+        };
+
+        let hardware = match device_type {
+            Some(device_type) => Some(HardwareDevice::attach(
+                &mut context,
+                device_type,
+                dma_buf_frames,
+            )?),
+            None => None,
+        };
+
+        let decoder = context.decoder().video()?;
+
+        Ok(Self {
+            input,
+            stream_index,
+            time_base,
+            frame_rate,
+            decoder,
+            backend,
+            hardware,
+            hardware_frames: false,
+            scaler: None,
+            dma_buf_export: Arc::new(AtomicBool::new(dma_buf_frames > 0)),
+            pool: FramePool::default(),
+            corrupt_packets: 0,
+            last_pts: 0.0,
+        })
+    }
+
+    pub fn frame_rate(&self) -> f32 {
+        self.frame_rate
+    }
+
+    /// Backend which decoded the last frame
+    pub fn backend(&self) -> DecoderBackend {
+        if self.hardware_frames {
+            self.backend
+        } else {
+            DecoderBackend::Software
+        }
+    }
+
+    /// Lets the consumer of the frames stop the DMA-BUF export, when importing them fails
+    pub fn dma_buf_export(&self) -> Arc<AtomicBool> {
+        self.dma_buf_export.clone()
+    }
+
+    /// Decodes the next frame, `None` once the end of the video is reached
+    pub fn decode(&mut self) -> Result<Option<YuvFrame>, ffmpeg::Error> {
+        let mut frame = frame::Video::empty();
+
+        loop {
+            match self.decoder.receive_frame(&mut frame) {
+                Ok(()) => return self.convert(frame).map(Some),
+                Err(ffmpeg::Error::Eof) => return Ok(None),
+                Err(ffmpeg::Error::Other { errno: EAGAIN }) => {}
+                Err(err) => return Err(err),
+            }
+
+            // The decoder needs more data
+            let mut packet = Packet::empty();
+            let result = match packet.read(&mut self.input) {
+                Ok(()) if packet.stream() == self.stream_index => self.decoder.send_packet(&packet),
+                Ok(()) => Ok(()),
+                // Flushes the frames the decoder still holds
+                Err(ffmpeg::Error::Eof) => self.decoder.send_eof(),
+                Err(err) => Err(err),
+            };
+
+            match result {
+                Ok(()) => self.corrupt_packets = 0,
+                // Damaged parts of the file are skipped, until nothing readable is left
+                Err(ffmpeg::Error::InvalidData) if self.corrupt_packets < MAX_CORRUPT_PACKETS => {
+                    tracing::debug!("Skipping corrupt video packet");
+                    self.corrupt_packets += 1;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    pub fn seek_to_start(&mut self) -> Result<(), ffmpeg::Error> {
+        self.input.seek(i64::MIN, ..)?;
+        self.decoder.flush();
+        Ok(())
+    }
+
+    /// Moves to the last keyframe before `position`, in seconds. The frames decoded next can start
+    /// before `position`.
+    pub fn seek(&mut self, position: f64) -> Result<(), ffmpeg::Error> {
+        let timestamp = (position.max(0.0) * ffmpeg::ffi::AV_TIME_BASE as f64) as i64;
+        self.input.seek(timestamp, ..timestamp)?;
+        self.decoder.flush();
+        Ok(())
+    }
+
+    fn convert(&mut self, frame: frame::Video) -> Result<YuvFrame, ffmpeg::Error> {
+        let pts = match frame.timestamp() {
+            Some(timestamp) => timestamp as f64 * self.time_base,
+            None if self.frame_rate > 0.0 => self.last_pts + 1.0 / self.frame_rate as f64,
+            None => self.last_pts,
+        };
+        self.last_pts = pts;
+
+        // Downloads don't keep the color tags
+        let colorimetry = Colorimetry::from_frame(&frame);
+
+        let hardware = self
+            .hardware
+            .as_ref()
+            .filter(|hardware| frame.format() == hardware.pixel_format);
+        self.hardware_frames = hardware.is_some();
+
+        if let Some(hardware) = hardware
+            && self.dma_buf_export.load(Ordering::Relaxed)
+        {
+            match hardware.export(&frame) {
+                Ok(exported) => {
+                    return Ok(YuvFrame {
+                        pts,
+                        layout: PlaneLayout::Nv12,
+                        width: frame.width(),
+                        height: frame.height(),
+                        data: FrameData::DmaBuf(exported),
+                        colorimetry,
+                    });
+                }
+                Err(err) => {
+                    tracing::warn!("Can't export video frames, downloading them instead: {err}");
+                    self.dma_buf_export.store(false, Ordering::Relaxed);
+                }
+            }
+        }
+
+        let frame = match hardware {
+            Some(hardware) => hardware.download(&frame)?,
+            None => frame,
+        };
+
+        let (layout, frame) = match frame.format() {
+            Pixel::NV12 => (PlaneLayout::Nv12, frame),
+            Pixel::YUV420P | Pixel::YUVJ420P => (PlaneLayout::Yuv420p, frame),
+            _ => (PlaneLayout::Yuv420p, self.to_yuv420p(&frame)?),
+        };
+
+        let planes = (0..frame.planes())
+            .map(|index| Plane {
+                data: self.pool.copy(frame.data(index)),
+                stride: frame.stride(index),
+                width: frame.plane_width(index),
+                height: frame.plane_height(index),
+            })
+            .collect();
+
+        Ok(YuvFrame {
+            pts,
+            layout,
+            width: frame.width(),
+            height: frame.height(),
+            data: FrameData::Memory(planes),
+            colorimetry,
+        })
+    }
+
+    /// Converts high bit depth and other chroma subsamplings, keeping the YUV values as they are
+    fn to_yuv420p(&mut self, frame: &frame::Video) -> Result<frame::Video, ffmpeg::Error> {
+        let source = (frame.format(), frame.width(), frame.height());
+
+        if self
+            .scaler
+            .as_ref()
+            .map_or(true, |(format, width, height, _)| {
+                (*format, *width, *height) != source
+            })
+        {
+            tracing::debug!("Converting {:?} video frames to YUV420P", source.0);
+            let scaler = scaling::Context::get(
+                source.0,
+                source.1,
+                source.2,
+                Pixel::YUV420P,
+                source.1,
+                source.2,
+                scaling::Flags::BILINEAR,
+            )?;
+            self.scaler = Some((source.0, source.1, source.2, scaler));
+        }
+
+        let mut converted = frame::Video::empty();
+        self.scaler.as_mut().unwrap().3.run(frame, &mut converted)?;
+
+        Ok(converted)
+    }
+}
+
+/// Hardware decoding device attached to a codec context
+struct HardwareDevice {
+    context: *mut AVBufferRef,
+    pixel_format: Pixel,
+}
+
+impl HardwareDevice {
+    fn attach(
+        codec_context: &mut ffmpeg::codec::context::Context,
+        device_type: AVHWDeviceType,
+        exported_frames: usize,
+    ) -> Result<Self, ffmpeg::Error> {
+        let codec =
+            ffmpeg::decoder::find(codec_context.id()).ok_or(ffmpeg::Error::DecoderNotFound)?;
+
+        let pixel_format = unsafe { find_hardware_pixel_format(&codec, device_type) }
+            .ok_or(ffmpeg::Error::DecoderNotFound)?;
+
+        let mut context: *mut AVBufferRef = null_mut();
+        unsafe {
+            match ffmpeg::ffi::av_hwdevice_ctx_create(
+                &mut context,
+                device_type,
+                std::ptr::null(),
+                null_mut(),
+                0,
+            ) {
+                0 => {}
+                err => return Err(ffmpeg::Error::from(err)),
+            }
+
+            let codec_context = codec_context.as_mut_ptr();
+            (*codec_context).hw_device_ctx = ffmpeg::ffi::av_buffer_ref(context);
+            (*codec_context).opaque =
+                AVPixelFormat::from(pixel_format) as i32 as isize as *mut c_void;
+            (*codec_context).get_format = Some(get_hardware_format);
+            // Exported frames keep their surface until they are dropped
+            (*codec_context).extra_hw_frames = exported_frames as c_int;
+        }
+
+        Ok(Self {
+            context,
+            pixel_format,
+        })
+    }
+
+    /// Exports the planes of a frame without copying them, only works for VA-API surfaces
+    fn export(&self, frame: &frame::Video) -> Result<DmaBufFrame, ffmpeg::Error> {
+        let mut mapped = frame::Video::empty();
+        mapped.set_format(Pixel::DRM_PRIME);
+
+        let descriptor = unsafe {
+            match ffmpeg::ffi::av_hwframe_map(
+                mapped.as_mut_ptr(),
+                frame.as_ptr(),
+                ffmpeg::ffi::AV_HWFRAME_MAP_READ as c_int,
+            ) {
+                0 => *((*mapped.as_ptr()).data[0] as *const AVDRMFrameDescriptor),
+                err => return Err(ffmpeg::Error::from(err)),
+            }
+        };
+
+        // The renderer samples the luma and chroma planes from two separate layers
+        let layers = &descriptor.layers[..descriptor.nb_layers as usize];
+        let supported = layers.len() == 2
+            && layers.iter().all(|layer| layer.nb_planes == 1)
+            && matches!(
+                (layers[0].format, layers[1].format),
+                (DRM_FORMAT_R8, DRM_FORMAT_GR88) | (DRM_FORMAT_R16, DRM_FORMAT_GR1616)
+            );
+        if !supported {
+            return Err(ffmpeg::Error::InvalidData);
+        }
+
+        let layers = layers
+            .iter()
+            .enumerate()
+            .map(|(index, layer)| {
+                let plane = layer.planes[0];
+                let object = descriptor.objects[plane.object_index as usize];
+                // Chroma planes are subsampled in both directions
+                let subsampling = if index == 0 { 1 } else { 2 };
+
+                DmaBufLayer {
+                    fourcc: layer.format,
+                    width: frame.width().div_ceil(subsampling),
+                    height: frame.height().div_ceil(subsampling),
+                    fd: object.fd,
+                    offset: plane.offset as u32,
+                    pitch: plane.pitch as u32,
+                    modifier: object.format_modifier,
+                }
+            })
+            .collect();
+
+        Ok(DmaBufFrame {
+            layers,
+            _frame: Arc::new(mapped),
+        })
+    }
+
+    /// Copies a frame from video memory, usually as NV12 or P010
+    fn download(&self, frame: &frame::Video) -> Result<frame::Video, ffmpeg::Error> {
+        let mut downloaded = frame::Video::empty();
+
+        unsafe {
+            match ffmpeg::ffi::av_hwframe_transfer_data(downloaded.as_mut_ptr(), frame.as_ptr(), 0)
+            {
+                0 => Ok(downloaded),
+                err => Err(ffmpeg::Error::from(err)),
+            }
+        }
+    }
+}
+
+impl Drop for HardwareDevice {
+    fn drop(&mut self) {
+        unsafe {
+            ffmpeg::ffi::av_buffer_unref(&mut self.context);
+        }
+    }
+}
+
+const fn fourcc(code: &[u8; 4]) -> u32 {
+    u32::from_le_bytes(*code)
+}
+
+const DRM_FORMAT_R8: u32 = fourcc(b"R8  ");
+const DRM_FORMAT_GR88: u32 = fourcc(b"GR88");
+const DRM_FORMAT_R16: u32 = fourcc(b"R16 ");
+const DRM_FORMAT_GR1616: u32 = fourcc(b"GR32");
+
+unsafe fn find_hardware_pixel_format(
+    codec: &ffmpeg::codec::codec::Codec,
+    device_type: AVHWDeviceType,
+) -> Option<Pixel> {
+    let mut index = 0;
+
+    loop {
+        let config = ffmpeg::ffi::avcodec_get_hw_config(codec.as_ptr(), index);
+        if config.is_null() {
+            return None;
+        }
+
+        let supports_device_context =
+            (*config).methods & ffmpeg::ffi::AV_CODEC_HW_CONFIG_METHOD_HW_DEVICE_CTX as i32 != 0;
+        if supports_device_context && (*config).device_type == device_type {
+            return Some((*config).pix_fmt.into());
+        }
+
+        index += 1;
+    }
+}
+
+/// Picks the hardware format stored in the codec context opaque among the ones offered by ffmpeg
+unsafe extern "C" fn get_hardware_format(
+    context: *mut AVCodecContext,
+    formats: *const AVPixelFormat,
+) -> AVPixelFormat {
+    let wanted = (*context).opaque as isize as i32;
+
+    let mut software = None;
+    let mut format = formats;
+    while *format != AVPixelFormat::AV_PIX_FMT_NONE {
+        if *format as i32 == wanted {
+            return *format;
+        }
+
+        let descriptor = ffmpeg::ffi::av_pix_fmt_desc_get(*format);
+        let is_hardware = !descriptor.is_null()
+            && (*descriptor).flags & ffmpeg::ffi::AV_PIX_FMT_FLAG_HWACCEL as u64 != 0;
+        if !is_hardware && software.is_none() {
+            software = Some(*format);
+        }
+
+        format = format.add(1);
+    }
+
+    tracing::debug!("Hardware decoding not available for this video, decoding in software");
+    software.unwrap_or(AVPixelFormat::AV_PIX_FMT_NONE)
+}
@@ -1,26 +1,24 @@
-use std::cell::OnceCell;
-use std::collections::VecDeque;
 use std::ffi::{c_void, CString};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::ptr::null;
 use std::rc::Rc;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Mutex};
-use std::thread;
-use std::thread::JoinHandle;
-use std::time::Instant;
 
 use gl::types::{GLfloat, GLint, GLsizei, GLsizeiptr, GLuint};
 use smithay_client_toolkit::reexports::client::Connection;
-use video_rs::hwaccel::HardwareAccelerationDeviceType;
-use video_rs::{Decoder, DecoderBuilder, Error, Frame, Time};
 
+use waypaper_engine_shared::ipc::{DecoderBackend, Scaling, ScalingMode, VideoQueueStatus};
 use waypaper_engine_shared::project::WallpaperType;
 
 use crate::egl::EGLState;
-use crate::gl_utils::{compile_shader, link_program};
+use crate::gl_utils::{
+    compile_shader, link_program, set_int, set_mat3, set_vec2, set_vec3, set_vec4,
+};
+use crate::scaling::Placement;
+use crate::span::Span;
+use crate::video_decoder::PlaneLayout;
+use crate::video_source::{VideoSourceHandle, VideoSources};
 use crate::wallpaper::Wallpaper;
-use crate::wallpaper_renderer::WPRendererImpl;
+use crate::wallpaper_renderer::{OutputSettings, Suspension, WPRendererImpl, NO_POINTER};
 
 #[rustfmt::skip]
 static VERTEX_DATA: [GLfloat; 32] = [
@@ -46,11 +44,16 @@ const VERTEX_SHADER_SRC: &str = r#"
     out vec3 color;
     out vec2 tex_coord;
 
+    // Scale and offset, see `Placement`
+    uniform vec4 quad_transform;
+    uniform vec4 uv_transform;
+
     void main()
     {
-        gl_Position = vec4(aPos.x, -aPos.y, aPos.z, 1.0);
+        vec2 position = aPos.xy * quad_transform.xy + quad_transform.zw;
+        gl_Position = vec4(position.x, -position.y, aPos.z, 1.0);
         color = aColor;
-        tex_coord = aTexCoord;
+        tex_coord = aTexCoord * uv_transform.xy + uv_transform.zw;
     }
 "#;
 
@@ -62,22 +65,60 @@ const FRAGMENT_SHADER_SRC: &str = r#"
     in vec3 color;
     in vec2 tex_coord;
 
-    uniform sampler2D tex;
+    uniform sampler2D tex_y;
+    uniform sampler2D tex_u;
+    uniform sampler2D tex_v;
+    // NV12 frames have both chroma components interleaved in tex_u
+    uniform bool interleaved_chroma;
+
+    uniform mat3 yuv_to_rgb;
+    uniform vec3 yuv_offset;
+
+    // Still images are sampled from tex_y as RGB
+    uniform bool rgb_input;
+
+    // Normalized position of the pointer on the output, negative while it's away
+    uniform vec2 pointer;
 
     void main()
     {
-        out_color = texture(tex, tex_coord);
+        if (rgb_input) {
+            out_color = vec4(texture(tex_y, tex_coord).rgb, 1.0);
+            return;
+        }
+
+        float y = texture(tex_y, tex_coord).r;
+        vec2 chroma = interleaved_chroma
+            ? texture(tex_u, tex_coord).rg
+            : vec2(texture(tex_u, tex_coord).r, texture(tex_v, tex_coord).r);
+
+        vec3 rgb = yuv_to_rgb * (vec3(y, chroma) - yuv_offset);
+        out_color = vec4(clamp(rgb, 0.0, 1.0), 1.0);
     }
 "#;
 
 pub struct VideoRSWPRenderer {
     _connection: Rc<Connection>,
     _egl_state: Rc<EGLState>,
+    sources: VideoSources,
 
     render_context: Option<RenderContext>,
 
     video_path: Option<PathBuf>,
+    preview_path: Option<PathBuf>,
     started_playback: bool,
+    settings: OutputSettings,
+    span: Option<Span>,
+    suspension: Suspension,
+    pointer: (f32, f32),
+    // The preview is shown because of the suspension, rather than a decoding failure
+    static_preview: bool,
+
+    video: Option<VideoSourceHandle>,
+    // Shown once the video can't be decoded anymore
+    preview: Option<PreviewImage>,
+    // Why playback stopped, until it is reported
+    error: Option<String>,
 }
 
 struct RenderContext {
@@ -85,152 +126,46 @@ struct RenderContext {
     program: GLuint,
     vao: GLuint,
     ebo: GLuint,
-    data: Option<RenderData>,
 }
 
-struct RenderData {
+/// Still image shown instead of a video
+struct PreviewImage {
     texture: GLuint,
-
-    framerate: f32,
-    size: (u32, u32),
-
-    last_frame_time: Instant,
-    last_frame: Frame,
-
-    decoding_thread_handle: OnceCell<JoinHandle<()>>,
-    frames: Arc<Mutex<VecDeque<Frame>>>,
-
-    shutdown: Arc<AtomicBool>,
+    width: u32,
+    height: u32,
 }
 
 impl VideoRSWPRenderer {
-    pub(crate) fn new(connection: Rc<Connection>, egl_state: Rc<EGLState>) -> Self {
+    pub(crate) fn new(
+        connection: Rc<Connection>,
+        egl_state: Rc<EGLState>,
+        sources: VideoSources,
+    ) -> Self {
         Self {
             _connection: connection,
             _egl_state: egl_state,
+            sources,
             render_context: None,
             video_path: None,
+            preview_path: None,
             started_playback: false,
-        }
-    }
-
-    fn start_playback(&mut self) {
-        let source = video_rs::Location::File(self.video_path.as_ref().unwrap().clone());
-        //let decoder = Decoder::new(source).expect("Failed to create decoder");
-
-        let decoder = DecoderBuilder::new(source)
-            .with_hardware_acceleration(HardwareAccelerationDeviceType::VaApi)
-            .build()
-            .expect("Failed to create decoder");
-
-        let size = decoder.size_out();
-        let framerate = decoder.frame_rate();
-
-        let shutdown_arc = Arc::new(AtomicBool::new(false));
-
-        let (thread_handle, frames_vec) = start_decoding_thread(decoder, shutdown_arc.clone());
-
-        let ctx = self.render_context.as_mut().unwrap();
-
-        unsafe {
-            let mut texture: GLuint = 0;
-            gl::GenTextures(1, &mut texture);
-            gl::BindTexture(gl::TEXTURE_2D, texture);
-
-            gl::TexImage2D(
-                gl::TEXTURE_2D,
-                0,
-                gl::RGB as GLint,
-                size.0 as GLsizei,
-                size.1 as GLsizei,
-                0,
-                gl::RGB,
-                gl::UNSIGNED_BYTE,
-                null(),
-            );
-
-            ctx.data.replace(RenderData {
-                texture,
-                framerate,
-                size,
-                last_frame_time: Instant::now(),
-                last_frame: frames_vec.lock().unwrap().front().unwrap().clone(),
-                decoding_thread_handle: OnceCell::from(thread_handle),
-                frames: frames_vec.clone(),
-                shutdown: shutdown_arc,
-            });
+            settings: OutputSettings::default(),
+            span: None,
+            suspension: Suspension::default(),
+            pointer: NO_POINTER,
+            static_preview: false,
+            video: None,
+            preview: None,
+            error: None,
         }
     }
 }
 
-fn start_decoding_thread(
-    mut decoder: Decoder,
-    shutdown: Arc<AtomicBool>,
-) -> (JoinHandle<()>, Arc<Mutex<VecDeque<Frame>>>) {
-    let mut frames_vec = VecDeque::with_capacity(20);
-    frames_vec.push_back(decoder.decode().unwrap().1);
-    let frames_arc = Arc::new(Mutex::new(frames_vec)); // init with first frame
-
-    let weak = Arc::downgrade(&frames_arc);
-
-    let (mut decoder_split, mut reader, stream_index) = decoder.into_parts();
-
-    let handle = thread::spawn(move || {
-        tracing::debug!("Spawn decoding thread");
-        'outer: while !shutdown.load(Ordering::Relaxed) {
-            let packet_result = reader.read(stream_index);
-            
-            let packet = match packet_result {
-                Ok(packet) => packet,
-                Err(err) => match err {
-                    Error::ReadExhausted => {
-                        tracing::debug!("Video ended, seeking to start");
-                        reader.seek_to_start().unwrap();
-                        continue;
-                    },
-                    _ => panic!("Error while decoding video frame"),
-                },
-            };
-            
-            let (time, frame) = match decoder_split.decode(packet).expect("Failed to decode video frame") {
-                Some(v) => v,
-                None => continue,
-            };
-
-            while !shutdown.load(Ordering::Relaxed) {
-                if let Some(strong) = weak.upgrade() {
-                    if strong.lock().unwrap().len() >= 20 {
-                        tracing::debug!("Frames in queue >= 20, paused decoding");
-                        thread::park();
-                        tracing::debug!("Resumed decoding")
-                    } else {
-                        break;
-                    };
-                } else {
-                    break 'outer;
-                }
-            }
-
-            if let Some(strong) = weak.upgrade() {
-                let mut frames_vec = strong.lock().unwrap();
-                frames_vec.push_back(frame);
-            } else {
-                break 'outer;
-            }
-        }
-        
-        tracing::debug!("Got out of the decoding loop, draining decoder");
-        while let Ok(option) = decoder_split.drain_raw() && option.is_some() {
-            tracing::debug!("Draining frame");
-        }
-
-        tracing::debug!("Exited decoding Thread!");
-    });
-
-    (handle, frames_arc)
-}
-
 impl WPRendererImpl for VideoRSWPRenderer {
+    fn clear_color(&self) -> (f32, f32, f32) {
+        self.settings.scaling.background
+    }
+
     fn init_render(&mut self) {
         unsafe {
             let mut vao: GLuint = 0;
@@ -305,7 +240,6 @@ impl WPRendererImpl for VideoRSWPRenderer {
                 vao,
                 vbo,
                 ebo,
-                data: None,
             })
         }
     }
@@ -319,82 +253,279 @@ impl WPRendererImpl for VideoRSWPRenderer {
                 base_dir_path,
             } => {
                 self.video_path = Some(base_dir_path.join(project.file.as_ref().unwrap()));
+                self.preview_path = Some(base_dir_path.join(&project.preview));
                 self.started_playback = false;
+                self.preview = None;
+                self.static_preview = false;
             }
             _ => unreachable!(),
         }
     }
 
+    fn is_ready(&self) -> bool {
+        // Nothing is drawn when no decoder can play the video
+        self.video.is_some() || self.preview.is_some()
+    }
+
     fn render(&mut self, width: u32, height: u32) {
-        if !self.started_playback {
-            self.start_playback();
-            self.started_playback = true;
+        // Done here as the decoder textures and the preview are freed with a current context
+        let static_frame = self.suspension == Suspension::StaticFrame;
+        if static_frame && !self.static_preview {
+            self.video = None;
+            self.preview = self.load_preview();
+            self.static_preview = true;
+        } else if !static_frame && self.static_preview {
+            self.preview = None;
+            self.started_playback = false;
+            self.static_preview = false;
         }
 
-        let ctx = self.render_context.as_mut().unwrap();
-        let data = ctx.data.as_mut().unwrap();
+        if !self.started_playback && !static_frame {
+            // Nothing is drawn when no decoder can play the video
+            self.video = self
+                .sources
+                .open(self.video_path.as_ref().unwrap(), &self.settings);
+            self.started_playback = true;
+            self.set_suspension(self.suspension);
+        }
 
-        let frame = if Instant::now()
-            .duration_since(data.last_frame_time)
-            .as_secs_f32()
-            < 1.0 / data.framerate
+        if let Some(video) = &self.video
+            && !video.sync_playback()
         {
-            tracing::debug!("Not enough time since last frame, rendering last frame again");
-            &data.last_frame
-        } else {
-            data.last_frame_time = Instant::now();
-
-            let mut frames = data.frames.lock().unwrap();
-            match frames.pop_front() {
-                Some(frame) => {
-                    data.decoding_thread_handle.get().unwrap().thread().unpark();
-                    data.last_frame = frame;
-                    &data.last_frame
-                }
-                None => {
-                    tracing::debug!("No frame to render in queue! Rendering last frame");
-                    &data.last_frame
-                }
+            // Another output shows the same video but wants it paused, faster, ...
+            let detached = self.sources.detach(video);
+            self.video = detached;
+            self.set_suspension(self.suspension);
+        }
+
+        let failure = self
+            .video
+            .as_ref()
+            .and_then(|video| video.get().failure().map(str::to_string));
+        if let Some(error) = failure {
+            self.video = None;
+            self.preview = self.load_preview();
+            self.error = Some(error);
+        }
+
+        let scaling = self.settings.scaling;
+        let ctx = self.render_context.as_ref().unwrap();
+        let output_size = (width, height);
+        let span = self.span.as_ref();
+        let pointer = self.pointer;
+
+        if let Some(video) = &self.video {
+            let mut source = video.get();
+            source.update();
+            let frame = source.frame();
+            let (matrix, offset) = frame.colorimetry.yuv_to_rgb();
+
+            unsafe {
+                let content_size = (frame.width, frame.height);
+                ctx.draw(&scaling, content_size, output_size, span, pointer, |wrap| {
+                    source.bind_textures(wrap);
+
+                    set_int(ctx.program, "rgb_input", 0);
+                    set_int(ctx.program, "tex_y", 0);
+                    set_int(ctx.program, "tex_u", 1);
+                    set_int(ctx.program, "tex_v", 2);
+                    set_int(
+                        ctx.program,
+                        "interleaved_chroma",
+                        (frame.layout == PlaneLayout::Nv12) as GLint,
+                    );
+                    set_mat3(ctx.program, "yuv_to_rgb", &matrix);
+                    set_vec3(ctx.program, "yuv_offset", offset);
+                });
+            }
+        } else if let Some(preview) = &self.preview {
+            unsafe {
+                ctx.draw(
+                    &scaling,
+                    (preview.width, preview.height),
+                    output_size,
+                    span,
+                    pointer,
+                    |wrap| {
+                        preview.bind(wrap);
+
+                        set_int(ctx.program, "rgb_input", 1);
+                        set_int(ctx.program, "tex_y", 0);
+                    },
+                );
+            }
+        }
+    }
+
+    fn set_output_settings(&mut self, settings: OutputSettings) {
+        self.settings = settings;
+
+        if let Some(video) = &self.video {
+            video.set_output_settings(&settings);
+        }
+    }
+
+    fn set_suspension(&mut self, suspension: Suspension) {
+        self.suspension = suspension;
+
+        if let Some(video) = &self.video {
+            video.set_suspended(suspension != Suspension::Running);
+        }
+    }
+
+    fn set_span(&mut self, span: Option<Span>) {
+        self.span = span;
+    }
+
+    fn set_pointer_position(&mut self, position: Option<(f32, f32)>) {
+        self.pointer = position.unwrap_or(NO_POINTER);
+    }
+
+    fn seek(&mut self, position: f64) {
+        if let Some(video) = &self.video {
+            video.get().seek(position);
+        }
+    }
+
+    fn needs_redraw(&self) -> bool {
+        match &self.video {
+            Some(video) => !video.get().is_idle(),
+            // The preview is drawn as soon as it is loaded
+            None => self.preview.is_none(),
+        }
+    }
+
+    fn video_decoder(&self) -> Option<DecoderBackend> {
+        self.video.as_ref().map(|video| video.backend())
+    }
+
+    fn take_error(&mut self) -> Option<String> {
+        self.error.take()
+    }
+
+    fn video_queue(&self) -> Option<VideoQueueStatus> {
+        self.video.as_ref().map(|video| video.get().queue_status())
+    }
+
+    fn get_wp_type(&self) -> WallpaperType {
+        WallpaperType::Video
+    }
+}
+
+impl VideoRSWPRenderer {
+    /// Needs a current GL context
+    fn load_preview(&self) -> Option<PreviewImage> {
+        let path = self.preview_path.as_ref()?;
+
+        match PreviewImage::load(path) {
+            Ok(preview) => {
+                tracing::info!("Showing preview {}", path.to_string_lossy());
+                Some(preview)
+            }
+            Err(err) => {
+                tracing::warn!("Failed to load preview {}: {err}", path.to_string_lossy());
+                None
             }
+        }
+    }
+}
+
+impl RenderContext {
+    /// Draws `content_size` placed on the output, or on the canvas of `span`. `bind` binds the
+    /// textures and sets the uniforms of the content.
+    unsafe fn draw(
+        &self,
+        scaling: &Scaling,
+        content_size: (u32, u32),
+        output_size: (u32, u32),
+        span: Option<&Span>,
+        pointer: (f32, f32),
+        bind: impl FnOnce(GLuint),
+    ) {
+        // Reset viewport each frame to avoid problems when rendering on two screens with different resolutions
+        gl::Viewport(0, 0, output_size.0 as GLsizei, output_size.1 as GLsizei);
+
+        gl::BindVertexArray(self.vao);
+        gl::UseProgram(self.program);
+        gl::BindBuffer(gl::ELEMENT_ARRAY_BUFFER, self.ebo);
+
+        let placement = Placement::new(scaling, content_size, output_size, span);
+        set_vec4(
+            self.program,
+            "quad_transform",
+            placement.quad_scale,
+            placement.quad_offset,
+        );
+        set_vec4(
+            self.program,
+            "uv_transform",
+            placement.uv_scale,
+            placement.uv_offset,
+        );
+        set_vec2(self.program, "pointer", pointer.0, pointer.1);
+
+        let wrap = if scaling.mode == ScalingMode::Tile {
+            gl::REPEAT
+        } else {
+            gl::CLAMP_TO_EDGE
         };
+        bind(wrap);
 
-        unsafe {
-            // Reset viewport each frame to avoid problems when rendering on two screens with different resolutions
-            gl::Viewport(0, 0, width as GLsizei, height as GLsizei);
+        gl::DrawElements(gl::TRIANGLES, 6, gl::UNSIGNED_INT, null::<c_void>());
 
-            gl::BindVertexArray(ctx.vao);
-            gl::UseProgram(ctx.program);
-            gl::BindBuffer(gl::ELEMENT_ARRAY_BUFFER, ctx.ebo);
+        gl::ActiveTexture(gl::TEXTURE0);
+        gl::BindBuffer(gl::ELEMENT_ARRAY_BUFFER, 0);
+        gl::UseProgram(0);
+        gl::BindVertexArray(0);
+    }
+}
 
-            gl::BindTexture(gl::TEXTURE_2D, data.texture);
-            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_S, gl::REPEAT as GLint);
-            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_T, gl::REPEAT as GLint);
-            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::NEAREST as GLint);
-            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::LINEAR as GLint);
+impl PreviewImage {
+    /// Needs a current GL context. Only the first frame of animated previews is shown.
+    fn load(path: &Path) -> Result<Self, image::ImageError> {
+        let image = image::open(path)?.to_rgba8();
 
-            let (tex_width, tex_height) = data.size;
-            gl::TexSubImage2D(
+        let mut texture = 0;
+        unsafe {
+            gl::GenTextures(1, &mut texture);
+            gl::BindTexture(gl::TEXTURE_2D, texture);
+            gl::TexImage2D(
                 gl::TEXTURE_2D,
                 0,
+                gl::RGBA8 as GLint,
+                image.width() as GLsizei,
+                image.height() as GLsizei,
                 0,
-                0,
-                tex_width as GLsizei,
-                tex_height as GLsizei,
-                gl::RGB,
+                gl::RGBA,
                 gl::UNSIGNED_BYTE,
-                frame.as_ptr() as *const c_void,
+                image.as_ptr() as *const c_void,
             );
+            gl::BindTexture(gl::TEXTURE_2D, 0);
+        }
 
-            gl::DrawElements(gl::TRIANGLES, 6, gl::UNSIGNED_INT, null::<c_void>());
+        Ok(Self {
+            texture,
+            width: image.width(),
+            height: image.height(),
+        })
+    }
 
-            gl::BindBuffer(gl::ELEMENT_ARRAY_BUFFER, 0);
-            gl::UseProgram(0);
-            gl::BindVertexArray(0);
-        }
+    /// Binds the image to the first texture unit
+    unsafe fn bind(&self, wrap: GLuint) {
+        gl::ActiveTexture(gl::TEXTURE0);
+        gl::BindTexture(gl::TEXTURE_2D, self.texture);
+        gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_S, wrap as GLint);
+        gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_T, wrap as GLint);
+        gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::LINEAR as GLint);
+        gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::LINEAR as GLint);
     }
+}
 
-    fn get_wp_type(&self) -> WallpaperType {
-        WallpaperType::Video
+impl Drop for PreviewImage {
+    fn drop(&mut self) {
+        unsafe {
+            gl::DeleteTextures(1, &self.texture);
+        }
     }
 }
 
@@ -409,16 +540,3 @@ impl Drop for RenderContext {
         }
     }
 }
-
-impl Drop for RenderData {
-    fn drop(&mut self) {
-        self.shutdown.store(true, Ordering::Relaxed);
-        self.frames.lock().unwrap().clear();
-        self.decoding_thread_handle.get().unwrap().thread().unpark();
-
-        let _ = self.decoding_thread_handle.take().unwrap().join();
-        unsafe {
-            gl::DeleteTextures(1, &self.texture);
-        }
-    }
-}
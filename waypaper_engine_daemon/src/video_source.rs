@@ -0,0 +1,1007 @@
+use std::cell::{Cell, RefCell, RefMut};
+use std::collections::{HashMap, HashSet};
+use std::ffi::c_void;
+use std::path::{Path, PathBuf};
+use std::rc::{Rc, Weak};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use gl::types::{GLint, GLsizei, GLuint};
+use khronos_egl as egl;
+use smithay_client_toolkit::reexports::calloop::ping::Ping;
+use video_rs::ffmpeg;
+
+use waypaper_engine_shared::ipc::{DecoderBackend, VideoQueueStatus};
+
+use crate::audio::{AudioMixer, TrackHandle};
+use crate::audio_source::{self, PlaybackMode, SoundInput};
+use crate::config::VideoConfig;
+use crate::dma_buf::{DmaBufImage, DmaBufImporter};
+use crate::egl::EGLState;
+use crate::frame_queue::FrameQueue;
+use crate::video_decoder::{DmaBufFrame, FrameData, Plane, PlaneLayout, VideoDecoder, YuvFrame};
+use crate::wallpaper_renderer::{OutputSettings, Playback};
+
+// Going back by more than this means the audio looped back to the start of the file
+const AUDIO_LOOP_THRESHOLD: f64 = 1.0;
+
+// When the newest decoded frame is this late the decoder fell behind or rendering was paused, the
+// clock restarts from it instead of racing through frames to catch up
+const MAX_CLOCK_DRIFT: f64 = 0.5;
+
+// Bounds of the queue depth, which otherwise follows the memory budget
+const MIN_QUEUED_FRAMES: usize = 3;
+const MAX_QUEUED_FRAMES: usize = 20;
+
+// Decoding restarts this many times past the point where it failed before giving up on the video
+const MAX_RECOVERY_ATTEMPTS: u32 = 3;
+// Failures further apart than this are recovered from independently
+const RECOVERY_WINDOW: Duration = Duration::from_secs(30);
+// Skipped after the failing frame, more on each attempt
+const RECOVERY_SKIP: f64 = 1.0;
+
+/// Videos being played, shared by the renderers of every output so a video shown on several
+/// outputs is only decoded and uploaded once
+#[derive(Clone)]
+pub struct VideoSources {
+    egl_state: Rc<EGLState>,
+    mixer: AudioMixer,
+    decoders: Rc<[DecoderBackend]>,
+    // Bytes the decoded frames waiting in the queue of a video may take
+    queue_memory: usize,
+    // Pinged by decoding threads when they fail
+    wakeup: Ping,
+    sources: Rc<RefCell<HashMap<PathBuf, Weak<RefCell<VideoSource>>>>>,
+    next_output_id: Rc<Cell<usize>>,
+}
+
+/// Decoding thread, playback clock and textures of a video
+pub struct VideoSource {
+    path: PathBuf,
+    decoders: Rc<[DecoderBackend]>,
+    queue_memory: usize,
+    wakeup: Ping,
+    textures: PlaneTextures,
+    // Set when a new frame has to be uploaded
+    frame_changed: bool,
+    // Imports hardware decoded frames when the EGL implementation supports it
+    dma_buf: Option<DmaBufImporter>,
+    // Cleared to make the decoder download hardware frames when importing them fails
+    dma_buf_export: Arc<AtomicBool>,
+    backend: DecoderBackend,
+
+    // Playback clock, in presentation time: `clock_origin` at `clock_start`
+    clock_start: Instant,
+    clock_origin: f64,
+
+    playback: Playback,
+    // Waiting for the first frame decoded after a seek
+    seeking: bool,
+    // Frames of older generations were decoded before the last seek
+    generation: u64,
+
+    last_frame: VideoFrame,
+
+    decoding: DecodingThread,
+    control: Arc<Mutex<DecoderControl>>,
+    // Failures since the last one outside of the recovery window
+    failures: u32,
+    last_failure: Option<Instant>,
+    // Set once recovering failed, the video can't be played anymore
+    failed: Option<String>,
+
+    mixer: AudioMixer,
+    has_audio: bool,
+    // Drives the frame pacing while an output hears the video, only started then
+    audio: Option<TrackHandle>,
+    // The audio position is ignored until it gets close to this timestamp it was seeked to
+    audio_seek: Option<f64>,
+    // Settings of the outputs showing the video, by output id
+    outputs: HashMap<usize, OutputSettings>,
+    // The video pauses when every output showing it is suspended, like when they can't be seen
+    suspended_outputs: HashSet<usize>,
+}
+
+/// An output showing a video source, which is stopped once no output shows it anymore
+pub struct VideoSourceHandle {
+    source: Rc<RefCell<VideoSource>>,
+    output_id: usize,
+}
+
+struct VideoFrame {
+    /// Timestamp in the file, restarts when the video loops
+    pts: f64,
+    /// Timestamp since playback started, keeps increasing across loops and seeks
+    presentation: f64,
+    /// Number of seeks requested before the frame was decoded
+    generation: u64,
+    frame: YuvFrame,
+}
+
+/// Thread decoding frames ahead of their presentation, stopped when dropped
+struct DecodingThread {
+    handle: Option<JoinHandle<()>>,
+    frames: Arc<FrameQueue<VideoFrame>>,
+    // Receives the error that stopped the thread
+    failure: Receiver<DecoderFailure>,
+}
+
+struct DecoderFailure {
+    /// Timestamp of the last frame decoded
+    pts: f64,
+    error: ffmpeg::Error,
+}
+
+/// Requests to the decoding thread
+#[derive(Default)]
+struct DecoderControl {
+    seek: Option<f64>,
+    loop_range: Option<(f64, f64)>,
+    generation: u64,
+}
+
+/// One texture per plane of the frames, reallocated when their layout or size changes
+struct PlaneTextures {
+    textures: [GLuint; 3],
+    // Frames are imported into these first, so the current textures stay valid if importing fails
+    spare: [GLuint; 3],
+    allocated: Option<(PlaneLayout, u32, u32)>,
+    // Keeps the frame the textures were imported from alive while they are sampled
+    imported: Option<(Vec<DmaBufImage>, DmaBufFrame)>,
+}
+
+impl VideoSources {
+    pub fn new(
+        egl_state: Rc<EGLState>,
+        mixer: AudioMixer,
+        config: &VideoConfig,
+        wakeup: Ping,
+    ) -> Self {
+        Self {
+            egl_state,
+            mixer,
+            decoders: config.decoders.clone().into(),
+            queue_memory: config.queue_memory_mb * 1024 * 1024,
+            wakeup,
+            sources: Rc::new(RefCell::new(HashMap::new())),
+            next_output_id: Rc::new(Cell::new(0)),
+        }
+    }
+
+    /// Joins the source playing `path`, starting it when no output shows it yet. Returns `None`
+    /// when no decoder can play the video.
+    ///
+    /// Needs a current GL context.
+    pub fn open(&self, path: &Path, settings: &OutputSettings) -> Option<VideoSourceHandle> {
+        let existing = self.sources.borrow().get(path).and_then(Weak::upgrade);
+
+        let source = match existing {
+            Some(source) => {
+                tracing::debug!("Sharing the decoder of {}", path.to_string_lossy());
+                source
+            }
+            None => {
+                let source = Rc::new(RefCell::new(VideoSource::open(
+                    path,
+                    self.egl_state.clone(),
+                    &self.mixer,
+                    self.decoders.clone(),
+                    self.queue_memory,
+                    self.wakeup.clone(),
+                )?));
+
+                let mut sources = self.sources.borrow_mut();
+                sources.retain(|_, source| source.strong_count() > 0);
+                sources.insert(path.to_path_buf(), Rc::downgrade(&source));
+                source
+            }
+        };
+
+        Some(self.attach(source, settings))
+    }
+
+    /// Plays the video of `handle` from its current position in a source of its own, which other
+    /// outputs don't join. Used when the outputs sharing a source want different playback.
+    ///
+    /// Needs a current GL context.
+    pub fn detach(&self, handle: &VideoSourceHandle) -> Option<VideoSourceHandle> {
+        let (path, position, settings) = {
+            let source = handle.source.borrow();
+            let settings = source.outputs[&handle.output_id];
+            (source.path.clone(), source.last_frame.pts, settings)
+        };
+        tracing::debug!("Playing {} separately on an output", path.to_string_lossy());
+
+        let mut source = VideoSource::open(
+            &path,
+            self.egl_state.clone(),
+            &self.mixer,
+            self.decoders.clone(),
+            self.queue_memory,
+            self.wakeup.clone(),
+        )?;
+        source.seek(position);
+
+        Some(self.attach(Rc::new(RefCell::new(source)), &settings))
+    }
+
+    fn attach(
+        &self,
+        source: Rc<RefCell<VideoSource>>,
+        settings: &OutputSettings,
+    ) -> VideoSourceHandle {
+        let output_id = self.next_output_id.get();
+        self.next_output_id.set(output_id + 1);
+
+        let handle = VideoSourceHandle { source, output_id };
+        handle.set_output_settings(settings);
+        handle
+    }
+}
+
+impl VideoSource {
+    fn open(
+        path: &Path,
+        egl_state: Rc<EGLState>,
+        mixer: &AudioMixer,
+        decoders: Rc<[DecoderBackend]>,
+        queue_memory: usize,
+        wakeup: Ping,
+    ) -> Option<Self> {
+        let dma_buf = DmaBufImporter::new(egl_state);
+
+        let (decoder, first_frame) =
+            open_decoder(path, &decoders, queue_memory, dma_buf.is_some())?;
+        let backend = decoder.backend();
+        let dma_buf_export = decoder.dma_buf_export();
+
+        let control = Arc::new(Mutex::new(DecoderControl::default()));
+        let (decoding, first_frame) = DecodingThread::start(
+            decoder,
+            first_frame,
+            queue_memory,
+            control.clone(),
+            wakeup.clone(),
+        );
+
+        let has_audio = audio_source::has_audio(&SoundInput::File(path.to_path_buf()));
+
+        Some(Self {
+            path: path.to_path_buf(),
+            decoders,
+            queue_memory,
+            wakeup,
+            textures: PlaneTextures::new(),
+            frame_changed: true,
+            dma_buf,
+            dma_buf_export,
+            backend,
+            clock_start: Instant::now(),
+            clock_origin: first_frame.presentation,
+            playback: Playback::default(),
+            seeking: false,
+            generation: 0,
+            last_frame: first_frame,
+            decoding,
+            control,
+            failures: 0,
+            last_failure: None,
+            failed: None,
+            mixer: mixer.clone(),
+            has_audio,
+            audio: None,
+            audio_seek: None,
+            outputs: HashMap::new(),
+            suspended_outputs: HashSet::new(),
+        })
+    }
+
+    /// Moves to the frame due now and uploads it. Every output showing the video calls it before
+    /// drawing, only the first one does something.
+    pub fn update(&mut self) {
+        if let Ok(failure) = self.decoding.failure.try_recv() {
+            self.recover(failure);
+        }
+        if self.failed.is_some() {
+            return;
+        }
+
+        if self.seeking {
+            self.present_seeked_frame();
+        } else if !self.playback.paused {
+            self.loop_audio();
+
+            if let Some(clock) = self.audio_clock() {
+                self.sync_to_audio(clock);
+            } else {
+                self.sync_to_clock();
+            }
+        }
+
+        if !self.frame_changed {
+            return;
+        }
+        self.frame_changed = false;
+
+        let frame = &self.last_frame.frame;
+        let import_failure = match &frame.data {
+            FrameData::Memory(planes) => {
+                unsafe { self.textures.upload(frame, planes) };
+                None
+            }
+            FrameData::DmaBuf(exported) => self
+                .dma_buf
+                .as_ref()
+                .and_then(|importer| self.textures.import(exported, importer).err()),
+        };
+
+        if let Some(err) = import_failure {
+            tracing::warn!("Failed to import video frame, copying frames instead: {err}");
+            self.dma_buf_export.store(false, Ordering::Relaxed);
+            self.dma_buf = None;
+
+            // The previous frame stays on screen until the decoder copied the current one again,
+            // the frames queued meanwhile were exported too
+            self.seek(self.last_frame.pts);
+        }
+    }
+
+    pub fn frame(&self) -> &YuvFrame {
+        &self.last_frame.frame
+    }
+
+    /// Binds the planes of the current frame to the first three texture units
+    pub unsafe fn bind_textures(&self, wrap: GLuint) {
+        self.textures.bind(wrap);
+    }
+
+    pub fn backend(&self) -> DecoderBackend {
+        self.backend
+    }
+
+    pub fn queue_status(&self) -> VideoQueueStatus {
+        let frames = &self.decoding.frames;
+
+        VideoQueueStatus {
+            queued_frames: frames.depth(),
+            capacity: frames.capacity(),
+            dropped_frames: frames.dropped(),
+        }
+    }
+
+    /// True when the frame on screen won't change until the playback does
+    pub fn is_idle(&self) -> bool {
+        self.playback.paused && !self.seeking && !self.frame_changed
+    }
+
+    /// Error that stopped the playback, once decoding failed too many times
+    pub fn failure(&self) -> Option<&str> {
+        self.failed.as_deref()
+    }
+
+    /// Jumps to `position` in seconds, the current frame stays on screen until the decoder got
+    /// there
+    pub fn seek(&mut self, position: f64) {
+        let position = position.max(0.0);
+
+        {
+            let mut control = self.control.lock().unwrap();
+            control.seek = Some(position);
+            control.generation += 1;
+            self.generation = control.generation;
+        }
+
+        self.decoding.frames.clear();
+        self.seeking = true;
+
+        if let Some(audio) = &self.audio {
+            audio.seek(position);
+            self.audio_seek = Some(position);
+        }
+    }
+
+    /// Sets the playback, paused as long as every output showing the video is suspended
+    fn apply_playback(&mut self, mut playback: Playback) {
+        if self
+            .outputs
+            .keys()
+            .all(|id| self.suspended_outputs.contains(id))
+        {
+            playback.paused = true;
+        }
+
+        self.set_playback(playback);
+    }
+
+    fn set_playback(&mut self, playback: Playback) {
+        let previous = std::mem::replace(&mut self.playback, playback);
+        if previous == playback {
+            return;
+        }
+
+        // Resume from the frame on screen, at the new pace
+        self.restart_clock_at(self.last_frame.presentation);
+        if let Some(audio) = &self.audio {
+            audio.set_paused(playback.paused);
+            audio.set_speed(playback.rate);
+        }
+
+        if playback.loop_range != previous.loop_range {
+            // The frames already decoded past the end of the new range still play once
+            self.control.lock().unwrap().loop_range = playback.loop_range;
+
+            if let Some((start, end)) = playback.loop_range
+                && !(start..end).contains(&self.last_frame.pts)
+            {
+                self.seek(start);
+            }
+        }
+    }
+
+    /// Plays the audio at the volume of the loudest output that isn't muted, and stops decoding
+    /// it while nobody hears it
+    fn update_audio(&mut self) {
+        let volume = self
+            .outputs
+            .values()
+            .filter(|settings| !settings.muted)
+            .map(|settings| settings.volume)
+            .reduce(f32::max);
+
+        match volume {
+            Some(volume) if self.has_audio => {
+                if self.audio.is_none() {
+                    self.start_audio();
+                }
+                self.audio.as_ref().unwrap().set_volume(volume);
+            }
+            _ => {
+                self.audio = None;
+                self.audio_seek = None;
+            }
+        }
+    }
+
+    /// Starts the audio from the frame on screen, the video follows the clock until it caught up
+    fn start_audio(&mut self) {
+        let audio = self.mixer.play(
+            vec![SoundInput::File(self.path.clone())],
+            PlaybackMode::Loop,
+            1.0,
+        );
+        audio.set_paused(self.playback.paused);
+        audio.set_speed(self.playback.rate);
+        audio.seek(self.last_frame.pts);
+
+        self.audio_seek = Some(self.last_frame.pts);
+        self.audio = Some(audio);
+    }
+
+    /// Audio position to sync the video to, `None` while the audio catches up with a seek
+    fn audio_clock(&mut self) -> Option<f64> {
+        let position = self.audio.as_ref()?.position();
+
+        if let (Some(position), Some(target)) = (position, self.audio_seek) {
+            if (position - target).abs() > AUDIO_LOOP_THRESHOLD {
+                return None;
+            }
+            self.audio_seek = None;
+        }
+
+        position
+    }
+
+    /// The audio doesn't know about the loop range, brings it back to its start
+    fn loop_audio(&mut self) {
+        let (Some((start, end)), Some(audio)) = (self.playback.loop_range, &self.audio) else {
+            return;
+        };
+        let Some(position) = audio.position() else {
+            return;
+        };
+
+        let outside = position >= end || position + AUDIO_LOOP_THRESHOLD < start;
+        if outside && self.audio_seek.is_none() {
+            audio.seek(start);
+            self.audio_seek = Some(start);
+        }
+    }
+
+    /// Shows the most recent frame the audio clock went past
+    fn sync_to_audio(&mut self, clock: f64) {
+        let last_pts = self.last_frame.pts;
+        // Both streams loop on their own, when the audio restarted first skip to the start
+        let threshold = match self.playback.loop_range {
+            Some((start, end)) => AUDIO_LOOP_THRESHOLD.min((end - start) / 2.0),
+            None => AUDIO_LOOP_THRESHOLD,
+        };
+        let audio_looped = clock + threshold < last_pts;
+
+        self.present_due_frames(|frame| {
+            frame.pts <= clock || (audio_looped && frame.pts >= last_pts)
+        });
+
+        // Keep the playback clock in step in case the audio stops driving it
+        self.restart_clock_at(self.last_frame.presentation);
+    }
+
+    /// Shows the most recent frame the playback clock went past
+    fn sync_to_clock(&mut self) {
+        let elapsed = self.clock_start.elapsed().as_secs_f64() * self.playback.rate as f64;
+        let clock = self.clock_origin + elapsed;
+
+        let caught_up = self.present_due_frames(|frame| frame.presentation <= clock);
+
+        if !caught_up && clock - self.last_frame.presentation > MAX_CLOCK_DRIFT {
+            tracing::debug!("Video playback fell behind, restarting clock");
+            self.restart_clock_at(self.last_frame.presentation);
+        }
+    }
+
+    /// Shows the most recent due frame, the others are dropped as late. Returns false when the
+    /// queue ran out before reaching a frame that isn't due yet.
+    fn present_due_frames(&mut self, is_due: impl Fn(&VideoFrame) -> bool) -> bool {
+        let (frame, caught_up) = self.decoding.frames.pop_due(is_due);
+
+        if let Some(frame) = frame {
+            self.last_frame = frame;
+            self.frame_changed = true;
+        }
+
+        caught_up
+    }
+
+    /// Shows the first frame decoded after the last seek once it's ready, the older ones are
+    /// dropped
+    fn present_seeked_frame(&mut self) {
+        let generation = self.generation;
+
+        if let Some(frame) = self
+            .decoding
+            .frames
+            .pop_first(|frame| frame.generation == generation)
+        {
+            self.restart_clock_at(frame.presentation);
+            self.last_frame = frame;
+            self.frame_changed = true;
+            self.seeking = false;
+        }
+    }
+
+    /// Restarts decoding a bit after the frame it failed at, gives up after repeated failures
+    fn recover(&mut self, failure: DecoderFailure) {
+        tracing::warn!(
+            "Decoding {} failed at {:.3}s: {}",
+            self.path.to_string_lossy(),
+            failure.pts,
+            failure.error
+        );
+
+        if self
+            .last_failure
+            .is_some_and(|last| last.elapsed() > RECOVERY_WINDOW)
+        {
+            self.failures = 0;
+        }
+        self.last_failure = Some(Instant::now());
+        self.failures += 1;
+
+        if self.failures > MAX_RECOVERY_ATTEMPTS {
+            tracing::error!("Giving up on {}", self.path.to_string_lossy());
+            self.failed = Some(failure.error.to_string());
+            return;
+        }
+
+        let dma_buf = self.dma_buf.is_some();
+        let Some((decoder, first_frame)) =
+            open_decoder(&self.path, &self.decoders, self.queue_memory, dma_buf)
+        else {
+            self.failed = Some(failure.error.to_string());
+            return;
+        };
+
+        self.backend = decoder.backend();
+        self.dma_buf_export = decoder.dma_buf_export();
+        // Stops the previous thread, which already exited
+        (self.decoding, _) = DecodingThread::start(
+            decoder,
+            first_frame,
+            self.queue_memory,
+            self.control.clone(),
+            self.wakeup.clone(),
+        );
+
+        let position = failure.pts + RECOVERY_SKIP * self.failures as f64;
+        tracing::info!("Resuming playback at {position:.3}s");
+        self.seek(position);
+    }
+
+    fn restart_clock_at(&mut self, presentation: f64) {
+        self.clock_start = Instant::now();
+        self.clock_origin = presentation;
+    }
+}
+
+/// Gives frames a presentation timestamp that keeps increasing when the video loops
+struct LoopTimeline {
+    frame_duration: f64,
+    offset: f64,
+    last_pts: f64,
+}
+
+impl LoopTimeline {
+    fn new(framerate: f32) -> Self {
+        Self {
+            frame_duration: if framerate > 0.0 {
+                1.0 / framerate as f64
+            } else {
+                0.0
+            },
+            offset: 0.0,
+            last_pts: 0.0,
+        }
+    }
+
+    /// The next frame, at `pts`, follows the last one
+    fn jumped_to(&mut self, pts: f64) {
+        self.offset += self.last_pts + self.frame_duration - pts;
+    }
+
+    fn frame(&mut self, frame: YuvFrame, generation: u64) -> VideoFrame {
+        self.last_pts = frame.pts;
+
+        VideoFrame {
+            pts: frame.pts,
+            presentation: self.offset + frame.pts,
+            generation,
+            frame,
+        }
+    }
+}
+
+/// Frames of `frame_size` bytes fitting in `memory`
+fn queue_capacity(memory: usize, frame_size: usize) -> usize {
+    (memory / frame_size.max(1)).clamp(MIN_QUEUED_FRAMES, MAX_QUEUED_FRAMES)
+}
+
+/// Opens `path` with the first decoder of `decoders` able to play it, along with its first frame
+fn open_decoder(
+    path: &Path,
+    decoders: &[DecoderBackend],
+    queue_memory: usize,
+    dma_buf: bool,
+) -> Option<(VideoDecoder, YuvFrame)> {
+    // Exported frames can wait in the queue, be displayed, and back the textures until the next
+    // one is imported. 8 bit 4:2:0 frames take 1.5 bytes per pixel.
+    let dma_buf_frames = |width: u32, height: u32| {
+        if dma_buf {
+            queue_capacity(queue_memory, width as usize * height as usize * 3 / 2) + 2
+        } else {
+            0
+        }
+    };
+
+    VideoDecoder::open_with_fallback(path, decoders, &dma_buf_frames)
+}
+
+impl DecodingThread {
+    /// Decodes frames until dropped or until decoding fails, the queue depth depends on the size
+    /// of the frames
+    fn start(
+        decoder: VideoDecoder,
+        first_frame: YuvFrame,
+        queue_memory: usize,
+        control: Arc<Mutex<DecoderControl>>,
+        wakeup: Ping,
+    ) -> (Self, VideoFrame) {
+        let capacity = queue_capacity(queue_memory, first_frame.byte_size());
+        tracing::debug!(
+            "Queueing up to {capacity} frames of {}x{}",
+            first_frame.width,
+            first_frame.height
+        );
+
+        let frames = Arc::new(FrameQueue::new(capacity));
+        let (failure_tx, failure) = mpsc::channel();
+
+        let (handle, first_frame) = start_decoding_thread(
+            decoder,
+            first_frame,
+            frames.clone(),
+            control,
+            failure_tx,
+            wakeup,
+        );
+
+        let thread = Self {
+            handle: Some(handle),
+            frames,
+            failure,
+        };
+        (thread, first_frame)
+    }
+}
+
+impl Drop for DecodingThread {
+    fn drop(&mut self) {
+        self.frames.close();
+
+        let _ = self.handle.take().unwrap().join();
+    }
+}
+
+/// Decodes frames into `frames` until the queue is closed or an error is sent to `failure`, then
+/// pings `wakeup` so the error is handled even if no output is drawing
+fn start_decoding_thread(
+    mut decoder: VideoDecoder,
+    first_frame: YuvFrame,
+    frames: Arc<FrameQueue<VideoFrame>>,
+    control: Arc<Mutex<DecoderControl>>,
+    failure: Sender<DecoderFailure>,
+    wakeup: Ping,
+) -> (JoinHandle<()>, VideoFrame) {
+    let mut timeline = LoopTimeline::new(decoder.frame_rate());
+    let first_frame = timeline.frame(first_frame, 0);
+
+    let handle = thread::spawn(move || {
+        tracing::debug!("Spawn decoding thread");
+        // Seeks land on a keyframe, the frames before the requested timestamp are skipped
+        let mut skip_until = None;
+
+        while !frames.is_closed() {
+            let (seek, loop_range, generation) = {
+                let mut control = control.lock().unwrap();
+                (control.seek.take(), control.loop_range, control.generation)
+            };
+
+            if let Some(position) = seek {
+                tracing::debug!("Seeking to {position:.3}s");
+                skip_until = seek_decoder(&mut decoder, &mut timeline, position);
+            }
+
+            let frame = match decoder.decode() {
+                Ok(Some(frame)) => frame,
+                Ok(None) => {
+                    let start = loop_range.map_or(0.0, |(start, _)| start);
+                    tracing::debug!("Video ended, seeking to {start:.3}s");
+                    skip_until = seek_decoder(&mut decoder, &mut timeline, start);
+                    continue;
+                }
+                Err(error) => {
+                    let pts = timeline.last_pts;
+                    let _ = failure.send(DecoderFailure { pts, error });
+                    wakeup.ping();
+                    break;
+                }
+            };
+
+            if let Some(position) = skip_until {
+                if frame.pts + timeline.frame_duration / 2.0 < position {
+                    continue;
+                }
+                skip_until = None;
+            }
+
+            if let Some((start, end)) = loop_range
+                && frame.pts >= end
+            {
+                tracing::debug!("Reached the end of the loop range, seeking to {start:.3}s");
+                skip_until = seek_decoder(&mut decoder, &mut timeline, start);
+                continue;
+            }
+
+            // Blocks while the queue is full
+            if !frames.push(timeline.frame(frame, generation)) {
+                break;
+            }
+        }
+
+        tracing::debug!("Exited decoding Thread!");
+    });
+
+    (handle, first_frame)
+}
+
+/// Returns the timestamp of the first frame to keep
+fn seek_decoder(
+    decoder: &mut VideoDecoder,
+    timeline: &mut LoopTimeline,
+    position: f64,
+) -> Option<f64> {
+    let result = if position > 0.0 {
+        decoder.seek(position)
+    } else {
+        decoder.seek_to_start()
+    };
+
+    if let Err(err) = result {
+        tracing::warn!("Failed to seek video to {position:.3}s: {err}");
+        return None;
+    }
+
+    timeline.jumped_to(position);
+    Some(position)
+}
+
+impl VideoSourceHandle {
+    pub fn get(&self) -> RefMut<'_, VideoSource> {
+        self.source.borrow_mut()
+    }
+
+    pub fn backend(&self) -> DecoderBackend {
+        self.source.borrow().backend
+    }
+
+    /// The playback is only applied by `sync_playback`, so outputs updated one after the other
+    /// don't see each other's stale settings
+    pub fn set_output_settings(&self, settings: &OutputSettings) {
+        let mut source = self.source.borrow_mut();
+        source.outputs.insert(self.output_id, *settings);
+        source.update_audio();
+    }
+
+    /// Applies the playback of this output, returns false when another output showing the video
+    /// wants a different one
+    pub fn sync_playback(&self) -> bool {
+        let mut source = self.source.borrow_mut();
+        let playback = source.outputs[&self.output_id].playback;
+
+        if source
+            .outputs
+            .values()
+            .any(|settings| settings.playback != playback)
+        {
+            return false;
+        }
+
+        source.apply_playback(playback);
+        true
+    }
+
+    pub fn set_suspended(&self, suspended: bool) {
+        let mut source = self.source.borrow_mut();
+        if suspended {
+            source.suspended_outputs.insert(self.output_id);
+        } else {
+            source.suspended_outputs.remove(&self.output_id);
+        }
+
+        let playback = source.outputs[&self.output_id].playback;
+        source.apply_playback(playback);
+    }
+}
+
+impl Drop for VideoSourceHandle {
+    fn drop(&mut self) {
+        let mut source = self.source.borrow_mut();
+        source.outputs.remove(&self.output_id);
+        source.suspended_outputs.remove(&self.output_id);
+        source.update_audio();
+    }
+}
+
+impl PlaneTextures {
+    fn new() -> Self {
+        let mut textures = [0; 3];
+        let mut spare = [0; 3];
+        unsafe {
+            gl::GenTextures(3, textures.as_mut_ptr());
+            gl::GenTextures(3, spare.as_mut_ptr());
+        }
+
+        Self {
+            textures,
+            spare,
+            allocated: None,
+            imported: None,
+        }
+    }
+
+    fn fits(&self, frame: &YuvFrame) -> bool {
+        self.allocated == Some((frame.layout, frame.width, frame.height))
+    }
+
+    /// Uploads every plane of `frame`, reallocating the textures if needed
+    unsafe fn upload(&mut self, frame: &YuvFrame, planes: &[Plane]) {
+        // Images can't be written to, the textures need new storage
+        self.imported = None;
+
+        let reallocate = !self.fits(frame);
+        if reallocate {
+            tracing::debug!(
+                "Allocating {:?} textures for {}x{} frames",
+                frame.layout,
+                frame.width,
+                frame.height
+            );
+            self.allocated = Some((frame.layout, frame.width, frame.height));
+        }
+
+        // Planes rows aren't aligned on 4 bytes and may be padded by the decoder
+        gl::PixelStorei(gl::UNPACK_ALIGNMENT, 1);
+
+        for (index, plane) in planes.iter().enumerate() {
+            let (internal_format, format, bytes_per_pixel) =
+                if frame.layout == PlaneLayout::Nv12 && index == 1 {
+                    (gl::RG8, gl::RG, 2)
+                } else {
+                    (gl::R8, gl::RED, 1)
+                };
+
+            gl::BindTexture(gl::TEXTURE_2D, self.textures[index]);
+            gl::PixelStorei(
+                gl::UNPACK_ROW_LENGTH,
+                (plane.stride / bytes_per_pixel) as GLint,
+            );
+
+            if reallocate {
+                gl::TexImage2D(
+                    gl::TEXTURE_2D,
+                    0,
+                    internal_format as GLint,
+                    plane.width as GLsizei,
+                    plane.height as GLsizei,
+                    0,
+                    format,
+                    gl::UNSIGNED_BYTE,
+                    plane.data.as_ptr() as *const c_void,
+                );
+            } else {
+                gl::TexSubImage2D(
+                    gl::TEXTURE_2D,
+                    0,
+                    0,
+                    0,
+                    plane.width as GLsizei,
+                    plane.height as GLsizei,
+                    format,
+                    gl::UNSIGNED_BYTE,
+                    plane.data.as_ptr() as *const c_void,
+                );
+            }
+        }
+
+        gl::PixelStorei(gl::UNPACK_ROW_LENGTH, 0);
+        gl::PixelStorei(gl::UNPACK_ALIGNMENT, 4);
+    }
+
+    /// Backs the textures with the layers of `frame`, without copying them
+    fn import(&mut self, frame: &DmaBufFrame, importer: &DmaBufImporter) -> Result<(), egl::Error> {
+        let images = frame
+            .layers
+            .iter()
+            .zip(self.spare)
+            .map(|(layer, texture)| importer.import(layer, texture))
+            .collect::<Result<_, _>>()?;
+
+        std::mem::swap(&mut self.textures, &mut self.spare);
+        self.allocated = None;
+        self.imported = Some((images, frame.clone()));
+        Ok(())
+    }
+
+    /// Binds the plane textures to the first three texture units
+    unsafe fn bind(&self, wrap: GLuint) {
+        for (unit, texture) in self.textures.iter().enumerate() {
+            gl::ActiveTexture(gl::TEXTURE0 + unit as GLuint);
+            gl::BindTexture(gl::TEXTURE_2D, *texture);
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_S, wrap as GLint);
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_T, wrap as GLint);
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::LINEAR as GLint);
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::LINEAR as GLint);
+        }
+    }
+}
+
+impl Drop for PlaneTextures {
+    fn drop(&mut self) {
+        unsafe {
+            gl::DeleteTextures(3, self.textures.as_ptr());
+            gl::DeleteTextures(3, self.spare.as_ptr());
+        }
+    }
+}
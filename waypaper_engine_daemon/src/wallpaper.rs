@@ -16,6 +16,7 @@ pub enum Wallpaper {
         project: WEProject,
     },
     Scene {
+        base_dir_path: PathBuf,
         project: WEProject,
         scene_package: ScenePackage,
     },
@@ -50,6 +51,7 @@ impl Wallpaper {
                 let scene_package = ScenePackage::new(&scene_pkg_path).unwrap();
 
                 Wallpaper::Scene {
+                    base_dir_path: path,
                     project,
                     scene_package,
                 }
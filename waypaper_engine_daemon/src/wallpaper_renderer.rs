@@ -1,27 +1,148 @@
 use std::rc::Rc;
+use std::time::Duration;
 
 use smithay_client_toolkit::reexports::client::Connection;
 
+use waypaper_engine_shared::ipc::{
+    DecoderBackend, OcclusionPolicy, Scaling, Transition, VideoQueueStatus,
+};
 use waypaper_engine_shared::project::WallpaperType;
 
 use crate::egl::EGLState;
+use crate::scene_source::SceneSources;
+use crate::scene_wp_renderer::SceneWPRenderer;
+use crate::span::Span;
+use crate::transition::TransitionRenderer;
 use crate::video_rs_wp_renderer::VideoRSWPRenderer;
+use crate::video_source::VideoSources;
 use crate::wallpaper::Wallpaper;
 
+/// Value of the `pointer` uniform of wallpaper shaders while the pointer is away from the output
+pub(crate) const NO_POINTER: (f32, f32) = (-1.0, -1.0);
+
+/// Per output preferences, kept across wallpaper changes
+#[derive(Debug, Clone, Copy)]
+pub struct OutputSettings {
+    /// Bloom post-processing of scenes, can be turned off on machines that can't afford it
+    pub bloom: bool,
+    pub volume: f32,
+    pub muted: bool,
+    pub scaling: Scaling,
+    pub playback: Playback,
+    pub occlusion: OcclusionPolicy,
+    /// Frames per second, lowered to the refresh rate divided by a whole number
+    pub max_fps: Option<u32>,
+    /// Width of the frame around the screen on the sides and on the top and bottom, in logical
+    /// pixels. Spanned wallpapers are hidden behind it, so they line up across screens.
+    pub bezel: (u32, u32),
+    /// Used when a wallpaper is set without choosing a transition
+    pub transition: Transition,
+}
+
+/// Holds a wallpaper back regardless of its playback settings, while its output is hidden or to
+/// save power
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Suspension {
+    #[default]
+    Running,
+    Paused,
+    /// Shows a still image, video wallpapers stop decoding and start over once running again
+    StaticFrame,
+}
+
+/// How the wallpaper of an output plays
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Playback {
+    pub paused: bool,
+    /// Speed multiplier, between `MIN_PLAYBACK_RATE` and `MAX_PLAYBACK_RATE`
+    pub rate: f32,
+    /// Start and end timestamps of the part of a video played in a loop, in seconds
+    pub loop_range: Option<(f64, f64)>,
+}
+
+pub const MIN_PLAYBACK_RATE: f32 = 0.1;
+pub const MAX_PLAYBACK_RATE: f32 = 4.0;
+
+impl Default for OutputSettings {
+    fn default() -> Self {
+        Self {
+            bloom: true,
+            volume: 1.0,
+            muted: false,
+            scaling: Scaling::default(),
+            playback: Playback::default(),
+            occlusion: OcclusionPolicy::default(),
+            max_fps: None,
+            bezel: (0, 0),
+            transition: Transition::default(),
+        }
+    }
+}
+
+impl Default for Playback {
+    fn default() -> Self {
+        Self {
+            paused: false,
+            rate: 1.0,
+            loop_range: None,
+        }
+    }
+}
+
+impl Playback {
+    pub fn set_rate(&mut self, rate: f32) {
+        if rate.is_nan() {
+            tracing::warn!("Ignoring invalid playback rate");
+            return;
+        }
+
+        self.rate = rate.clamp(MIN_PLAYBACK_RATE, MAX_PLAYBACK_RATE);
+    }
+
+    /// Ignores ranges that are empty or start before the video
+    pub fn set_loop_range(&mut self, range: Option<(f64, f64)>) {
+        if let Some((start, end)) = range
+            && !(start >= 0.0 && start < end)
+        {
+            tracing::warn!("Ignoring invalid loop range {start}..{end}");
+            return;
+        }
+
+        self.loop_range = range;
+    }
+}
+
 pub struct WPRenderer {
     connection: Rc<Connection>,
     egl_state: Rc<EGLState>,
+    scene_sources: SceneSources,
     renderer: Option<Box<dyn WPRendererImpl>>,
     renderer_initialized: bool,
+    settings: OutputSettings,
+    span: Option<Span>,
+    // Blends the previous wallpaper into the current one
+    transition: Option<TransitionRenderer>,
+    video_sources: VideoSources,
 }
 
 impl WPRenderer {
-    pub fn new(connection: Rc<Connection>, egl_state: Rc<EGLState>) -> Self {
+    pub fn new(
+        connection: Rc<Connection>,
+        egl_state: Rc<EGLState>,
+        scene_sources: SceneSources,
+        settings: OutputSettings,
+        video_sources: VideoSources,
+    ) -> Self {
         Self {
             connection,
             egl_state,
+            scene_sources,
             renderer: None,
             renderer_initialized: false,
+            settings,
+            span: None,
+            transition: None,
+            video_sources,
         }
     }
 
@@ -34,9 +155,16 @@ impl WPRenderer {
                     self.renderer = Some(Box::new(VideoRSWPRenderer::new(
                         self.connection.clone(),
                         self.egl_state.clone(),
+                        self.video_sources.clone(),
+                    )));
+                }
+                Wallpaper::Scene { .. } => {
+                    self.renderer = Some(Box::new(SceneWPRenderer::new(
+                        self.connection.clone(),
+                        self.egl_state.clone(),
+                        self.scene_sources.clone(),
                     )));
                 }
-                Wallpaper::Scene { .. } => {}
                 Wallpaper::Web { .. } => {}
                 Wallpaper::Preset { .. } => {}
             }
@@ -44,7 +172,30 @@ impl WPRenderer {
             self.renderer_initialized = false;
         }
 
-        self.renderer.as_mut().unwrap().setup_wallpaper(wallpaper);
+        let renderer = self.renderer.as_mut().unwrap();
+        renderer.set_output_settings(self.settings);
+        renderer.set_span(self.span);
+        renderer.setup_wallpaper(wallpaper);
+    }
+
+    /// Keeps the current wallpaper drawing until the next one set up is blended in, needs a
+    /// current GL context
+    pub(crate) fn start_transition(&mut self, transition: Transition) {
+        if !self.renderer_initialized {
+            return;
+        }
+
+        // A transition still running is cut short
+        let previous = self.renderer.take().unwrap();
+        self.renderer_initialized = false;
+        self.transition = Some(TransitionRenderer::new(previous, transition));
+    }
+
+    /// Drops the wallpaper renderer, needs a current GL context
+    pub(crate) fn release(&mut self) {
+        self.transition = None;
+        self.renderer = None;
+        self.renderer_initialized = false;
     }
 
     pub(crate) fn clear_color(&self) -> (f32, f32, f32) {
@@ -56,10 +207,121 @@ impl WPRenderer {
     }
 
     pub(crate) fn render(&mut self, width: u32, height: u32) {
-        if let Some(renderer) = self.renderer.as_mut() {
-            renderer.render(width, height);
-        } else {
+        let Some(renderer) = self.renderer.as_mut() else {
             unreachable!();
+        };
+
+        if let Some(transition) = self.transition.as_mut() {
+            transition.render(renderer.as_mut(), width, height);
+
+            if transition.is_finished() {
+                // Frees the previous wallpaper while the context is current
+                self.transition = None;
+            }
+        } else {
+            renderer.render(width, height);
+        }
+    }
+
+    pub(crate) fn resize(&mut self, width: u32, height: u32) {
+        if let Some(renderer) = self.renderer.as_mut() {
+            renderer.resize(width, height);
+        }
+        if let Some(transition) = self.transition.as_mut() {
+            transition.previous_mut().resize(width, height);
+        }
+    }
+
+    /// Holds playback back without touching the playback settings
+    pub(crate) fn set_suspension(&mut self, suspension: Suspension) {
+        if let Some(renderer) = self.renderer.as_mut() {
+            renderer.set_suspension(suspension);
+        }
+        if let Some(transition) = self.transition.as_mut() {
+            transition.previous_mut().set_suspension(suspension);
+        }
+    }
+
+    pub(crate) fn set_pointer_position(&mut self, position: Option<(f32, f32)>) {
+        if let Some(renderer) = self.renderer.as_mut() {
+            renderer.set_pointer_position(position);
+        }
+        if let Some(transition) = self.transition.as_mut() {
+            transition.previous_mut().set_pointer_position(position);
+        }
+    }
+
+    pub(crate) fn video_decoder(&self) -> Option<DecoderBackend> {
+        self.renderer
+            .as_ref()
+            .and_then(|renderer| renderer.video_decoder())
+    }
+
+    pub(crate) fn video_queue(&self) -> Option<VideoQueueStatus> {
+        self.renderer
+            .as_ref()
+            .and_then(|renderer| renderer.video_queue())
+    }
+
+    pub(crate) fn seek(&mut self, position: f64) {
+        if let Some(renderer) = self.renderer.as_mut() {
+            renderer.seek(position);
+        }
+        if let Some(transition) = self.transition.as_mut() {
+            transition.previous_mut().seek(position);
+        }
+    }
+
+    /// Reason the wallpaper stopped playing, returned once
+    pub(crate) fn take_error(&mut self) -> Option<String> {
+        self.renderer
+            .as_mut()
+            .and_then(|renderer| renderer.take_error())
+    }
+
+    /// False when the last frame drawn can stay on screen, like while paused
+    pub(crate) fn needs_redraw(&self) -> bool {
+        self.transition.is_some()
+            || self
+                .renderer
+                .as_ref()
+                .is_none_or(|renderer| renderer.needs_redraw())
+    }
+
+    /// Shows part of the wallpaper when it spans several outputs
+    pub(crate) fn set_span(&mut self, span: Option<Span>) {
+        self.span = span;
+
+        if let Some(renderer) = self.renderer.as_mut() {
+            renderer.set_span(span);
+        }
+        if let Some(transition) = self.transition.as_mut() {
+            transition.previous_mut().set_span(span);
+        }
+    }
+
+    pub(crate) fn span(&self) -> Option<Span> {
+        self.span
+    }
+
+    pub(crate) fn settings(&self) -> &OutputSettings {
+        &self.settings
+    }
+
+    pub(crate) fn next_change(&self) -> Option<Duration> {
+        self.renderer
+            .as_ref()
+            .and_then(|renderer| renderer.next_change())
+    }
+
+    pub(crate) fn set_output_settings(&mut self, settings: OutputSettings) {
+        self.settings = settings;
+
+        if let Some(renderer) = self.renderer.as_mut() {
+            renderer.set_output_settings(settings);
+        }
+        if let Some(transition) = self.transition.as_mut() {
+            transition.previous_mut().set_output_settings(settings);
         }
     }
 
@@ -86,5 +348,57 @@ pub(crate) trait WPRendererImpl {
 
     fn render(&mut self, width: u32, height: u32);
 
+    /// Whether the last frame rendered shows the wallpaper, transitions wait for it
+    fn is_ready(&self) -> bool {
+        true
+    }
+
+    /// Called with a current GL context before the first frame rendered at a new output size,
+    /// like after a mode change or a rotation. Placement is computed again on each frame anyway.
+    fn resize(&mut self, _width: u32, _height: u32) {}
+
+    /// Pointer position on the output normalized to [0, 1], `None` when it left the output.
+    /// Renderers hand it to their shaders as `uniform vec2 pointer`, or [`NO_POINTER`].
+    fn set_pointer_position(&mut self, _position: Option<(f32, f32)>) {}
+
+    /// Called when the output gets hidden or the power policy changes, and for the renderer of a
+    /// newly set wallpaper
+    fn set_suspension(&mut self, _suspension: Suspension) {}
+
+    /// Called before `setup_wallpaper` and whenever the output joins or leaves a span, or the
+    /// outputs of its span move
+    fn set_span(&mut self, _span: Option<Span>) {}
+
+    /// Called before `setup_wallpaper` and whenever the settings of the output change
+    fn set_output_settings(&mut self, _settings: OutputSettings) {}
+
+    /// Jumps to a timestamp in seconds, for video wallpapers
+    fn seek(&mut self, _position: f64) {}
+
+    /// Whether the next frame can differ from the last one, frame callbacks stop otherwise
+    fn needs_redraw(&self) -> bool {
+        true
+    }
+
+    /// Time until the wallpaper changes on its own while it doesn't need redrawing, like a clock
+    fn next_change(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Decoder in use, for video wallpapers
+    fn video_decoder(&self) -> Option<DecoderBackend> {
+        None
+    }
+
+    /// Decoded frames waiting to be shown, for video wallpapers
+    fn video_queue(&self) -> Option<VideoQueueStatus> {
+        None
+    }
+
+    /// Set when playback failed for good and a fallback is shown instead
+    fn take_error(&mut self) -> Option<String> {
+        None
+    }
+
     fn get_wp_type(&self) -> WallpaperType;
 }
@@ -1,82 +1,236 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::ops::{Deref, DerefMut};
 use std::rc::Rc;
+use std::time::{Duration, Instant};
 
 use fps_counter::FPSCounter;
 use gl::COLOR_BUFFER_BIT;
 use khronos_egl::ATTRIB_NONE;
 use smithay_client_toolkit::output::OutputInfo;
+use smithay_client_toolkit::reexports::calloop::channel::{self, Event};
+use smithay_client_toolkit::reexports::calloop::ping::{make_ping, Ping};
+use smithay_client_toolkit::reexports::calloop::timer::{TimeoutAction, Timer};
+use smithay_client_toolkit::reexports::calloop::LoopHandle;
+use smithay_client_toolkit::reexports::calloop_wayland_source::WaylandSource;
 use smithay_client_toolkit::reexports::client::globals::{registry_queue_init, GlobalList};
 use smithay_client_toolkit::reexports::client::protocol::wl_output::WlOutput;
 use smithay_client_toolkit::reexports::client::protocol::wl_surface::WlSurface;
-use smithay_client_toolkit::reexports::client::protocol::{wl_output, wl_seat, wl_surface};
-use smithay_client_toolkit::reexports::client::{Connection, EventQueue, Proxy, QueueHandle};
+use smithay_client_toolkit::reexports::client::protocol::{
+    wl_output, wl_pointer, wl_seat, wl_surface,
+};
+use smithay_client_toolkit::reexports::client::{
+    delegate_noop, event_created_child, Connection, Dispatch, Proxy, QueueHandle, WEnum,
+};
+use smithay_client_toolkit::reexports::protocols::wp::fractional_scale::v1::client::{
+    wp_fractional_scale_manager_v1::WpFractionalScaleManagerV1,
+    wp_fractional_scale_v1::{self, WpFractionalScaleV1},
+};
+use smithay_client_toolkit::reexports::protocols::wp::presentation_time::client::{
+    wp_presentation::{self, WpPresentation},
+    wp_presentation_feedback::{self, WpPresentationFeedback},
+};
+use smithay_client_toolkit::reexports::protocols::wp::viewporter::client::{
+    wp_viewport::WpViewport, wp_viewporter::WpViewporter,
+};
+use smithay_client_toolkit::reexports::protocols_wlr::foreign_toplevel::v1::client::{
+    zwlr_foreign_toplevel_handle_v1::{self, ZwlrForeignToplevelHandleV1},
+    zwlr_foreign_toplevel_manager_v1::{self, ZwlrForeignToplevelManagerV1},
+};
+use smithay_client_toolkit::reexports::protocols_wlr::output_power_management::v1::client::{
+    zwlr_output_power_manager_v1::ZwlrOutputPowerManagerV1,
+    zwlr_output_power_v1::{self, ZwlrOutputPowerV1},
+};
 use smithay_client_toolkit::shell::wlr_layer::Anchor;
 use smithay_client_toolkit::{
-    compositor::{CompositorHandler, CompositorState},
-    delegate_compositor, delegate_layer, delegate_output, delegate_registry, delegate_seat,
+    compositor::{CompositorHandler, CompositorState, Region},
+    delegate_compositor, delegate_layer, delegate_output, delegate_pointer, delegate_registry,
+    delegate_seat,
     output::{OutputHandler, OutputState},
     registry::{ProvidesRegistryState, RegistryState},
     registry_handlers,
-    seat::{Capability, SeatHandler, SeatState},
+    seat::{
+        pointer::{PointerEvent, PointerEventKind, PointerHandler},
+        Capability, SeatHandler, SeatState,
+    },
     shell::{
         wlr_layer::{
             KeyboardInteractivity, Layer, LayerShell, LayerShellHandler, LayerSurface,
-            LayerSurfaceConfigure,
+            LayerSurfaceConfigure, SurfaceKind,
         },
         WaylandSurface,
     },
 };
 use wayland_egl::WlEglSurface;
 
+use waypaper_engine_shared::ipc::{
+    DaemonEvent, DaemonStatus, OcclusionPolicy, OutputStatus, PointerTracking, Scaling, ShellLayer,
+    Transition,
+};
+
+use crate::app_state::AppState;
+use crate::audio::{AudioMixer, AudioOutput};
+use crate::config::{Config, OutputIdentity};
 use crate::egl::EGLState;
+use crate::occlusion::{Toplevels, Visibility};
+use crate::power::{self, PowerAction, PowerState};
+use crate::scene_source::SceneSources;
+use crate::span::{self, SpanOutput};
+use crate::video_source::VideoSources;
 use crate::wallpaper::Wallpaper;
-use crate::wallpaper_renderer::WPRenderer;
+use crate::wallpaper_renderer::{OutputSettings, Playback, Suspension, WPRenderer};
+
+/// Layer surface of an output
+#[derive(Debug, Clone, PartialEq)]
+pub struct LayerOptions {
+    pub layer: ShellLayer,
+    pub namespace: String,
+    /// Top, right, bottom and left, in logical pixels
+    pub margin: (i32, i32, i32, i32),
+}
+
+impl Default for LayerOptions {
+    fn default() -> Self {
+        Self {
+            layer: ShellLayer::default(),
+            namespace: "waypaper_engine".to_string(),
+            margin: (0, 0, 0, 0),
+        }
+    }
+}
+
+fn shell_layer(layer: ShellLayer) -> Layer {
+    match layer {
+        ShellLayer::Background => Layer::Background,
+        ShellLayer::Bottom => Layer::Bottom,
+        ShellLayer::Top => Layer::Top,
+        ShellLayer::Overlay => Layer::Overlay,
+    }
+}
+
+/// Size of an output left inside the margins
+fn inner_size(output_size: (i32, i32), margin: (i32, i32, i32, i32)) -> (u32, u32) {
+    let (top, right, bottom, left) = margin;
+    (
+        (output_size.0 - left - right).max(1) as u32,
+        (output_size.1 - top - bottom).max(1) as u32,
+    )
+}
 
 pub struct RenderingContext {
     pub(crate) connection: Rc<Connection>,
     pub(crate) egl_state: Rc<EGLState>,
-    event_queue: EventQueue<WLState>,
     wl_state: WLState,
+    _audio_output: AudioOutput,
 }
 
 impl RenderingContext {
-    pub fn new() -> Self {
+    /// Wayland events and decoder wakeups are dispatched by `event_loop`
+    pub fn new(config: Config, event_loop: &LoopHandle<'static, AppState>) -> Self {
         let connection = Rc::new(Connection::connect_to_env().unwrap());
         let egl_state = Rc::new(EGLState::new(connection.clone()));
-        let (globals, event_queue): (GlobalList, EventQueue<WLState>) =
-            registry_queue_init(&connection).unwrap();
+        let (globals, mut event_queue) = registry_queue_init::<WLState>(&connection).unwrap();
         let queue_handle = event_queue.handle();
+        let audio_output = AudioOutput::new();
+
+        // Lets decoding threads wake up layers that stopped drawing, to report their failures
+        let (decoder_wakeup, decoder_wakeup_source) = make_ping().unwrap();
+        let (power_tx, power_rx) = channel::channel();
+        power::start_monitor(&config.power, power_tx);
 
-        let wl_state = WLState::new(
+        let mut wl_state = WLState::new(
             connection.clone(),
             egl_state.clone(),
+            audio_output.mixer(),
+            config,
             &globals,
             queue_handle,
+            event_loop.clone(),
+            decoder_wakeup,
         );
 
+        // Outputs have to be known before the first request comes in
+        event_queue.roundtrip(&mut wl_state).unwrap();
+
+        event_loop
+            .insert_source(
+                WaylandSource::new((*connection).clone(), event_queue),
+                |_, queue, app| {
+                    let context = &mut app.rendering_context;
+                    let dispatched = queue.dispatch_pending(&mut context.wl_state);
+                    context.collect_events();
+                    app.restore_wallpapers();
+                    dispatched
+                },
+            )
+            .unwrap();
+        event_loop
+            .insert_source(decoder_wakeup_source, |_, _, app| {
+                app.rendering_context.wl_state.wake_layers();
+            })
+            .unwrap();
+        event_loop
+            .insert_source(power_rx, |event, _, app| {
+                if let Event::Msg(state) = event {
+                    app.rendering_context.wl_state.set_power_state(&state);
+                }
+            })
+            .unwrap();
+        event_loop
+            .insert_source(
+                Timer::from_duration(VISIBILITY_CHECK_INTERVAL),
+                |_, _, app| {
+                    app.rendering_context.wl_state.check_visibility();
+                    TimeoutAction::ToDuration(VISIBILITY_CHECK_INTERVAL)
+                },
+            )
+            .unwrap();
+
         tracing::info!("Created WL state");
 
         Self {
             connection,
             egl_state,
-            event_queue,
             wl_state,
+            _audio_output: audio_output,
+        }
+    }
+
+    fn collect_events(&mut self) {
+        let failures: Vec<_> = self
+            .wl_state
+            .layers
+            .iter_mut()
+            .filter_map(|(name, layer)| {
+                let error = layer.renderer.take_error()?;
+                Some(DaemonEvent::WallpaperFailed {
+                    screen: name.clone(),
+                    error,
+                })
+            })
+            .collect();
+
+        for event in failures {
+            self.wl_state.push_event(event);
         }
     }
 
-    pub fn tick(&mut self) {
-        self.event_queue.roundtrip(&mut self.wl_state).unwrap(); // FIXME: roundtrip is probably overkill but we can't use blocking_dispatch and dispatch_pending causes frame drops
+    pub(crate) fn take_events(&mut self) -> Vec<DaemonEvent> {
+        self.collect_events();
+        self.wl_state.events.drain(..).collect()
+    }
 
-        /*if self.wl_state.layers.values().any(|layer| layer.exit) {
-            tracing::debug!("Exiting");
-            break;
-        }*/
+    /// Outputs connected since the last call, including the ones present at startup
+    pub(crate) fn take_new_outputs(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.wl_state.new_outputs)
     }
 
-    pub fn get_outputs(&mut self) -> OutputsList {
-        self.event_queue.roundtrip(&mut self.wl_state).unwrap();
+    /// Wallpaper the config assigns to an output
+    pub(crate) fn configured_wallpaper(&self, output_name: &str) -> Option<u64> {
+        let identity = self.wl_state.output_identity(output_name);
+        self.wl_state.config.output_wallpaper(&identity)
+    }
 
+    pub fn get_outputs(&self) -> OutputsList {
         OutputsList(
             self.wl_state
                 .output_state
@@ -91,42 +245,218 @@ impl RenderingContext {
         )
     }
 
-    pub(crate) fn set_wallpaper(&mut self, output: (&WlOutput, &OutputInfo), wallpaper: Wallpaper) {
+    /// `transition` set to `None` uses the one configured for the output
+    pub(crate) fn set_wallpaper(
+        &mut self,
+        output: (&WlOutput, &OutputInfo),
+        wallpaper: Wallpaper,
+        transition: Option<Transition>,
+    ) {
         let output_name = output.1.name.clone().unwrap();
+        let qh = self.wl_state.queue_handle.clone();
 
-        let layer = if self.wl_state.layers.contains_key(&output_name.clone()) {
-            self.wl_state.layers.get_mut(&output_name.clone()).unwrap()
-        } else {
-            self.wl_state.setup_layer(output)
-        };
+        if !self.wl_state.layers.contains_key(&output_name) {
+            self.wl_state.setup_layer(output);
+            // The other outputs of its span get a smaller part of it
+            self.wl_state.update_spans();
+        }
+
+        let layer = self.wl_state.layers.get_mut(&output_name).unwrap();
+        layer.set_wallpaper(wallpaper, transition);
+        layer.wake(&qh);
+    }
+
+    /// Makes `output_names` show their wallpaper across them, they leave their previous span
+    pub(crate) fn set_span(&mut self, output_names: Vec<String>) {
+        tracing::info!("Spanning wallpapers across {}", output_names.join(", "));
+
+        for name in &output_names {
+            self.wl_state.remove_from_spans(name);
+        }
+        self.wl_state.spans.push(output_names);
+        self.wl_state.update_spans();
+    }
 
-        layer.set_wallpaper(wallpaper);
+    pub(crate) fn leave_span(&mut self, output_name: &str) {
+        self.wl_state.remove_from_spans(output_name);
+        self.wl_state.update_spans();
+    }
+
+    pub(crate) fn set_bloom_enabled(&mut self, output_name: &str, enabled: bool) {
+        self.wl_state
+            .update_output_settings(output_name, |settings| settings.bloom = enabled);
+        self.wl_state.wake_layers();
+    }
+
+    pub(crate) fn set_volume(&mut self, output_name: &str, volume: f32) {
+        self.wl_state
+            .update_output_settings(output_name, |settings| settings.volume = volume);
+    }
+
+    pub(crate) fn set_muted(&mut self, output_name: &str, muted: bool) {
+        self.wl_state
+            .update_output_settings(output_name, |settings| settings.muted = muted);
+    }
+
+    pub(crate) fn set_scaling(&mut self, output_name: &str, scaling: Scaling) {
+        self.wl_state
+            .update_output_settings(output_name, |settings| settings.scaling = scaling);
+        self.wl_state.wake_layers();
+    }
+
+    /// `output_name` set to `None` applies to every output
+    pub(crate) fn set_paused(&mut self, output_name: Option<&str>, paused: bool) {
+        self.wl_state
+            .update_playback(output_name, |playback| playback.paused = paused);
+    }
+
+    pub(crate) fn set_playback_rate(&mut self, output_name: Option<&str>, rate: f32) {
+        self.wl_state
+            .update_playback(output_name, |playback| playback.set_rate(rate));
+    }
+
+    pub(crate) fn set_loop_range(&mut self, output_name: Option<&str>, range: Option<(f64, f64)>) {
+        self.wl_state
+            .update_playback(output_name, |playback| playback.set_loop_range(range));
+    }
+
+    pub(crate) fn set_max_fps(&mut self, output_name: Option<&str>, fps: Option<u32>) {
+        let fps = fps.filter(|fps| *fps > 0);
+
+        for name in self.wl_state.target_outputs(output_name) {
+            self.wl_state
+                .update_output_settings(&name, |settings| settings.max_fps = fps);
+        }
+    }
+
+    pub(crate) fn set_layer_options(
+        &mut self,
+        output_name: Option<&str>,
+        layer: Option<ShellLayer>,
+        namespace: Option<String>,
+        margin: Option<(i32, i32, i32, i32)>,
+    ) {
+        for name in self.wl_state.target_outputs(output_name) {
+            self.wl_state.update_layer_options(&name, |options| {
+                if let Some(layer) = layer {
+                    options.layer = layer;
+                }
+                if let Some(namespace) = &namespace {
+                    options.namespace = namespace.clone();
+                }
+                if let Some(margin) = margin {
+                    options.margin = margin;
+                }
+            });
+        }
+    }
+
+    pub(crate) fn seek(&mut self, output_name: Option<&str>, position: f64) {
+        for name in self.wl_state.target_outputs(output_name) {
+            if let Some(layer) = self.wl_state.layers.get_mut(&name) {
+                layer.renderer.seek(position);
+            }
+        }
+
+        self.wl_state.wake_layers();
+    }
+
+    pub(crate) fn set_pointer_tracking(&mut self, mode: PointerTracking) {
+        self.wl_state.set_pointer_tracking(mode);
+    }
+
+    pub(crate) fn pointer_moved(&mut self, x: f64, y: f64) {
+        self.wl_state.pointer_moved(x, y);
+    }
+
+    pub(crate) fn status(&self) -> DaemonStatus {
+        let mut outputs: Vec<_> = self
+            .wl_state
+            .layers
+            .iter()
+            .map(|(name, layer)| OutputStatus {
+                name: name.clone(),
+                video_decoder: layer.renderer.video_decoder(),
+                video_queue: layer.renderer.video_queue(),
+                hidden: layer.hidden,
+            })
+            .collect();
+        outputs.sort_by(|a, b| a.name.cmp(&b.name));
+
+        DaemonStatus { outputs }
     }
 }
 
 pub struct WLState {
     pub connection: Rc<Connection>,
     pub(crate) egl_state: Rc<EGLState>,
+    scene_sources: SceneSources,
     pub(crate) queue_handle: QueueHandle<WLState>,
+    // Schedules the frames of layers that don't follow frame callbacks
+    event_loop: LoopHandle<'static, AppState>,
     registry_state: RegistryState,
     output_state: OutputState,
     seat_state: SeatState,
     compositor_state: CompositorState,
     layer_shell: LayerShell,
+    // Both are needed to render at fractional scales, integer scales are used otherwise
+    viewporter: Option<WpViewporter>,
+    fractional_scale_manager: Option<WpFractionalScaleManagerV1>,
+    // Tells when frames are shown, to align capped frame rates to the refresh cycle
+    presentation: Option<WpPresentation>,
+    // Clock of the presentation timestamps
+    presentation_clock: libc::clockid_t,
+    // Tell when outputs are turned off or covered by fullscreen windows, when available
+    output_power_manager: Option<ZwlrOutputPowerManagerV1>,
+    toplevel_manager: Option<ZwlrForeignToplevelManagerV1>,
+    toplevels: Toplevels,
+    // Applies to every output, from the power rules matching the battery and power profile
+    power_action: Option<PowerAction>,
+    pointer: Option<wl_pointer::WlPointer>,
+    pointer_tracking: PointerTracking,
+    config: Config,
+    video_sources: VideoSources,
+    // Kept by output name so settings also apply to layers created later
+    output_settings: HashMap<String, OutputSettings>,
+    layer_options: HashMap<String, LayerOptions>,
+    // Waiting for a GetEvents request, the oldest ones are dropped when nobody asks
+    events: VecDeque<DaemonEvent>,
+    // Waiting for their wallpaper to be restored
+    new_outputs: Vec<String>,
+    // Names of the outputs showing a wallpaper across them
+    spans: Vec<Vec<String>>,
 
     pub layers: HashMap<String, SimpleLayer>,
 }
 
+const MAX_PENDING_EVENTS: usize = 32;
+
+const VISIBILITY_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+// Frame callbacks are late by up to a frame, a few seconds without any means the surface is hidden
+const FRAME_CALLBACK_GRACE: Duration = Duration::from_secs(2);
+
 impl WLState {
     pub fn new(
         connection: Rc<Connection>,
         egl_state: Rc<EGLState>,
+        mixer: AudioMixer,
+        config: Config,
         globals: &GlobalList,
         queue_handle: QueueHandle<Self>,
+        event_loop: LoopHandle<'static, AppState>,
+        decoder_wakeup: Ping,
     ) -> Self {
+        let video_sources = VideoSources::new(
+            egl_state.clone(),
+            mixer.clone(),
+            &config.video,
+            decoder_wakeup,
+        );
+
         Self {
             connection,
             egl_state,
+            scene_sources: SceneSources::new(mixer),
             registry_state: RegistryState::new(globals),
             output_state: OutputState::new(globals, &queue_handle),
             seat_state: SeatState::new(globals, &queue_handle),
@@ -134,7 +464,29 @@ impl WLState {
                 .expect("wl_compositor is not available"),
             layer_shell: LayerShell::bind(globals, &queue_handle)
                 .expect("layer shell is not available"),
+            viewporter: globals.bind(&queue_handle, 1..=1, ()).ok(),
+            fractional_scale_manager: globals.bind(&queue_handle, 1..=1, ()).ok(),
+            presentation: globals.bind(&queue_handle, 1..=1, ()).ok(),
+            presentation_clock: libc::CLOCK_MONOTONIC,
+            output_power_manager: config
+                .watch_output_power
+                .then(|| globals.bind(&queue_handle, 1..=1, ()).ok())
+                .flatten(),
+            // Version 2 adds the fullscreen state
+            toplevel_manager: globals.bind(&queue_handle, 2..=3, ()).ok(),
+            toplevels: Toplevels::default(),
+            power_action: None,
+            pointer: None,
+            pointer_tracking: PointerTracking::default(),
+            video_sources,
+            spans: config.spans.clone(),
+            config,
+            output_settings: HashMap::new(),
+            layer_options: HashMap::new(),
+            events: VecDeque::new(),
+            new_outputs: Vec::new(),
             queue_handle,
+            event_loop,
 
             layers: HashMap::new(),
         }
@@ -146,26 +498,46 @@ impl WLState {
             .create_surface(&self.queue_handle)
             .into();
 
-        let output_size = output.1.logical_size.unwrap();
+        let options = self.layer_options(output.1.name.as_ref().unwrap()).clone();
+        let (top, right, bottom, left) = options.margin;
+        let output_size = inner_size(output.1.logical_size.unwrap(), options.margin);
 
         let layer = self.layer_shell.create_layer_surface(
             &self.queue_handle,
             surface,
-            Layer::Background,
-            Some("waypaper_engine"),
+            shell_layer(options.layer),
+            Some(options.namespace),
             Some(output.0),
         );
 
         layer.set_exclusive_zone(-1); // -1 means we don't want our surface to be moved to accommodate for other surfaces
         layer.set_anchor(Anchor::BOTTOM | Anchor::TOP | Anchor::LEFT | Anchor::RIGHT); // All anchors means centered on screen
-        layer.set_size(output_size.0 as u32, output_size.1 as u32); // We ask for the full size of the screen
+        layer.set_margin(top, right, bottom, left);
+        layer.set_size(output_size.0, output_size.1); // We ask for the full size of the screen, minus the margins
         layer.set_keyboard_interactivity(KeyboardInteractivity::None); // No keyboard grabbing at all
+        self.update_input_region(layer.wl_surface(), options.layer);
+
+        let viewport = self
+            .viewporter
+            .as_ref()
+            .map(|viewporter| viewporter.get_viewport(layer.wl_surface(), &self.queue_handle, ()));
+        let fractional_scale = self
+            .fractional_scale_manager
+            .as_ref()
+            .filter(|_| viewport.is_some())
+            .map(|manager| {
+                manager.get_fractional_scale(layer.wl_surface(), &self.queue_handle, ())
+            });
 
         layer.commit();
         self.connection.roundtrip().unwrap(); // Block until the wayland server has processed everything
 
-        let wl_egl_surface =
-            WlEglSurface::new(layer.wl_surface().id(), output_size.0, output_size.1).unwrap();
+        let wl_egl_surface = WlEglSurface::new(
+            layer.wl_surface().id(),
+            output_size.0 as i32,
+            output_size.1 as i32,
+        )
+        .unwrap();
 
         let egl_window_surface = unsafe {
             self.egl_state.egl.create_platform_window_surface(
@@ -180,35 +552,382 @@ impl WLState {
         layer.commit();
         self.connection.roundtrip().unwrap();
 
+        let settings = *self.output_settings(output.1.name.as_ref().unwrap());
+        let output_power = self
+            .output_power_manager
+            .as_ref()
+            .map(|manager| manager.get_output_power(output.0, &self.queue_handle, ()));
+        let visibility = Visibility {
+            fullscreen: self.toplevels.covers(output.0),
+            ..Visibility::default()
+        };
+
         let layer = SimpleLayer {
-            exit: false,
             first_configure: true,
-            width: output_size.0 as u32,
-            height: output_size.1 as u32,
+            frame_pending: false,
+            width: output_size.0,
+            height: output_size.1,
+            scale: 120,
+            viewport,
+            fractional_scale,
             layer,
             //keyboard: None,
             //keyboard_focus: false,
             //pointer: None,
             egl_state: self.egl_state.clone(),
-            _wl_egl_surface: wl_egl_surface,
+            wl_egl_surface,
             egl_window_surface,
+            drawn_size: (0, 0),
+            last_draw: Instant::now(),
             output: (output.0.clone(), output.1.clone()),
-
-            renderer: WPRenderer::new(self.connection.clone(), self.egl_state.clone()),
+            margin: options.margin,
+            output_power,
+            visibility,
+            hidden: false,
+            power_action: self.power_action,
+            event_loop: self.event_loop.clone(),
+            presentation: self.presentation.clone(),
+            draws: 0,
+            draw_scheduled: false,
+            last_presented: None,
+            presented_refresh: None,
+
+            renderer: WPRenderer::new(
+                self.connection.clone(),
+                self.egl_state.clone(),
+                self.scene_sources.clone(),
+                settings,
+                self.video_sources.clone(),
+            ),
             fps_counter: FPSCounter::new(),
             wallpaper: None,
         };
 
         self.layers
             .insert(output.1.name.as_ref().unwrap().clone(), layer);
-        self.layers
+        let layer = self
+            .layers
             .get_mut(output.1.name.as_ref().unwrap())
-            .unwrap()
+            .unwrap();
+        layer.apply_visibility();
+        layer
+    }
+
+    /// Wallpapers only take pointer input under windows, on the top and overlay layers they would
+    /// take every click away from them
+    fn update_input_region(&self, surface: &WlSurface, layer: ShellLayer) {
+        let below_windows = matches!(layer, ShellLayer::Background | ShellLayer::Bottom);
+
+        if self.pointer_tracking == PointerTracking::Surface && below_windows {
+            surface.set_input_region(None); // No input region means the whole surface
+        } else {
+            // An empty input region lets every pointer event through to what's below the wallpaper
+            let region =
+                Region::new(&self.compositor_state).expect("wl_compositor is not available");
+            surface.set_input_region(Some(region.wl_region()));
+        }
+    }
+
+    pub fn update_output_settings(
+        &mut self,
+        output_name: &str,
+        update: impl FnOnce(&mut OutputSettings),
+    ) {
+        let settings = self.output_settings(output_name);
+        update(settings);
+        let settings = *settings;
+
+        if let Some(layer) = self.layers.get_mut(output_name) {
+            layer.renderer.set_output_settings(settings);
+        }
+    }
+
+    fn update_playback(&mut self, output_name: Option<&str>, update: impl Fn(&mut Playback)) {
+        for name in self.target_outputs(output_name) {
+            self.update_output_settings(&name, |settings| update(&mut settings.playback));
+        }
+
+        // Only once every output is updated, as outputs sharing a video compare their playback
+        self.wake_layers();
+    }
+
+    /// `output_name`, or every output with a wallpaper when `None`
+    fn target_outputs(&self, output_name: Option<&str>) -> Vec<String> {
+        match output_name {
+            Some(name) => vec![name.to_string()],
+            None => self.layers.keys().cloned().collect(),
+        }
+    }
+
+    /// Draws the layers waiting for no frame callback, paused layers stopped drawing and have to
+    /// be woken up to show changes
+    fn wake_layers(&mut self) {
+        for layer in self.layers.values_mut() {
+            layer.wake(&self.queue_handle);
+        }
+    }
+
+    /// Marks the layers whose frame callbacks stopped coming as hidden, and draws the hidden layers
+    /// that are slowed down instead of paused
+    fn check_visibility(&mut self) {
+        for layer in self.layers.values_mut() {
+            if layer.frame_pending
+                && !layer.visibility.no_frame_callbacks
+                && layer.last_draw.elapsed() > FRAME_CALLBACK_GRACE
+            {
+                layer.visibility.no_frame_callbacks = true;
+                layer.apply_visibility();
+            }
+
+            if layer.hidden && layer.renderer.settings().occlusion == OcclusionPolicy::SlowDown {
+                layer.wake(&self.queue_handle);
+            }
+        }
+    }
+
+    fn set_power_state(&mut self, state: &PowerState) {
+        let action = power::select_action(&self.config.power.rules, state);
+        if action == self.power_action {
+            return;
+        }
+
+        tracing::info!("Power policy set to {action:?}");
+        self.power_action = action;
+
+        for layer in self.layers.values_mut() {
+            layer.power_action = action;
+            layer.renderer.set_suspension(layer.suspension());
+            // Resumes, or draws the static frame
+            layer.wake(&self.queue_handle);
+        }
+    }
+
+    /// Hides the layers of the outputs a fullscreen window is shown on
+    fn refresh_fullscreen(&mut self) {
+        for layer in self.layers.values_mut() {
+            layer.visibility.fullscreen = self.toplevels.covers(&layer.output.0);
+
+            if layer.apply_visibility() {
+                layer.wake(&self.queue_handle);
+            }
+        }
+    }
+
+    fn output_settings(&mut self, output_name: &str) -> &mut OutputSettings {
+        if !self.output_settings.contains_key(output_name) {
+            let settings = self
+                .config
+                .output_settings(&self.output_identity(output_name));
+            self.output_settings
+                .insert(output_name.to_string(), settings);
+        }
+
+        self.output_settings.get_mut(output_name).unwrap()
+    }
+
+    fn layer_options(&mut self, output_name: &str) -> &mut LayerOptions {
+        if !self.layer_options.contains_key(output_name) {
+            let options = self
+                .config
+                .layer_options(&self.output_identity(output_name));
+            self.layer_options.insert(output_name.to_string(), options);
+        }
+
+        self.layer_options.get_mut(output_name).unwrap()
+    }
+
+    fn update_layer_options(&mut self, output_name: &str, update: impl FnOnce(&mut LayerOptions)) {
+        let options = self.layer_options(output_name);
+        let previous = options.clone();
+        update(options);
+        let options = options.clone();
+
+        if options == previous {
+            return;
+        }
+        tracing::info!("Layer of output {output_name} set to {options:?}");
+
+        let Some(layer) = self.layers.get_mut(output_name) else {
+            return;
+        };
+
+        // Layers can only be moved since version 2 of layer-shell, and keep their namespace
+        let can_move = match layer.layer.kind() {
+            SurfaceKind::Wlr(surface) => surface.version() >= 2,
+            _ => false,
+        };
+
+        if options.namespace != previous.namespace || (options.layer != previous.layer && !can_move)
+        {
+            let output = layer.output.clone();
+            let wallpaper = layer.wallpaper.take();
+            self.remove_layer(output_name);
+
+            let qh = self.queue_handle.clone();
+            let layer = self.setup_layer((&output.0, &output.1));
+            if let Some(wallpaper) = wallpaper {
+                layer.set_wallpaper(wallpaper, None);
+                layer.wake(&qh);
+            }
+            self.update_spans();
+        } else {
+            let surface = layer.layer.wl_surface().clone();
+            self.update_input_region(&surface, options.layer);
+
+            let layer = self.layers.get_mut(output_name).unwrap();
+            let (top, right, bottom, left) = options.margin;
+            layer.margin = options.margin;
+            layer.layer.set_layer(shell_layer(options.layer));
+            layer.layer.set_margin(top, right, bottom, left);
+            // The compositor answers with a configure of the new size
+            layer.request_size();
+            layer.layer.commit();
+        }
+    }
+
+    fn output_identity(&self, output_name: &str) -> OutputIdentity {
+        self.output_state
+            .outputs()
+            .filter_map(|output| self.output_state.info(&output))
+            .find(|info| info.name.as_deref() == Some(output_name))
+            .map(|info| OutputIdentity::from(&info))
+            .unwrap_or_else(|| OutputIdentity::from_name(output_name))
+    }
+
+    fn push_event(&mut self, event: DaemonEvent) {
+        self.events.push_back(event);
+
+        while self.events.len() > MAX_PENDING_EVENTS {
+            self.events.pop_front();
+        }
+    }
+
+    fn remove_from_spans(&mut self, output_name: &str) {
+        for span in &mut self.spans {
+            span.retain(|name| name != output_name);
+        }
+        self.spans.retain(|span| span.len() > 1);
+    }
+
+    /// Gives the layers of each span their part of it, from the position of the outputs of the
+    /// span showing a wallpaper
+    fn update_spans(&mut self) {
+        let mut spans = HashMap::new();
+
+        for names in &self.spans {
+            let outputs: Vec<_> = names
+                .iter()
+                .filter_map(|name| {
+                    let layer = self.layers.get(name)?;
+                    Some(SpanOutput {
+                        name: name.clone(),
+                        position: layer.output.1.logical_position?,
+                        size: layer.output.1.logical_size?,
+                        bezel: layer.renderer.settings().bezel,
+                    })
+                })
+                .collect();
+
+            spans.extend(span::layout(&outputs));
+        }
+
+        for (name, layer) in &mut self.layers {
+            let span = spans.get(name).copied();
+            if span != layer.renderer.span() {
+                layer.renderer.set_span(span);
+                layer.wake(&self.queue_handle);
+            }
+        }
+    }
+
+    /// Tears down the layer of an output, along with its wallpaper
+    fn remove_layer(&mut self, output_name: &str) {
+        if self.layers.remove(output_name).is_some() {
+            tracing::info!("Removed the layer of output {output_name}");
+        }
+    }
+
+    pub fn set_pointer_tracking(&mut self, mode: PointerTracking) {
+        tracing::info!("Pointer tracking set to {mode:?}");
+        self.pointer_tracking = mode;
+
+        for (output_name, layer) in &self.layers {
+            let shell_layer = self
+                .layer_options
+                .get(output_name)
+                .map_or(ShellLayer::default(), |options| options.layer);
+            self.update_input_region(layer.layer.wl_surface(), shell_layer);
+            layer.layer.commit();
+        }
+
+        if mode == PointerTracking::Surface
+            && self
+                .layer_options
+                .values()
+                .any(|options| matches!(options.layer, ShellLayer::Top | ShellLayer::Overlay))
+        {
+            tracing::warn!(
+                "Wallpapers above windows don't track the pointer, use external tracking instead"
+            );
+        }
+
+        for layer in self.layers.values_mut() {
+            layer.set_pointer_position(None);
+        }
+        self.wake_moving_layers();
+    }
+
+    /// Dispatches a cursor position given in the compositor global space to the layer of the
+    /// output it is on
+    pub fn pointer_moved(&mut self, x: f64, y: f64) {
+        if self.pointer_tracking != PointerTracking::External {
+            return;
+        }
+
+        for layer in self.layers.values_mut() {
+            let (Some((output_x, output_y)), Some((width, height))) =
+                (layer.output.1.logical_position, layer.output.1.logical_size)
+            else {
+                continue;
+            };
+
+            let (top, right, bottom, left) = layer.margin;
+            let position = (x - (output_x + left) as f64, y - (output_y + top) as f64);
+            let inside = (0.0..(width - left - right) as f64).contains(&position.0)
+                && (0.0..(height - top - bottom) as f64).contains(&position.1);
+
+            layer.set_pointer_position(inside.then_some(position));
+        }
+        self.wake_moving_layers();
+    }
+
+    /// Scenes at rest start moving again with the parallax, on every output showing them
+    fn wake_moving_layers(&mut self) {
+        for layer in self.layers.values_mut() {
+            if layer.renderer.needs_redraw() {
+                layer.wake(&self.queue_handle);
+            }
+        }
     }
 }
 
 impl Drop for SimpleLayer {
     fn drop(&mut self) {
+        // GL resources of the wallpaper are freed while the context is current
+        self.egl_state.attach_context(self.egl_window_surface);
+        self.renderer.release();
+        self.egl_state.detach_context();
+
+        if let Some(fractional_scale) = &self.fractional_scale {
+            fractional_scale.destroy();
+        }
+        if let Some(viewport) = &self.viewport {
+            viewport.destroy();
+        }
+        if let Some(output_power) = &self.output_power {
+            output_power.destroy();
+        }
+
         self.egl_state
             .egl
             .destroy_surface(self.egl_state.egl_display, self.egl_window_surface)
@@ -253,19 +972,46 @@ impl DerefMut for OutputsList {
 }
 
 pub struct SimpleLayer {
-    exit: bool,
     first_configure: bool,
+    // A frame callback was requested and not received yet
+    frame_pending: bool,
+    // Surface size, in logical pixels
     width: u32,
     height: u32,
+    // Preferred scale of the output, in 120ths
+    scale: u32,
+    // Scales buffers down to the surface size, buffers are scaled by wl_surface otherwise
+    viewport: Option<WpViewport>,
+    fractional_scale: Option<WpFractionalScaleV1>,
     layer: LayerSurface,
     //keyboard: Option<wl_keyboard::WlKeyboard>,
     //keyboard_focus: bool,
     //pointer: Option<wl_pointer::WlPointer>,
     //mpv_renderer: MpvRenderer,
     egl_state: Rc<EGLState>,
-    _wl_egl_surface: WlEglSurface,
+    wl_egl_surface: WlEglSurface,
     egl_window_surface: khronos_egl::Surface,
+    // Buffer size the renderer last drew at
+    drawn_size: (u32, u32),
+    last_draw: Instant,
     output: (WlOutput, OutputInfo),
+    // Space left around the layer on its output, in logical pixels
+    margin: (i32, i32, i32, i32),
+    output_power: Option<ZwlrOutputPowerV1>,
+    visibility: Visibility,
+    // Visibility last applied to the renderer
+    hidden: bool,
+    power_action: Option<PowerAction>,
+    event_loop: LoopHandle<'static, AppState>,
+    presentation: Option<WpPresentation>,
+    // Frames drawn since the layer was created, a scheduled draw is dropped when it changed
+    draws: u64,
+    // Waiting for a timer to draw the next frame, to keep under a frame rate cap
+    draw_scheduled: bool,
+    // From presentation feedback, when the last frame was shown and the refresh cycle it was
+    // shown at
+    last_presented: Option<Instant>,
+    presented_refresh: Option<Duration>,
 
     renderer: WPRenderer,
     wallpaper: Option<Wallpaper>,
@@ -276,10 +1022,19 @@ impl CompositorHandler for WLState {
     fn scale_factor_changed(
         &mut self,
         _conn: &Connection,
-        _qh: &QueueHandle<Self>,
-        _surface: &wl_surface::WlSurface,
-        _new_factor: i32,
+        qh: &QueueHandle<Self>,
+        surface: &wl_surface::WlSurface,
+        new_factor: i32,
     ) {
+        // Fractional scales are more precise, when the compositor sends them
+        if let Some(layer) = self
+            .layers
+            .values_mut()
+            .find(|layer| layer.layer.wl_surface() == surface)
+            && layer.fractional_scale.is_none()
+        {
+            layer.set_scale(new_factor.max(1) as u32 * 120, qh);
+        }
     }
 
     fn transform_changed(
@@ -289,6 +1044,8 @@ impl CompositorHandler for WLState {
         _surface: &wl_surface::WlSurface,
         _new_transform: wl_output::Transform,
     ) {
+        // Buffers are left for the compositor to rotate, the rotated size of the output is handled
+        // by update_output
     }
 
     fn frame(
@@ -298,12 +1055,26 @@ impl CompositorHandler for WLState {
         surface: &wl_surface::WlSurface,
         _time: u32,
     ) {
-        if let Some(layer) = self
+        let Some(layer) = self
             .layers
             .values_mut()
             .find(|layer| layer.layer.wl_surface() == surface)
-        {
-            layer.draw(qh);
+        else {
+            return;
+        };
+
+        layer.frame_pending = false;
+        if layer.visibility.no_frame_callbacks {
+            layer.visibility.no_frame_callbacks = false;
+            layer.apply_visibility();
+        }
+
+        match layer.next_frame_delay() {
+            Some(delay) => {
+                layer.draw_scheduled = true;
+                layer.schedule_draw(delay);
+            }
+            None => layer.draw(qh),
         }
     }
 
@@ -331,29 +1102,84 @@ impl OutputHandler for WLState {
         &mut self.output_state
     }
 
-    fn new_output(&mut self, _conn: &Connection, _qh: &QueueHandle<Self>, _output: WlOutput) {}
+    fn new_output(&mut self, _conn: &Connection, _qh: &QueueHandle<Self>, output: WlOutput) {
+        let Some(name) = self.output_state.info(&output).and_then(|info| info.name) else {
+            tracing::warn!("Ignoring output without a name");
+            return;
+        };
 
-    fn update_output(&mut self, _conn: &Connection, _qh: &QueueHandle<Self>, _output: WlOutput) {
-        // TODO resize wallpaper if output size or scale has changed
+        tracing::info!("Output {name} connected");
+        self.push_event(DaemonEvent::OutputConnected {
+            screen: name.clone(),
+        });
+        self.new_outputs.push(name);
     }
 
-    fn output_destroyed(&mut self, _conn: &Connection, _qh: &QueueHandle<Self>, output: WlOutput) {
-        if let Some(layer) = self.layers.values().find(|layer| layer.output.0 == output) {
-            // TODO : test this
-            self.layers
-                .remove(&layer.output.1.name.clone().unwrap())
-                .unwrap();
+    fn update_output(&mut self, _conn: &Connection, _qh: &QueueHandle<Self>, output: WlOutput) {
+        let Some(info) = self.output_state.info(&output) else {
+            return;
+        };
+        let Some(layer) = self
+            .layers
+            .values_mut()
+            .find(|layer| layer.output.0 == output)
+        else {
+            return;
+        };
+
+        let previous_size = layer.output.1.logical_size;
+        layer.output.1 = info;
+
+        // Mode changes and rotations change the logical size, scale changes are handled per surface
+        if let Some((width, height)) = layer.output.1.logical_size
+            && Some((width, height)) != previous_size
+        {
+            tracing::info!(
+                "Output {} resized to {width}x{height}",
+                layer.output.1.name.as_ref().unwrap()
+            );
+
+            // The compositor answers with a configure of the new size
+            layer.request_size();
+            layer.layer.commit();
         }
+
+        // The output may have moved within its span
+        self.update_spans();
+    }
+
+    fn output_destroyed(&mut self, _conn: &Connection, _qh: &QueueHandle<Self>, output: WlOutput) {
+        let name = self
+            .layers
+            .iter()
+            .find(|(_, layer)| layer.output.0 == output)
+            .map(|(name, _)| name.clone())
+            .or_else(|| self.output_state.info(&output).and_then(|info| info.name));
+        let Some(name) = name else {
+            return;
+        };
+
+        tracing::info!("Output {name} disconnected");
+        self.remove_layer(&name);
+        self.update_spans();
+        self.new_outputs.retain(|new_output| *new_output != name);
+        self.push_event(DaemonEvent::OutputDisconnected { screen: name });
     }
 }
 
 impl LayerShellHandler for WLState {
     fn closed(&mut self, _conn: &Connection, _qh: &QueueHandle<Self>, layer: &LayerSurface) {
-        self.layers
-            .values_mut()
-            .find(|l| l.layer == *layer)
-            .unwrap()
-            .exit = true;
+        // Like when the output is disabled, a new layer is created if a wallpaper is set again
+        let name = self
+            .layers
+            .iter()
+            .find(|(_, l)| l.layer == *layer)
+            .map(|(name, _)| name.clone());
+
+        if let Some(name) = name {
+            tracing::info!("Compositor closed the layer of output {name}");
+            self.remove_layer(&name);
+        }
     }
 
     fn configure(
@@ -371,6 +1197,7 @@ impl LayerShellHandler for WLState {
             .values_mut()
             .find(|l| l.layer == *layer_surface)
             .unwrap();
+        let previous_size = (layer.width, layer.height);
 
         // Size equal to zero means the compositor let us choose
 
@@ -382,10 +1209,15 @@ impl LayerShellHandler for WLState {
             layer.height = configure.new_size.1;
         }
 
+        layer.resize_buffer();
+
         // Initiate the first draw.
         if layer.first_configure {
             layer.first_configure = false;
             layer.draw(qh);
+        } else if (layer.width, layer.height) != previous_size {
+            // Paused wallpapers are drawn again at the new size
+            layer.wake(qh);
         }
     }
 }
@@ -400,22 +1232,26 @@ impl SeatHandler for WLState {
     fn new_capability(
         &mut self,
         _conn: &Connection,
-        _qh: &QueueHandle<Self>,
-        _seat: wl_seat::WlSeat,
-        _capability: Capability,
+        qh: &QueueHandle<Self>,
+        seat: wl_seat::WlSeat,
+        capability: Capability,
     ) {
         /*if capability == Capability::Keyboard && self.keyboard.is_none() {
             tracing::debug!("Set keyboard capability");
             let keyboard =
                 self.seat_state.get_keyboard(qh, &seat, None).expect("Failed to create keyboard");
             self.keyboard = Some(keyboard);
-        }
+        }*/
 
+        // The pointer position drives the camera parallax of scene wallpapers
         if capability == Capability::Pointer && self.pointer.is_none() {
             tracing::debug!("Set pointer capability");
-            let pointer = self.seat_state.get_pointer(qh, &seat).expect("Failed to create pointer");
+            let pointer = self
+                .seat_state
+                .get_pointer(qh, &seat)
+                .expect("Failed to create pointer");
             self.pointer = Some(pointer);
-        }*/
+        }
     }
 
     fn remove_capability(
@@ -423,39 +1259,257 @@ impl SeatHandler for WLState {
         _conn: &Connection,
         _: &QueueHandle<Self>,
         _: wl_seat::WlSeat,
-        _capability: Capability,
+        capability: Capability,
     ) {
         /*if capability == Capability::Keyboard && self.keyboard.is_some() {
             tracing::debug!("Unset keyboard capability");
             self.keyboard.take().unwrap().release();
-        }
+        }*/
 
         if capability == Capability::Pointer && self.pointer.is_some() {
             tracing::debug!("Unset pointer capability");
             self.pointer.take().unwrap().release();
-        }*/
+        }
     }
 
     fn remove_seat(&mut self, _: &Connection, _: &QueueHandle<Self>, _: wl_seat::WlSeat) {}
 }
 
+impl PointerHandler for WLState {
+    fn pointer_frame(
+        &mut self,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+        _pointer: &wl_pointer::WlPointer,
+        events: &[PointerEvent],
+    ) {
+        if self.pointer_tracking != PointerTracking::Surface {
+            return;
+        }
+
+        for event in events {
+            let Some(layer) = self
+                .layers
+                .values_mut()
+                .find(|layer| layer.layer.wl_surface() == &event.surface)
+            else {
+                continue;
+            };
+
+            match event.kind {
+                PointerEventKind::Enter { .. } | PointerEventKind::Motion { .. } => {
+                    layer.set_pointer_position(Some(event.position));
+                }
+                PointerEventKind::Leave { .. } => layer.set_pointer_position(None),
+                _ => {}
+            }
+        }
+        self.wake_moving_layers();
+    }
+}
+
 impl SimpleLayer {
-    pub fn set_wallpaper(&mut self, wp: Wallpaper) {
+    pub fn set_wallpaper(&mut self, wp: Wallpaper, transition: Option<Transition>) {
+        let transition = transition.unwrap_or(self.renderer.settings().transition);
+
+        self.egl_state.attach_context(self.egl_window_surface);
+        // The current wallpaper stays on screen until the new one is ready, it stops moving while
+        // the new one loads on the first frame
+        self.renderer.start_transition(transition);
+        self.egl_state.detach_context();
+
         self.renderer.setup_for(&wp);
+        self.renderer.set_suspension(self.suspension());
         self.wallpaper = Some(wp);
+        // The renderer may have been replaced
+        self.drawn_size = (0, 0);
 
         self.egl_state.attach_context(self.egl_window_surface);
         self.renderer.init_render();
         self.egl_state.detach_context();
     }
 
+    /// Forwards the pointer position on this output, normalized to [0, 1], to the renderer
+    pub fn set_pointer_position(&mut self, position: Option<(f64, f64)>) {
+        let (width, height) = (self.width.max(1) as f64, self.height.max(1) as f64);
+
+        self.renderer
+            .set_pointer_position(position.map(|(x, y)| ((x / width) as f32, (y / height) as f32)));
+    }
+
+    /// Applies a change of visibility, returns true when the layer just became visible again
+    fn apply_visibility(&mut self) -> bool {
+        let hidden = self.visibility.is_hidden();
+        if hidden == self.hidden {
+            return false;
+        }
+
+        self.hidden = hidden;
+        tracing::info!(
+            "Output {} is {} ({:?})",
+            self.output.1.name.as_ref().unwrap(),
+            if hidden { "hidden" } else { "visible" },
+            self.visibility
+        );
+        self.renderer.set_suspension(self.suspension());
+
+        !hidden
+    }
+
+    fn suspension(&self) -> Suspension {
+        match self.power_action {
+            Some(PowerAction::StaticFrame) => Suspension::StaticFrame,
+            Some(PowerAction::Pause) => Suspension::Paused,
+            _ if self.hidden && self.renderer.settings().occlusion == OcclusionPolicy::Pause => {
+                Suspension::Paused
+            }
+            _ => Suspension::Running,
+        }
+    }
+
+    /// The lowest of the cap of the output and the one of the power rules
+    fn max_fps(&self) -> Option<u32> {
+        let power_cap = match self.power_action {
+            Some(PowerAction::MaxFps(fps)) => Some(fps),
+            _ => None,
+        };
+
+        [self.renderer.settings().max_fps, power_cap]
+            .into_iter()
+            .flatten()
+            .min()
+            .map(|fps| fps.max(1))
+    }
+
+    /// Duration of a refresh cycle, measured by presentation feedback or from the output mode
+    fn refresh_interval(&self) -> Option<Duration> {
+        self.presented_refresh.or_else(|| {
+            let mode = self.output.1.modes.iter().find(|mode| mode.current)?;
+            (mode.refresh_rate > 0)
+                .then(|| Duration::from_secs_f64(1000.0 / mode.refresh_rate as f64))
+        })
+    }
+
+    /// Time left before the next frame may be drawn, when the frame rate is capped
+    fn next_frame_delay(&self) -> Option<Duration> {
+        let interval = Duration::from_secs_f64(1.0 / self.max_fps()? as f64);
+
+        let Some(refresh) = self.refresh_interval() else {
+            return interval
+                .checked_sub(self.last_draw.elapsed())
+                .filter(|delay| !delay.is_zero());
+        };
+
+        // Frames stay on screen for a whole number of refresh cycles, so they are evenly paced
+        let ratio = interval.as_secs_f64() / refresh.as_secs_f64();
+        let cycles = (ratio - 0.01).ceil().max(1.0) as u32;
+        if cycles == 1 {
+            return None;
+        }
+
+        // The frame callback comes in around when the last frame was shown, it is late when the
+        // feedback is from an older frame
+        let shown = self
+            .last_presented
+            .filter(|presented| *presented >= self.last_draw)
+            .unwrap_or(self.last_draw);
+        // Committed half a cycle early to make it to the targeted refresh
+        let deadline = shown + refresh * cycles - refresh / 2;
+
+        deadline
+            .checked_duration_since(Instant::now())
+            .filter(|delay| !delay.is_zero())
+    }
+
+    /// Draws the layer after `delay`, unless it is drawn before
+    fn schedule_draw(&mut self, delay: Duration) {
+        let output_name = self.output.1.name.clone().unwrap();
+        let draws = self.draws;
+
+        self.event_loop
+            .insert_source(Timer::from_duration(delay), move |_, _, app| {
+                let wl_state = &mut app.rendering_context.wl_state;
+                if let Some(layer) = wl_state.layers.get_mut(&output_name)
+                    && layer.draws == draws
+                {
+                    layer.draw(&wl_state.queue_handle);
+                }
+                TimeoutAction::Drop
+            })
+            .unwrap();
+    }
+
+    /// Size of the buffers drawn to, in physical pixels
+    fn buffer_size(&self) -> (u32, u32) {
+        // Rounded half away from zero, as the fractional scale protocol asks
+        let scale = |size: u32| ((size as u64 * self.scale as u64 + 60) / 120) as u32;
+        (scale(self.width), scale(self.height))
+    }
+
+    /// Renders at `scale`, in 120ths, from the next frame on
+    fn set_scale(&mut self, scale: u32, qh: &QueueHandle<WLState>) {
+        if scale == self.scale {
+            return;
+        }
+
+        tracing::info!(
+            "Output {} scale set to {}",
+            self.output.1.name.as_ref().unwrap(),
+            scale as f64 / 120.0
+        );
+        self.scale = scale;
+
+        if self.viewport.is_none() {
+            // Only integer scales can be applied without a viewport
+            self.layer
+                .wl_surface()
+                .set_buffer_scale((scale / 120) as i32);
+        }
+
+        self.resize_buffer();
+        self.wake(qh);
+    }
+
+    /// Asks for the size of the output minus the margins
+    fn request_size(&self) {
+        if let Some(output_size) = self.output.1.logical_size {
+            let (width, height) = inner_size(output_size, self.margin);
+            self.layer.set_size(width, height);
+        }
+    }
+
+    /// Matches the EGL surface to the size and scale of the layer
+    fn resize_buffer(&mut self) {
+        let (width, height) = self.buffer_size();
+        self.wl_egl_surface
+            .resize(width as i32, height as i32, 0, 0);
+
+        if let Some(viewport) = &self.viewport {
+            viewport.set_destination(self.width as i32, self.height as i32);
+        }
+    }
+
+    /// Draws a frame if the layer stopped drawing, to resume playback or show a seek
+    pub fn wake(&mut self, qh: &QueueHandle<WLState>) {
+        if !self.first_configure && !self.frame_pending && !self.draw_scheduled {
+            self.draw(qh);
+        }
+    }
+
     pub fn draw(&mut self, qh: &QueueHandle<WLState>) {
-        let width = self.width;
-        let height = self.height;
+        let (width, height) = self.buffer_size();
+        self.last_draw = Instant::now();
+        self.draws += 1;
+        self.draw_scheduled = false;
 
         // Attach the egl context to the current surface
         self.egl_state.attach_context(self.egl_window_surface);
 
+        if self.drawn_size != (width, height) {
+            self.renderer.resize(width, height);
+            self.drawn_size = (width, height);
+        }
+
         // Draw to the window:
         {
             unsafe {
@@ -464,7 +1518,7 @@ impl SimpleLayer {
                 gl::ClearColor(clear_color.0, clear_color.1, clear_color.2, 1.0);
                 gl::Clear(COLOR_BUFFER_BIT);
 
-                self.renderer.render(self.width, self.height);
+                self.renderer.render(width, height);
             }
         }
 
@@ -475,6 +1529,12 @@ impl SimpleLayer {
             i32::try_from(width).unwrap(),
             i32::try_from(height).unwrap(),
         );
+        if let Some(presentation) = &self.presentation
+            && self.max_fps().is_some()
+        {
+            let output_name = self.output.1.name.clone().unwrap();
+            presentation.feedback(self.layer.wl_surface(), qh, output_name);
+        }
         self.egl_state
             .egl
             .swap_buffers(self.egl_state.egl_display, self.egl_window_surface)
@@ -483,10 +1543,18 @@ impl SimpleLayer {
         // Now that buffers are swapped we can reset the egl context
         self.egl_state.detach_context();
 
-        // Request our next frame
-        self.layer
-            .wl_surface()
-            .frame(qh, self.layer.wl_surface().clone());
+        // Request our next frame, unless nothing will change like when the wallpaper is paused.
+        // Hidden layers stop, or are drawn by check_visibility when slowed down.
+        let keeps_running = self.suspension() == Suspension::Running
+            && (!self.hidden || self.renderer.settings().occlusion == OcclusionPolicy::KeepRunning);
+        if keeps_running && self.renderer.needs_redraw() {
+            self.layer
+                .wl_surface()
+                .frame(qh, self.layer.wl_surface().clone());
+            self.frame_pending = true;
+        } else if keeps_running && let Some(delay) = self.renderer.next_change() {
+            self.schedule_draw(delay);
+        }
 
         // Commit to present.
         self.layer.commit();
@@ -503,9 +1571,191 @@ impl SimpleLayer {
 delegate_compositor!(WLState);
 delegate_output!(WLState);
 delegate_seat!(WLState);
+delegate_pointer!(WLState);
 delegate_layer!(WLState);
 delegate_registry!(WLState);
 
+delegate_noop!(WLState: WpViewporter);
+delegate_noop!(WLState: WpViewport);
+delegate_noop!(WLState: WpFractionalScaleManagerV1);
+delegate_noop!(WLState: ZwlrOutputPowerManagerV1);
+
+impl Dispatch<WpFractionalScaleV1, ()> for WLState {
+    fn event(
+        state: &mut Self,
+        proxy: &WpFractionalScaleV1,
+        event: wp_fractional_scale_v1::Event,
+        _data: &(),
+        _conn: &Connection,
+        qh: &QueueHandle<Self>,
+    ) {
+        if let wp_fractional_scale_v1::Event::PreferredScale { scale } = event
+            && let Some(layer) = state
+                .layers
+                .values_mut()
+                .find(|layer| layer.fractional_scale.as_ref() == Some(proxy))
+        {
+            layer.set_scale(scale, qh);
+        }
+    }
+}
+
+impl Dispatch<WpPresentation, ()> for WLState {
+    fn event(
+        state: &mut Self,
+        _proxy: &WpPresentation,
+        event: wp_presentation::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+        if let wp_presentation::Event::ClockId { clk_id } = event {
+            state.presentation_clock = clk_id as libc::clockid_t;
+        }
+    }
+}
+
+impl Dispatch<WpPresentationFeedback, String> for WLState {
+    fn event(
+        state: &mut Self,
+        _proxy: &WpPresentationFeedback,
+        event: wp_presentation_feedback::Event,
+        output_name: &String,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+        let wp_presentation_feedback::Event::Presented {
+            tv_sec_hi,
+            tv_sec_lo,
+            tv_nsec,
+            refresh,
+            ..
+        } = event
+        else {
+            return;
+        };
+        let Some(layer) = state.layers.get_mut(output_name) else {
+            return;
+        };
+
+        let presented = Duration::new((tv_sec_hi as u64) << 32 | tv_sec_lo as u64, tv_nsec);
+        let age = clock_time(state.presentation_clock).saturating_sub(presented);
+        layer.last_presented = Instant::now().checked_sub(age);
+        // Zero when the output has no constant refresh rate
+        layer.presented_refresh = (refresh > 0).then(|| Duration::from_nanos(refresh as u64));
+    }
+}
+
+fn clock_time(clock: libc::clockid_t) -> Duration {
+    let mut time = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe {
+        libc::clock_gettime(clock, &mut time);
+    }
+
+    Duration::new(time.tv_sec as u64, time.tv_nsec as u32)
+}
+
+impl Dispatch<ZwlrOutputPowerV1, ()> for WLState {
+    fn event(
+        state: &mut Self,
+        proxy: &ZwlrOutputPowerV1,
+        event: zwlr_output_power_v1::Event,
+        _data: &(),
+        _conn: &Connection,
+        qh: &QueueHandle<Self>,
+    ) {
+        let Some(layer) = state
+            .layers
+            .values_mut()
+            .find(|layer| layer.output_power.as_ref() == Some(proxy))
+        else {
+            return;
+        };
+
+        match event {
+            zwlr_output_power_v1::Event::Mode { mode } => {
+                layer.visibility.powered_off =
+                    mode == WEnum::Value(zwlr_output_power_v1::Mode::Off);
+            }
+            zwlr_output_power_v1::Event::Failed => {
+                // Another client watches the output, or it doesn't support power management
+                tracing::debug!(
+                    "Can't watch the power of output {}",
+                    layer.output.1.name.as_ref().unwrap()
+                );
+                proxy.destroy();
+                layer.output_power = None;
+                layer.visibility.powered_off = false;
+            }
+            _ => {}
+        }
+
+        if layer.apply_visibility() {
+            layer.wake(qh);
+        }
+    }
+}
+
+impl Dispatch<ZwlrForeignToplevelManagerV1, ()> for WLState {
+    fn event(
+        state: &mut Self,
+        _proxy: &ZwlrForeignToplevelManagerV1,
+        event: zwlr_foreign_toplevel_manager_v1::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+        if let zwlr_foreign_toplevel_manager_v1::Event::Finished = event {
+            state.toplevel_manager = None;
+        }
+    }
+
+    event_created_child!(WLState, ZwlrForeignToplevelManagerV1, [
+        zwlr_foreign_toplevel_manager_v1::EVT_TOPLEVEL_OPCODE => (ZwlrForeignToplevelHandleV1, ()),
+    ]);
+}
+
+impl Dispatch<ZwlrForeignToplevelHandleV1, ()> for WLState {
+    fn event(
+        state: &mut Self,
+        proxy: &ZwlrForeignToplevelHandleV1,
+        event: zwlr_foreign_toplevel_handle_v1::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+        use zwlr_foreign_toplevel_handle_v1::{Event, State};
+
+        match event {
+            Event::State { state: states } => {
+                let states: Vec<_> = states
+                    .chunks_exact(4)
+                    .map(|state| u32::from_ne_bytes(state.try_into().unwrap()))
+                    .collect();
+                let fullscreen = states.contains(&(State::Fullscreen as u32))
+                    && !states.contains(&(State::Minimized as u32));
+
+                state.toplevels.set_fullscreen(proxy.id(), fullscreen);
+            }
+            Event::OutputEnter { output } => state.toplevels.output_enter(proxy.id(), output),
+            Event::OutputLeave { output } => state.toplevels.output_leave(proxy.id(), &output),
+            Event::Done => {
+                state.toplevels.done(proxy.id());
+                state.refresh_fullscreen();
+            }
+            Event::Closed => {
+                state.toplevels.closed(&proxy.id());
+                proxy.destroy();
+                state.refresh_fullscreen();
+            }
+            _ => {}
+        }
+    }
+}
+
 impl ProvidesRegistryState for WLState {
     fn registry(&mut self) -> &mut RegistryState {
         &mut self.registry_state
@@ -2,6 +2,269 @@ use serde::{Deserialize, Serialize};
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum IPCRequest {
-    SetWP { id: u64, screen: String },
+    /// `transition` falls back to the one configured for the output
+    SetWP {
+        id: u64,
+        screen: String,
+        transition: Option<Transition>,
+    },
+    /// Shows one wallpaper across several outputs, laid out as the compositor places them. Setting
+    /// another wallpaper on one of them takes it out of the span.
+    SetSpannedWP {
+        id: u64,
+        screens: Vec<String>,
+        transition: Option<Transition>,
+    },
+    /// Toggles the bloom post-processing of scene wallpapers on an output
+    SetBloom {
+        screen: String,
+        enabled: bool,
+    },
+    /// Volume of the wallpaper on an output, from 0 to 1
+    SetVolume {
+        screen: String,
+        volume: f32,
+    },
+    SetMuted {
+        screen: String,
+        muted: bool,
+    },
+    SetScaling {
+        screen: String,
+        scaling: Scaling,
+    },
+    SetPointerTracking {
+        mode: PointerTracking,
+    },
+    /// Cursor position in the compositor global space, in logical pixels
+    PointerMoved {
+        x: f64,
+        y: f64,
+    },
+    /// Pauses or resumes the wallpaper of an output, or of every output when `screen` is `None`
+    SetPaused {
+        screen: Option<String>,
+        paused: bool,
+    },
+    /// Playback speed of video and scene wallpapers, from 0.1 to 4
+    SetPlaybackRate {
+        screen: Option<String>,
+        rate: f32,
+    },
+    /// Jumps to a timestamp of a video wallpaper, in seconds. Outputs playing the same video in
+    /// step jump together.
+    Seek {
+        screen: Option<String>,
+        position: f64,
+    },
+    /// Loops a video wallpaper between two timestamps in seconds, `None` plays the whole video
+    SetLoopRange {
+        screen: Option<String>,
+        range: Option<(f64, f64)>,
+    },
+    /// Caps the frame rate of an output, or of every output when `screen` is `None`. `None`
+    /// draws at the refresh rate of the output.
+    SetMaxFps {
+        screen: Option<String>,
+        fps: Option<u32>,
+    },
+    /// Moves the layer of an output, or of every output when `screen` is `None`. Unset fields are
+    /// kept, and the layer is created again when its namespace changes.
+    SetLayerOptions {
+        screen: Option<String>,
+        layer: Option<ShellLayer>,
+        namespace: Option<String>,
+        /// Top, right, bottom and left margins in logical pixels
+        margin: Option<(i32, i32, i32, i32)>,
+    },
+    /// Answered with `IPCResponse::Status`
+    GetStatus,
+    /// Answered with `IPCResponse::Events`, holding the events since the last call
+    GetEvents,
     StopDaemon,
 }
+
+/// Answer to the requests returning something, the other ones get an empty reply
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum IPCResponse {
+    Status(DaemonStatus),
+    Events(Vec<DaemonEvent>),
+}
+
+/// Things that happened in the daemon without being requested, oldest first
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum DaemonEvent {
+    /// The wallpaper of an output couldn't be played, its preview image is shown instead
+    WallpaperFailed {
+        screen: String,
+        error: String,
+    },
+    OutputConnected {
+        screen: String,
+    },
+    /// The layer of the output and its wallpaper were torn down
+    OutputDisconnected {
+        screen: String,
+    },
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct DaemonStatus {
+    pub outputs: Vec<OutputStatus>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct OutputStatus {
+    pub name: String,
+    /// Decoder playing the video wallpaper of the output, `None` for other wallpapers
+    pub video_decoder: Option<DecoderBackend>,
+    pub video_queue: Option<VideoQueueStatus>,
+    /// Covered by a fullscreen window, turned off, or not drawn by the compositor
+    pub hidden: bool,
+}
+
+/// Frames of a video wallpaper decoded ahead of their presentation
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct VideoQueueStatus {
+    pub queued_frames: usize,
+    /// Depends on the resolution of the video and the memory budget
+    pub capacity: usize,
+    /// Frames skipped because they were decoded too late, since the video started
+    pub dropped_frames: u64,
+}
+
+/// Video decoding backends, hardware ones need a driver supporting the video codec
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DecoderBackend {
+    Vaapi,
+    Vulkan,
+    Vdpau,
+    Software,
+}
+
+/// What the wallpaper of an output does while the output is hidden
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OcclusionPolicy {
+    /// Stops playback and drawing until the output is visible again
+    #[default]
+    Pause,
+    /// Keeps playing, but only draws a couple of frames per second
+    SlowDown,
+    KeepRunning,
+}
+
+/// Layer-shell layer wallpapers are drawn on, from the lowest to the highest
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ShellLayer {
+    #[default]
+    Background,
+    /// Above other background surfaces, still under windows
+    Bottom,
+    Top,
+    Overlay,
+}
+
+/// How wallpapers learn about the cursor position
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PointerTracking {
+    /// Wallpapers never receive the pointer position
+    #[default]
+    Disabled,
+    /// Wallpaper layers accept pointer input and read the position from the seat. Clicks landing
+    /// on the wallpaper itself are consumed by it, so desktops relying on clicks on the background
+    /// should use `External`. Ignored for wallpapers on the top and overlay layers, above windows.
+    Surface,
+    /// Wallpaper layers let every input through, the position is fed by an external source with
+    /// `PointerMoved` requests (for example a script polling `hyprctl cursorpos`)
+    External,
+}
+
+/// How a wallpaper is fitted to an output with a different size or aspect ratio
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ScalingMode {
+    /// Fills the output while keeping the aspect ratio, cropping what doesn't fit
+    #[default]
+    Cover,
+    /// Fits the whole wallpaper in the output, the remaining space is filled with the background
+    Contain,
+    /// Fills the output exactly, ignoring the aspect ratio
+    Stretch,
+    /// Keeps the native size of the wallpaper
+    Center,
+    /// Repeats the wallpaper at its native size. Scenes have no native size and are covered instead.
+    Tile,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Scaling {
+    pub mode: ScalingMode,
+    /// Position of the wallpaper when it's smaller or larger than the output, from (0, 0) for the
+    /// top left corner to (1, 1) for the bottom right one
+    pub alignment: (f32, f32),
+    /// Moves the wallpaper after alignment, in output pixels
+    pub offset: (f32, f32),
+    /// Color around the wallpaper when it doesn't cover the output
+    pub background: (f32, f32, f32),
+}
+
+impl Default for Scaling {
+    fn default() -> Self {
+        Self {
+            mode: ScalingMode::default(),
+            alignment: (0.5, 0.5),
+            offset: (0.0, 0.0),
+            background: (0.0, 0.0, 0.0),
+        }
+    }
+}
+
+/// Animation from the previous wallpaper of an output to the next one, which starts once the next
+/// one drew its first frame
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Transition {
+    pub effect: TransitionEffect,
+    /// 0 cuts to the next wallpaper as soon as it is ready
+    pub duration_ms: u32,
+    pub easing: Easing,
+}
+
+impl Default for Transition {
+    fn default() -> Self {
+        Self {
+            effect: TransitionEffect::default(),
+            duration_ms: 500,
+            easing: Easing::default(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransitionEffect {
+    #[default]
+    Crossfade,
+    /// Uncovers the next wallpaper from left to right
+    Wipe,
+    /// Uncovers the next wallpaper in a circle growing from the center of the output
+    GrowCircle,
+    /// Turns the previous wallpaper into large pixels, which shrink down to the next one
+    Pixelate,
+}
+
+/// Pace of a transition over its duration
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Easing {
+    Linear,
+    EaseIn,
+    EaseOut,
+    #[default]
+    EaseInOut,
+}
@@ -4,6 +4,7 @@ use std::str::FromStr;
 use cgmath::{Vector2, Vector3};
 use serde::{Deserialize, Deserializer};
 use serde::de::{Error, Unexpected};
+use serde_json::Value;
 
 use crate::project::WallpaperType;
 
@@ -122,3 +123,64 @@ where
         Err(Error::invalid_length(parts.len(), &"2 floats"))
     }
 }
+
+/// Unwraps values bound to a user property, which are stored as `{"user": ..., "value": ...}`
+fn unwrap_user_value(value: Value) -> Value {
+    match value {
+        Value::Object(mut map) if map.contains_key("value") => map.remove("value").unwrap(),
+        value => value,
+    }
+}
+
+fn parse_floats(value: &Value) -> Option<Vec<f32>> {
+    match value {
+        Value::Number(number) => Some(vec![number.as_f64()? as f32]),
+        Value::String(s) => s
+            .split_whitespace()
+            .map(|part| f32::from_str(part).ok())
+            .collect(),
+        Value::Bool(b) => Some(vec![if *b { 1.0 } else { 0.0 }]),
+        _ => None,
+    }
+}
+
+/// Deserializes a float that can be stored as a number, a string or a user property
+pub fn as_f32_lenient<'de, D>(deserializer: D) -> Result<f32, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = unwrap_user_value(Deserialize::deserialize(deserializer)?);
+
+    match parse_floats(&value).as_deref() {
+        Some([f]) => Ok(*f),
+        _ => Err(Error::custom(format!("expected a float, got {value}"))),
+    }
+}
+
+/// Deserializes a vector stored either as a `"x y z"` string or as a single scalar applied to every
+/// component, both optionally wrapped in a user property
+pub fn as_vec3f32_lenient<'de, D>(deserializer: D) -> Result<Vector3<f32>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = unwrap_user_value(Deserialize::deserialize(deserializer)?);
+
+    match parse_floats(&value).as_deref() {
+        Some([f]) => Ok(Vector3::new(*f, *f, *f)),
+        Some([x, y]) => Ok(Vector3::new(*x, *y, 0.0)),
+        Some([x, y, z]) => Ok(Vector3::new(*x, *y, *z)),
+        _ => Err(Error::custom(format!("expected 1 to 3 floats, got {value}"))),
+    }
+}
+
+pub fn as_vec2f32_opt<'de, D>(deserializer: D) -> Result<Option<Vector2<f32>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = unwrap_user_value(Deserialize::deserialize(deserializer)?);
+
+    match parse_floats(&value).as_deref() {
+        Some([x, y]) => Ok(Some(Vector2::new(*x, *y))),
+        _ => Ok(None),
+    }
+}
@@ -36,7 +36,11 @@ fn set_wp(wp_id: u64, screen: String, channel: State<Mutex<IpcChannel>>) {
     let response = channel
         .lock()
         .unwrap()
-        .send::<_, IPCRequest>(IPCRequest::SetWP { id: wp_id, screen })
+        .send::<_, IPCRequest>(IPCRequest::SetWP {
+            id: wp_id,
+            screen,
+            transition: None,
+        })
         .expect("Failed to communicate with daemon");
 
     if let Some(response) = response {